@@ -1,20 +1,126 @@
+use crate::concurrency;
 use crate::errors::DownloadError;
 use crate::errors::UploadError;
-use crate::io::consts::SLICE_SIZE;
 use crate::io::Cluster;
+use crate::model;
+use crate::throttle;
 
-use std::cmp;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
+use std::{cmp, fs};
 
-use futures::{future, stream};
-use reqwest::{Client, Response, StatusCode};
+use futures::future;
+use reqwest::{Body, Certificate, Client, Proxy, Response, StatusCode};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use tokio::time;
 
 const READ_TIMEOUT: Duration = Duration::from_secs(20);
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
 
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const MAX_IDLE_PER_HOST: usize = 32;
+
+static NETWORK: RwLock<Option<Network>> = RwLock::new(None);
+
+// The clients every request goes through, so connections and TLS sessions are reused across
+// clusters. HTTP/2 is negotiated wherever the server offers it
+#[derive(Clone)]
+pub struct Network {
+    api: Client,    // discord api and attachment downloads
+    upload: Client, // no read timeout, sending the body of an upload can stall for a while
+}
+
+impl Network {
+    pub fn new(settings: &model::Network) -> Result<Self, String> {
+        let proxy = match settings.proxy_url.trim() {
+            "" => None,
+            url => {
+                let proxy = Proxy::all(url).map_err(|err| format!("Invalid proxy url: {}", err))?;
+                match settings.proxy_username.as_str() {
+                    "" => Some(proxy),
+                    username => Some(proxy.basic_auth(username, &settings.proxy_password)),
+                }
+            }
+        };
+
+        let certificates = match settings.ca_bundle.trim() {
+            "" => Vec::new(),
+            path => {
+                let bundle = fs::read(path)
+                    .map_err(|err| format!("Could not read the CA bundle: {}", err))?;
+                Certificate::from_pem_bundle(&bundle)
+                    .map_err(|err| format!("Invalid CA bundle: {}", err))?
+            }
+        };
+
+        let builder = || {
+            let mut builder = Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+                .pool_max_idle_per_host(MAX_IDLE_PER_HOST)
+                .tcp_keepalive(KEEP_ALIVE)
+                .http2_adaptive_window(true)
+                .http2_keep_alive_interval(KEEP_ALIVE)
+                .http2_keep_alive_while_idle(true);
+
+            if !settings.user_agent.trim().is_empty() {
+                builder = builder.user_agent(settings.user_agent.trim());
+            }
+
+            if let Some(proxy) = &proxy {
+                builder = builder.proxy(proxy.clone());
+            }
+
+            for certificate in &certificates {
+                builder = builder.add_root_certificate(certificate.clone());
+            }
+
+            builder
+        };
+
+        let read_timeout = settings.timeout.map_or(READ_TIMEOUT, Duration::from_secs);
+        let api = builder()
+            .read_timeout(read_timeout)
+            .build()
+            .map_err(|err| format!("Could not build the http client: {}", err))?;
+        let upload = builder()
+            .build()
+            .map_err(|err| format!("Could not build the http client: {}", err))?;
+
+        Ok(Self { api, upload })
+    }
+}
+
+// Requests already in flight finish on the clients they started with
+pub fn configure(network: Network) {
+    *NETWORK.write().expect("failed to lock network") = Some(network);
+}
+
+fn network() -> Network {
+    if let Some(network) = &*NETWORK.read().expect("failed to lock network") {
+        return network.clone();
+    }
+
+    // Only taken before the settings are applied on startup
+    let mut network = NETWORK.write().expect("failed to lock network");
+    network
+        .get_or_insert_with(|| {
+            Network::new(&model::Network::default()).expect("failed to build the http client")
+        })
+        .clone()
+}
+
+// Clients are reference counted, cloning one shares its connection pool
+fn client() -> Client {
+    network().api
+}
+
+fn upload_client() -> Client {
+    network().upload
+}
+
 #[derive(Debug, Deserialize)]
 pub struct UploadDetailsInner {
     upload_url: String,
@@ -31,14 +137,79 @@ struct RateLimit {
     retry_after: f32,
 }
 
+// How requests are authorized, bots and webhooks can't use the endpoints only clients use
+#[derive(Debug, Clone)]
+pub enum Auth {
+    User(String),
+    Bot(String),
+    // Messages are posted through the webhook, everything else goes through the bot
+    Webhook {
+        bot: String,
+        id: String,
+        token: String,
+    },
+}
+
+impl Auth {
+    fn header(&self) -> String {
+        match self {
+            Self::User(token) => token.clone(),
+            Self::Bot(token) | Self::Webhook { bot: token, .. } => format!("Bot {}", token),
+        }
+    }
+
+    // A webhook only posts to its own channel, any other channel is written by the bot
+    pub fn bot(&self) -> Self {
+        match self {
+            Self::Webhook { bot, .. } => Self::Bot(bot.clone()),
+            auth => auth.clone(),
+        }
+    }
+}
+
+// Splits a webhook url into its id and token
+pub fn parse_webhook(url: &str) -> Option<(String, String)> {
+    let path = url
+        .trim()
+        .strip_prefix("https://")?
+        .split_once('/')
+        .filter(|(host, _)| {
+            matches!(
+                *host,
+                "discord.com" | "discordapp.com" | "canary.discord.com" | "ptb.discord.com"
+            )
+        })?
+        .1;
+
+    let mut segments = path.trim_end_matches('/').split('/');
+    if segments.next() != Some("api") {
+        return None;
+    }
+
+    let mut segment = segments.next()?;
+    if segment.starts_with('v') && segment[1..].parse::<u8>().is_ok() {
+        segment = segments.next()?;
+    }
+
+    match (segment, segments.next(), segments.next(), segments.next()) {
+        ("webhooks", Some(id), Some(token), None)
+            if id.parse::<u64>().is_ok() && !token.is_empty() =>
+        {
+            Some((id.to_owned(), token.to_owned()))
+        }
+        _ => None,
+    }
+}
+
 pub async fn preupload<'a>(
-    token: &Arc<String>,
+    auth: &Arc<Auth>,
     channel: &Arc<String>,
     size: u64,
+    slice_size: u64,
 ) -> Result<Vec<UploadDetailsInner>, UploadError> {
-    let slices = (size + SLICE_SIZE - 1) / SLICE_SIZE;
+    let slices = size.div_ceil(slice_size);
     let slices = (0..slices).map(|slice| {
-        let size = cmp::min(SLICE_SIZE, size - slice * SLICE_SIZE);
+        let size = cmp::min(slice_size, size - slice * slice_size);
         format!(
             r#"{{"file_size":{},"filename":"-","id":0,"is_clip":false}}"#,
             size
@@ -46,11 +217,7 @@ pub async fn preupload<'a>(
     });
 
     let body = format!(r#"{{"files":[{}]}}"#, slices.collect::<Vec<_>>().join(","));
-    let client = Client::builder()
-        .read_timeout(READ_TIMEOUT)
-        .connect_timeout(CONNECT_TIMEOUT)
-        .build()
-        .map_err(UploadError::from)?;
+    let client = client();
 
     loop {
         let req = client
@@ -58,7 +225,7 @@ pub async fn preupload<'a>(
                 "https://discord.com/api/v9/channels/{}/attachments",
                 channel
             ))
-            .header("Authorization", token.as_str())
+            .header("Authorization", auth.header())
             .header("Content-Type", "application/json")
             .body(body.clone())
             .send()
@@ -77,6 +244,7 @@ pub async fn preupload<'a>(
                     rate_limit.retry_after
                 );
 
+                concurrency::UPLOAD.rate_limited();
                 time::sleep(time::Duration::from_secs_f32(rate_limit.retry_after)).await;
             }
             StatusCode::OK => {
@@ -95,20 +263,31 @@ pub async fn preupload<'a>(
 pub async fn upload<T>(details: &[UploadDetailsInner], mut cluster: T) -> Result<(), UploadError>
 where
     T: Cluster + Send + Sync,
-    <T as Cluster>::Iter: Send + Sync + 'static,
+    <T as Cluster>::Iter: Send + 'static,
 {
-    let client = Client::builder()
-        .connect_timeout(CONNECT_TIMEOUT)
-        .build()
-        .map_err(UploadError::from)?;
+    let gate = cluster.gate();
+    let bodies = details.iter().map(|_| {
+        Body::wrap_stream(throttle::upload(
+            gate.clone(),
+            cluster.next_slice().unwrap(),
+        ))
+    });
 
-    let futures = details.iter().map(|detail| {
-        let stream = stream::iter(cluster.next_slice().unwrap());
+    upload_bodies(details, bodies).await
+}
 
+// Uploads one body per attachment, used when the slices are not read from a local file
+pub async fn upload_bodies<I>(details: &[UploadDetailsInner], bodies: I) -> Result<(), UploadError>
+where
+    I: IntoIterator<Item = Body>,
+{
+    let client = upload_client();
+
+    let futures = details.iter().zip(bodies).map(|(detail, body)| {
         client
             .put(&detail.upload_url)
             .header("Content-Type", "application/octet-stream")
-            .body(reqwest::Body::wrap_stream(stream))
+            .body(body)
             .send()
     });
 
@@ -131,41 +310,62 @@ struct Message {
 }
 
 pub async fn finalize(
-    token: &Arc<String>,
+    auth: &Arc<Auth>,
     channel: &Arc<String>,
     details: &[UploadDetailsInner],
 ) -> Result<u64, UploadError> {
-    let client = Client::builder()
-        .read_timeout(READ_TIMEOUT)
-        .connect_timeout(CONNECT_TIMEOUT)
-        .build()
-        .map_err(UploadError::from)?;
+    let client = client();
+
+    let attachments = details.iter().enumerate().map(|(idx, detail)| {
+        let id = match **auth {
+            Auth::User(_) => 0,
+            _ => idx,
+        };
 
-    let attachments = details.iter().map(|detail| {
         format!(
-            r#"{{"filename":"-","uploaded_filename":"{}","id":"0"}}"#,
-            detail.upload_filename
+            r#"{{"filename":"-","uploaded_filename":"{}","id":"{}"}}"#,
+            detail.upload_filename, id
         )
     });
-
-    let body = format!(
-        r#"{{"attachments":[{}],"channel_id":"{}","content":"","type":0,"sticker_ids":[]}}"#,
-        attachments.collect::<Vec<_>>().join(","),
-        channel
-    );
+    let attachments = attachments.collect::<Vec<_>>().join(",");
+
+    // Only clients send the extra message fields, bots and webhooks would have them rejected
+    let (url, body) = match &**auth {
+        Auth::User(_) => (
+            format!("https://discord.com/api/v9/channels/{}/messages", channel),
+            format!(
+                r#"{{"attachments":[{}],"channel_id":"{}","content":"","type":0,"sticker_ids":[]}}"#,
+                attachments, channel
+            ),
+        ),
+        Auth::Bot(_) => (
+            format!("https://discord.com/api/v9/channels/{}/messages", channel),
+            format!(r#"{{"attachments":[{}],"content":""}}"#, attachments),
+        ),
+        Auth::Webhook { id, token, .. } => (
+            format!(
+                "https://discord.com/api/v9/webhooks/{}/{}?wait=true",
+                id, token
+            ),
+            format!(r#"{{"attachments":[{}],"content":""}}"#, attachments),
+        ),
+    };
 
     log::debug!("Finalizing resource: {}", body);
-    let url = format!("https://discord.com/api/v9/channels/{}/messages", channel);
 
     loop {
         let req = client
             .post(&url)
-            .header("Authorization", token.as_str())
             .header("Content-Type", "application/json")
-            .body(body.clone())
-            .send()
-            .await
-            .map_err(UploadError::from)?;
+            .body(body.clone());
+
+        // The webhook token in the url is all the authorization it needs
+        let req = match **auth {
+            Auth::Webhook { .. } => req,
+            _ => req.header("Authorization", auth.header()),
+        };
+
+        let req = req.send().await.map_err(UploadError::from)?;
 
         let status = req.status();
         match status {
@@ -179,6 +379,7 @@ pub async fn finalize(
                     rate_limit.retry_after
                 );
 
+                concurrency::UPLOAD.rate_limited();
                 time::sleep(time::Duration::from_secs_f32(rate_limit.retry_after)).await;
             }
             StatusCode::OK => {
@@ -195,6 +396,61 @@ pub async fn finalize(
     }
 }
 
+// Creates a public thread without a starter message, returns its id
+pub async fn create_thread(
+    auth: &Arc<Auth>,
+    channel: &Arc<String>,
+    name: &str,
+) -> Result<String, UploadError> {
+    let client = client();
+
+    // Thread names are capped at 100 characters, archived threads reopen on the next message
+    let name = name.chars().take(100).collect::<String>();
+    let body = format!(
+        r#"{{"name":{},"type":11,"auto_archive_duration":10080}}"#,
+        serde_json::to_string(&name).expect("failed to encode thread name")
+    );
+
+    loop {
+        let req = client
+            .post(format!(
+                "https://discord.com/api/v9/channels/{}/threads",
+                channel
+            ))
+            .header("Authorization", auth.header())
+            .header("Content-Type", "application/json")
+            .body(body.clone())
+            .send()
+            .await
+            .map_err(UploadError::from)?;
+
+        let status = req.status();
+        match status {
+            StatusCode::UNAUTHORIZED => return Err(UploadError::Unauthorized),
+            StatusCode::FORBIDDEN => return Err(UploadError::Forbidden),
+            StatusCode::NOT_FOUND => return Err(UploadError::NotFound),
+            StatusCode::TOO_MANY_REQUESTS => {
+                let rate_limit: RateLimit = req.json().await.map_err(UploadError::from)?;
+                log::warn!(
+                    "Thread creation rate limited, retrying in {} seconds",
+                    rate_limit.retry_after
+                );
+
+                concurrency::UPLOAD.rate_limited();
+                time::sleep(time::Duration::from_secs_f32(rate_limit.retry_after)).await;
+            }
+            StatusCode::OK | StatusCode::CREATED => {
+                let thread: Message = req.json().await.map_err(UploadError::from)?;
+                return Ok(thread.id);
+            }
+            _ => {
+                log::error!("Failed to create thread: {}", req.text().await?);
+                return Err(UploadError::Unknown((status.as_u16(), status.to_string())));
+            }
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Attachment {
     pub url: String,
@@ -217,16 +473,12 @@ pub struct MessageFull {
 }
 
 pub async fn fetch_messages(
-    token: &Arc<String>,
+    auth: &Arc<Auth>,
     channel: &Arc<String>,
     id: u64,
     limit: usize,
 ) -> Result<Vec<MessageFull>, DownloadError> {
-    let client = Client::builder()
-        .read_timeout(READ_TIMEOUT)
-        .connect_timeout(CONNECT_TIMEOUT)
-        .build()
-        .map_err(DownloadError::from)?;
+    let client = client();
 
     let url = format!(
         "https://discord.com/api/v9/channels/{}/messages?limit={}&around={}",
@@ -236,7 +488,7 @@ pub async fn fetch_messages(
     loop {
         let req = client
             .get(&url)
-            .header("Authorization", token.as_str())
+            .header("Authorization", auth.header())
             .header("Content-Type", "application/json")
             .send()
             .await
@@ -254,6 +506,7 @@ pub async fn fetch_messages(
                     rate_limit.retry_after
                 );
 
+                concurrency::DOWNLOAD.rate_limited();
                 time::sleep(time::Duration::from_secs_f32(rate_limit.retry_after)).await;
             }
             StatusCode::OK => {
@@ -271,12 +524,104 @@ pub async fn fetch_messages(
     }
 }
 
+// Fetches the attachment urls of every message, in the order of the given ids
+pub async fn fetch_attachments(
+    auth: &Arc<Auth>,
+    channel: &Arc<String>,
+    ids: &[u64],
+) -> Result<Vec<Vec<String>>, DownloadError> {
+    let message_count = cmp::min(ids.len() * 2, 100);
+    let mut attachments = vec![None; ids.len()];
+    let mut pending = ids.to_vec();
+
+    while let Some(&id) = pending.first() {
+        let mut messages = fetch_messages(auth, channel, id, message_count).await?;
+
+        let mut has_found = false;
+        for message in messages.iter_mut() {
+            let message_id = message
+                .id
+                .parse::<u64>()
+                .expect("failed to parse message ID");
+
+            if message_id == id {
+                has_found = true;
+            }
+
+            if let Some(idx) = ids.iter().position(|id| *id == message_id)
+                && attachments[idx].is_none()
+            {
+                attachments[idx] = Some(message.attachments.take());
+                pending.retain(|id| *id != message_id);
+            }
+        }
+
+        if !has_found {
+            log::warn!("Message not found: {}", id);
+            return Err(DownloadError::NotFoundRemote);
+        }
+    }
+
+    Ok(attachments
+        .into_iter()
+        .map(Option::unwrap_or_default)
+        .collect())
+}
+
+pub async fn delete_message(
+    auth: &Arc<Auth>,
+    channel: &Arc<String>,
+    id: u64,
+) -> Result<(), UploadError> {
+    let client = client();
+
+    // A webhook can delete its own messages without the bot needing to manage messages
+    let url = match &**auth {
+        Auth::Webhook {
+            id: webhook, token, ..
+        } => format!(
+            "https://discord.com/api/v9/webhooks/{}/{}/messages/{}",
+            webhook, token, id
+        ),
+        _ => format!(
+            "https://discord.com/api/v9/channels/{}/messages/{}",
+            channel, id
+        ),
+    };
+
+    loop {
+        let req = match **auth {
+            Auth::Webhook { .. } => client.delete(&url),
+            _ => client.delete(&url).header("Authorization", auth.header()),
+        };
+
+        let req = req.send().await.map_err(UploadError::from)?;
+
+        let status = req.status();
+        match status {
+            StatusCode::UNAUTHORIZED => return Err(UploadError::Unauthorized),
+            StatusCode::FORBIDDEN => return Err(UploadError::Forbidden),
+            StatusCode::NOT_FOUND => return Err(UploadError::NotFound),
+            StatusCode::TOO_MANY_REQUESTS => {
+                let rate_limit: RateLimit = req.json().await.map_err(UploadError::from)?;
+                log::warn!(
+                    "Message deletion rate limited, retrying in {} seconds",
+                    rate_limit.retry_after
+                );
+
+                time::sleep(time::Duration::from_secs_f32(rate_limit.retry_after)).await;
+            }
+            StatusCode::OK | StatusCode::NO_CONTENT => return Ok(()),
+            _ => {
+                log::error!("Failed to delete message: {}", req.text().await?);
+                return Err(UploadError::Unknown((status.as_u16(), status.to_string())));
+            }
+        }
+    }
+}
+
 pub async fn download(url: String) -> Result<Response, DownloadError> {
-    let client = Client::builder()
-        .read_timeout(READ_TIMEOUT)
-        .connect_timeout(CONNECT_TIMEOUT)
-        .build()
-        .map_err(DownloadError::from)?;
+    let client = client();
 
     loop {
         let req = client.get(&url).send().await.map_err(DownloadError::from)?;
@@ -293,6 +638,7 @@ pub async fn download(url: String) -> Result<Response, DownloadError> {
                     rate_limit.retry_after
                 );
 
+                concurrency::DOWNLOAD.rate_limited();
                 time::sleep(time::Duration::from_secs_f32(rate_limit.retry_after)).await;
             }
             StatusCode::OK => {
@@ -307,3 +653,185 @@ pub async fn download(url: String) -> Result<Response, DownloadError> {
         }
     }
 }
+
+#[derive(Debug, Deserialize)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Webhook {
+    pub channel_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Role {
+    pub id: String,
+    pub permissions: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Guild {
+    pub name: String,
+    pub owner_id: String,
+    pub roles: Vec<Role>,
+    #[serde(default)]
+    pub premium_tier: u8,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Member {
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Overwrite {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: u8, // 0 for a role, 1 for a member
+    pub allow: String,
+    pub deny: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Channel {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: u8,
+    pub name: Option<String>,
+    pub parent_id: Option<String>,
+    #[serde(default)]
+    pub permission_overwrites: Vec<Overwrite>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Threads {
+    threads: Vec<Channel>,
+}
+
+pub async fn fetch_user(auth: &Arc<Auth>) -> Result<User, DownloadError> {
+    get(Some(auth), "users/@me".to_owned()).await
+}
+
+// Needs no authorization, the token in the url is enough
+pub async fn fetch_webhook(id: &str, token: &str) -> Result<Webhook, DownloadError> {
+    get(None, format!("webhooks/{}/{}", id, token)).await
+}
+
+pub async fn fetch_guild(auth: &Arc<Auth>, guild: &str) -> Result<Guild, DownloadError> {
+    get(Some(auth), format!("guilds/{}", guild)).await
+}
+
+// Users can only read their own membership through the oauth endpoint
+pub async fn fetch_member(
+    auth: &Arc<Auth>,
+    guild: &str,
+    user: &str,
+) -> Result<Member, DownloadError> {
+    let path = match **auth {
+        Auth::User(_) => format!("users/@me/guilds/{}/member", guild),
+        _ => format!("guilds/{}/members/{}", guild, user),
+    };
+
+    get(Some(auth), path).await
+}
+
+pub async fn fetch_channels(auth: &Arc<Auth>, guild: &str) -> Result<Vec<Channel>, DownloadError> {
+    get(Some(auth), format!("guilds/{}/channels", guild)).await
+}
+
+pub async fn fetch_threads(auth: &Arc<Auth>, guild: &str) -> Result<Vec<Channel>, DownloadError> {
+    let threads: Threads = get(Some(auth), format!("guilds/{}/threads/active", guild)).await?;
+    Ok(threads.threads)
+}
+
+async fn get<T: DeserializeOwned>(
+    auth: Option<&Arc<Auth>>,
+    path: String,
+) -> Result<T, DownloadError> {
+    let client = client();
+
+    let url = format!("https://discord.com/api/v9/{}", path);
+
+    loop {
+        let req = match auth {
+            Some(auth) => client.get(&url).header("Authorization", auth.header()),
+            None => client.get(&url),
+        };
+
+        let req = req.send().await.map_err(DownloadError::from)?;
+
+        let status = req.status();
+        match status {
+            StatusCode::UNAUTHORIZED => return Err(DownloadError::Unauthorized),
+            StatusCode::FORBIDDEN => return Err(DownloadError::Forbidden),
+            StatusCode::NOT_FOUND => return Err(DownloadError::NotFound),
+            StatusCode::TOO_MANY_REQUESTS => {
+                let rate_limit: RateLimit = req.json().await.map_err(DownloadError::from)?;
+                log::warn!(
+                    "Request to {} rate limited, retrying in {} seconds",
+                    path,
+                    rate_limit.retry_after
+                );
+
+                time::sleep(time::Duration::from_secs_f32(rate_limit.retry_after)).await;
+            }
+            StatusCode::OK => return req.json().await.map_err(DownloadError::from),
+            _ => {
+                return Err(DownloadError::Unknown((
+                    status.as_u16(),
+                    status.to_string(),
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(id: &str, token: &str) -> Option<(String, String)> {
+        Some((id.to_owned(), token.to_owned()))
+    }
+
+    #[test]
+    fn webhook_url_splits_into_id_and_token() {
+        assert_eq!(
+            parse_webhook("https://discord.com/api/webhooks/123/abc"),
+            parsed("123", "abc")
+        );
+        assert_eq!(
+            parse_webhook("  https://discord.com/api/v10/webhooks/123/abc/ \n"),
+            parsed("123", "abc")
+        );
+    }
+
+    #[test]
+    fn webhook_url_on_any_discord_host() {
+        for host in ["discordapp.com", "canary.discord.com", "ptb.discord.com"] {
+            let url = format!("https://{}/api/webhooks/123/abc", host);
+            assert_eq!(parse_webhook(&url), parsed("123", "abc"));
+        }
+    }
+
+    #[test]
+    fn webhook_url_rejects_anything_else() {
+        for url in [
+            "",
+            "http://discord.com/api/webhooks/123/abc",
+            "https://example.com/api/webhooks/123/abc",
+            "https://discord.com.example.com/api/webhooks/123/abc",
+            "https://discord.com/webhooks/123/abc",
+            "https://discord.com/api/v/webhooks/123/abc",
+            "https://discord.com/api/webhooks/123",
+            "https://discord.com/api/webhooks/abc/abc",
+            "https://discord.com/api/webhooks/123//",
+            "https://discord.com/api/webhooks/123/abc/github",
+            "https://discord.com/api/channels/123/abc",
+        ] {
+            assert_eq!(parse_webhook(url), None, "{}", url);
+        }
+    }
+}
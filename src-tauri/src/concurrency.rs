@@ -0,0 +1,327 @@
+use crate::io::consts::{DOWNLOAD_THREADS, UPLOAD_THREADS};
+use crate::model::{Bounds, Concurrency};
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+
+// Shared by every job going the same way, starting where the fixed limits used to be
+pub static UPLOAD: Controller = Controller::new("upload", UPLOAD_THREADS);
+pub static DOWNLOAD: Controller = Controller::new("download", DOWNLOAD_THREADS);
+
+// Rate limits come in bursts, one halving per burst is enough
+const BACKOFF: Duration = Duration::from_secs(1);
+
+// How many clusters go at once, raised one at a time while the throughput keeps up and halved
+// when Discord rate limits
+pub struct Controller {
+    name: &'static str,
+    inner: Mutex<Inner>,
+    notify: Notify,
+}
+
+struct Inner {
+    limit: usize,
+    active: usize,
+    bounds: Bounds,
+    round: Round,
+    best_rate: f64,
+    best_latency: Option<Duration>,
+    backed_off: Option<Instant>,
+}
+
+// Clusters finished since the limit last changed
+struct Round {
+    started: Option<Instant>, // when the first of them went
+    clusters: usize,
+    bytes: u64,
+    latency: Duration,
+    limited: bool,
+}
+
+impl Round {
+    const fn new() -> Self {
+        Self {
+            started: None,
+            clusters: 0,
+            bytes: 0,
+            latency: Duration::ZERO,
+            limited: false,
+        }
+    }
+}
+
+impl Controller {
+    const fn new(name: &'static str, limit: usize) -> Self {
+        Self {
+            name,
+            inner: Mutex::new(Inner {
+                limit,
+                active: 0,
+                bounds: Bounds { min: 1, max: limit },
+                round: Round::new(),
+                best_rate: 0.0,
+                best_latency: None,
+                backed_off: None,
+            }),
+            notify: Notify::const_new(),
+        }
+    }
+
+    // The most clusters that can ever go at once, for the streams that drive them
+    pub fn max(&self) -> usize {
+        self.lock().bounds.max
+    }
+
+    pub async fn acquire(&self) -> Permit<'_> {
+        loop {
+            // Created before checking, so a release in between isn't missed
+            let notified = self.notify.notified();
+            {
+                let mut inner = self.lock();
+                if inner.active < inner.limit {
+                    inner.active += 1;
+                    inner.round.started.get_or_insert_with(Instant::now);
+                    return Permit {
+                        controller: self,
+                        started: Instant::now(),
+                    };
+                }
+            }
+
+            notified.await;
+        }
+    }
+
+    pub fn rate_limited(&self) {
+        let mut inner = self.lock();
+        inner.round.limited = true;
+
+        let now = Instant::now();
+        if inner
+            .backed_off
+            .is_some_and(|backed_off| now - backed_off < BACKOFF)
+        {
+            return;
+        }
+
+        let limit = (inner.limit / 2).max(inner.bounds.min);
+        if limit != inner.limit {
+            log::info!(
+                "Rate limited, lowering {} concurrency to {}",
+                self.name,
+                limit
+            );
+        }
+
+        inner.limit = limit;
+        inner.backed_off = Some(now);
+        inner.restart();
+    }
+
+    fn configure(&self, bounds: Bounds) {
+        let mut inner = self.lock();
+        inner.limit = inner.limit.clamp(bounds.min, bounds.max);
+        inner.bounds = bounds;
+        inner.restart();
+        drop(inner);
+
+        self.notify.notify_waiters();
+    }
+
+    fn finish(&self, bytes: u64, latency: Duration) {
+        let mut inner = self.lock();
+        inner.round.clusters += 1;
+        inner.round.bytes += bytes;
+        inner.round.latency += latency;
+
+        // A round lasts as many clusters as may go at once, so each of them had a say
+        if inner.round.clusters >= inner.limit {
+            inner.adjust(self.name);
+        }
+    }
+
+    fn release(&self) {
+        let mut inner = self.lock();
+        inner.active -= 1;
+
+        // Time spent idle between jobs isn't part of the throughput
+        if inner.active == 0 {
+            inner.restart();
+        }
+
+        drop(inner);
+        self.notify.notify_waiters();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("failed to lock concurrency")
+    }
+}
+
+impl Inner {
+    // Clusters already in flight count towards the next round
+    fn restart(&mut self) -> Round {
+        let round = std::mem::replace(&mut self.round, Round::new());
+        if self.active > 0 {
+            self.round.started = Some(Instant::now());
+        }
+
+        round
+    }
+
+    fn adjust(&mut self, name: &str) {
+        let round = self.restart();
+        let elapsed = round
+            .started
+            .map_or(0.0, |started| started.elapsed().as_secs_f64());
+        if elapsed == 0.0 {
+            return;
+        }
+
+        let rate = round.bytes as f64 / elapsed;
+        let latency = round.latency / round.clusters as u32;
+
+        // The best seen fades, so a link that got slower isn't held to what it once did
+        self.best_rate = (self.best_rate * 0.98).max(rate);
+        let best_latency = *self.best_latency.get_or_insert(latency);
+        self.best_latency = Some(best_latency.min(latency));
+
+        let limit = match round.limited {
+            true => self.limit,
+            // Another cluster at once only helps while it doesn't slow down the others
+            false if rate >= self.best_rate * 0.95 && latency < best_latency * 3 => self.limit + 1,
+            false if rate < self.best_rate * 0.8 => self.limit - 1,
+            false => self.limit,
+        }
+        .clamp(self.bounds.min, self.bounds.max);
+
+        if limit != self.limit {
+            log::debug!(
+                "{} concurrency {} -> {} at {:.2} MB/s, {:.2}s per cluster",
+                name,
+                self.limit,
+                limit,
+                rate / 1_000_000.0,
+                latency.as_secs_f64()
+            );
+        }
+
+        self.limit = limit;
+    }
+}
+
+// A cluster in flight, dropping it without finishing doesn't count towards the throughput
+pub struct Permit<'a> {
+    controller: &'a Controller,
+    started: Instant,
+}
+
+impl Permit<'_> {
+    pub fn done(self, bytes: u64) {
+        self.controller.finish(bytes, self.started.elapsed());
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.controller.release();
+    }
+}
+
+pub fn validate(concurrency: &Concurrency) -> Result<(), String> {
+    for bounds in [concurrency.upload, concurrency.download] {
+        if bounds.min == 0 {
+            return Err("At least one cluster has to go at once".to_owned());
+        }
+
+        if bounds.min > bounds.max {
+            return Err("The least clusters at once can't be more than the most".to_owned());
+        }
+    }
+
+    Ok(())
+}
+
+// Bounds apply right away, the limit found so far is kept when it fits them
+pub fn configure(concurrency: Concurrency) {
+    UPLOAD.configure(concurrency.upload);
+    DOWNLOAD.configure(concurrency.download);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inner(limit: usize, best_rate: f64) -> Inner {
+        Inner {
+            limit,
+            active: 0,
+            bounds: Bounds { min: 1, max: 8 },
+            round: Round::new(),
+            best_rate,
+            best_latency: Some(Duration::from_secs(1)),
+            backed_off: None,
+        }
+    }
+
+    // A round of a second, so the bytes are the rate
+    fn finish(inner: &mut Inner, bytes: u64, latency: Duration, limited: bool) {
+        inner.round = Round {
+            started: Some(Instant::now() - Duration::from_secs(1)),
+            clusters: 2,
+            bytes,
+            latency: latency * 2,
+            limited,
+        };
+        inner.adjust("test");
+    }
+
+    #[test]
+    fn keeping_up_raises_the_limit() {
+        let mut inner = inner(4, 1000.0);
+        finish(&mut inner, 1000, Duration::from_secs(1), false);
+        assert_eq!(inner.limit, 5);
+    }
+
+    #[test]
+    fn slowing_down_lowers_the_limit() {
+        let mut inner = inner(4, 1000.0);
+        finish(&mut inner, 500, Duration::from_secs(1), false);
+        assert_eq!(inner.limit, 3);
+    }
+
+    #[test]
+    fn higher_latency_holds_the_limit() {
+        let mut inner = inner(4, 1000.0);
+        finish(&mut inner, 1000, Duration::from_secs(3), false);
+        assert_eq!(inner.limit, 4);
+    }
+
+    #[test]
+    fn rate_limited_round_holds_the_limit() {
+        let mut inner = inner(4, 1000.0);
+        finish(&mut inner, 1000, Duration::from_secs(1), true);
+        assert_eq!(inner.limit, 4);
+    }
+
+    #[test]
+    fn limit_stays_within_bounds() {
+        let mut high = inner(8, 1000.0);
+        finish(&mut high, 1000, Duration::from_secs(1), false);
+        assert_eq!(high.limit, 8);
+
+        let mut low = inner(1, 1000.0);
+        finish(&mut low, 100, Duration::from_secs(1), false);
+        assert_eq!(low.limit, 1);
+    }
+
+    #[test]
+    fn empty_round_changes_nothing() {
+        let mut inner = inner(4, 1000.0);
+        inner.adjust("test");
+        assert_eq!(inner.limit, 4);
+        assert_eq!(inner.best_rate, 1000.0);
+    }
+}
@@ -0,0 +1,315 @@
+use crate::api::{self, Auth, Channel, Guild};
+use crate::errors::DownloadError;
+
+use std::sync::Arc;
+
+use serde::Serialize;
+
+const ADMINISTRATOR: u64 = 1 << 3;
+const VIEW_CHANNEL: u64 = 1 << 10;
+const SEND_MESSAGES: u64 = 1 << 11;
+const ATTACH_FILES: u64 = 1 << 15;
+const READ_MESSAGE_HISTORY: u64 = 1 << 16;
+const SEND_MESSAGES_IN_THREADS: u64 = 1 << 38;
+
+const TEXT_CHANNELS: [u8; 2] = [0, 5]; // text, announcement
+const THREADS: [u8; 3] = [10, 11, 12]; // announcement, public, private
+
+#[derive(Debug, Serialize, Default)]
+pub struct Report {
+    pub user: Option<String>,
+    pub guild: Option<String>,
+    pub channels: Vec<ChannelInfo>,
+    pub problems: Vec<Problem>,
+}
+
+// A channel the files could be stored in, for the picker
+#[derive(Debug, Serialize)]
+pub struct ChannelInfo {
+    pub id: String,
+    pub name: String,
+    pub parent_id: Option<String>,
+    pub is_thread: bool,
+    pub is_usable: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub message: String,
+}
+
+impl Report {
+    // Settings that can't be tested at all
+    pub fn invalid(message: String) -> Self {
+        let mut report = Self::default();
+        report.problem("Settings", message);
+        report
+    }
+
+    fn problem(&mut self, kind: &'static str, message: String) {
+        log::warn!("Connection problem: {}", message);
+        self.problems.push(Problem { kind, message });
+    }
+}
+
+// Checks the credentials, the guild and the channel one after another, stopping at the first
+// step that makes the rest impossible to check
+pub async fn test(auth: Auth, guild_id: &str, channel_id: &str) -> Report {
+    let mut report = Report::default();
+    let auth = Arc::new(auth);
+
+    let user = match api::fetch_user(&auth).await {
+        Ok(user) => user,
+        Err(DownloadError::Unauthorized) => {
+            let message = match *auth {
+                Auth::User(_) => {
+                    "The user token was rejected, it may have expired or be a bot token"
+                }
+                _ => "The bot token was rejected, it may have been reset or be a user token",
+            };
+
+            report.problem("Unauthorized", message.to_owned());
+            return report;
+        }
+        Err(err) => {
+            report.problem("Network", format!("Could not reach Discord: {}", err));
+            return report;
+        }
+    };
+    report.user = Some(user.username);
+
+    if let Auth::Webhook { id, token, .. } = &*auth {
+        match api::fetch_webhook(id, token).await {
+            Ok(webhook) if webhook.channel_id.as_deref() != Some(channel_id) => report.problem(
+                "Webhook",
+                format!(
+                    "The webhook posts to channel {}, not to the selected channel",
+                    webhook.channel_id.unwrap_or_default()
+                ),
+            ),
+            Ok(_) => {}
+            Err(DownloadError::NotFound | DownloadError::Unauthorized) => report.problem(
+                "Webhook",
+                "The webhook no longer exists or its token is wrong".to_owned(),
+            ),
+            Err(err) => report.problem("Webhook", format!("Could not check the webhook: {}", err)),
+        }
+    }
+
+    if guild_id.is_empty() {
+        report.problem("Guild", "No guild is selected".to_owned());
+        return report;
+    }
+
+    let guild = match api::fetch_guild(&auth, guild_id).await {
+        Ok(guild) => guild,
+        Err(DownloadError::NotFound | DownloadError::Forbidden) => {
+            report.problem(
+                "Guild",
+                format!("The account is not a member of guild {}", guild_id),
+            );
+            return report;
+        }
+        Err(err) => {
+            report.problem("Guild", format!("Could not fetch the guild: {}", err));
+            return report;
+        }
+    };
+    report.guild = Some(guild.name.clone());
+
+    let roles = match api::fetch_member(&auth, guild_id, &user.id).await {
+        Ok(member) => member.roles,
+        Err(err) => {
+            report.problem(
+                "Permissions",
+                format!("Could not read the roles of the account: {}", err),
+            );
+            Vec::new()
+        }
+    };
+
+    let channels = match api::fetch_channels(&auth, guild_id).await {
+        Ok(channels) => channels,
+        Err(err) => {
+            report.problem("Channels", format!("Could not list the channels: {}", err));
+            return report;
+        }
+    };
+
+    // Listing threads needs more than reading a channel, so a failure only hides them
+    let threads = match api::fetch_threads(&auth, guild_id).await {
+        Ok(threads) => threads,
+        Err(err) => {
+            report.problem("Threads", format!("Could not list the threads: {}", err));
+            Vec::new()
+        }
+    };
+
+    let permissions = Permissions {
+        guild_id,
+        user_id: &user.id,
+        guild: &guild,
+        roles: &roles,
+    };
+
+    // Webhooks post on their own, the bot only has to read
+    let needed = match *auth {
+        Auth::Webhook { .. } => VIEW_CHANNEL | READ_MESSAGE_HISTORY,
+        _ => VIEW_CHANNEL | SEND_MESSAGES | ATTACH_FILES | READ_MESSAGE_HISTORY,
+    };
+
+    let mut selected = None;
+    for channel in channels
+        .iter()
+        .filter(|channel| TEXT_CHANNELS.contains(&channel.kind))
+    {
+        let granted = permissions.of(channel);
+        if channel.id == channel_id {
+            selected = Some((channel, granted, needed));
+        }
+
+        report
+            .channels
+            .push(info(channel, false, granted & needed == needed));
+    }
+
+    for thread in threads
+        .iter()
+        .filter(|thread| THREADS.contains(&thread.kind))
+    {
+        // Threads have no overwrites of their own, they follow their parent
+        let parent = channels
+            .iter()
+            .find(|channel| Some(&channel.id) == thread.parent_id.as_ref());
+        let granted = parent.map_or(0, |parent| permissions.of(parent));
+        let needed = match needed & SEND_MESSAGES {
+            0 => needed,
+            _ => needed & !SEND_MESSAGES | SEND_MESSAGES_IN_THREADS,
+        };
+
+        if thread.id == channel_id {
+            selected = Some((thread, granted, needed));
+        }
+
+        report
+            .channels
+            .push(info(thread, true, granted & needed == needed));
+    }
+
+    let (channel, granted, needed) = match (channel_id.is_empty(), selected) {
+        (true, _) => {
+            report.problem("Channel", "No channel is selected".to_owned());
+            return report;
+        }
+        (false, None) => {
+            report.problem(
+                "Channel",
+                format!(
+                    "Channel {} is not a text channel or an active thread of {}",
+                    channel_id, guild.name
+                ),
+            );
+            return report;
+        }
+        (false, Some(selected)) => selected,
+    };
+
+    let name = channel.name.as_deref().unwrap_or(&channel.id);
+    let missing = needed & !granted;
+    for (permission, message) in [
+        (VIEW_CHANNEL, "view"),
+        (SEND_MESSAGES, "send messages in"),
+        (SEND_MESSAGES_IN_THREADS, "send messages in"),
+        (ATTACH_FILES, "attach files in"),
+        (READ_MESSAGE_HISTORY, "read the message history of"),
+    ] {
+        if missing & permission != 0 {
+            report.problem(
+                "Permissions",
+                format!("The account can't {} #{}", message, name),
+            );
+        }
+    }
+
+    report
+}
+
+fn info(channel: &Channel, is_thread: bool, is_usable: bool) -> ChannelInfo {
+    ChannelInfo {
+        id: channel.id.clone(),
+        name: channel.name.clone().unwrap_or_default(),
+        parent_id: channel.parent_id.clone(),
+        is_thread,
+        is_usable,
+    }
+}
+
+// Resolves the permissions of the account the same way Discord does
+struct Permissions<'a> {
+    guild_id: &'a str,
+    user_id: &'a str,
+    guild: &'a Guild,
+    roles: &'a [String],
+}
+
+impl Permissions<'_> {
+    fn base(&self) -> u64 {
+        if self.guild.owner_id == self.user_id {
+            return u64::MAX;
+        }
+
+        // The @everyone role shares its id with the guild
+        let permissions = self
+            .guild
+            .roles
+            .iter()
+            .filter(|role| role.id == self.guild_id || self.roles.contains(&role.id))
+            .fold(0, |permissions, role| permissions | bits(&role.permissions));
+
+        match permissions & ADMINISTRATOR {
+            0 => permissions,
+            _ => u64::MAX,
+        }
+    }
+
+    fn of(&self, channel: &Channel) -> u64 {
+        let base = self.base();
+        if base == u64::MAX {
+            return base;
+        }
+
+        let overwrites = &channel.permission_overwrites;
+        let mut permissions = base;
+        if let Some(everyone) = overwrites.iter().find(|o| o.id == self.guild_id) {
+            permissions = apply(permissions, bits(&everyone.allow), bits(&everyone.deny));
+        }
+
+        let (allow, deny) = overwrites
+            .iter()
+            .filter(|o| o.kind == 0 && self.roles.contains(&o.id))
+            .fold((0, 0), |(allow, deny), o| {
+                (allow | bits(&o.allow), deny | bits(&o.deny))
+            });
+        permissions = apply(permissions, allow, deny);
+
+        if let Some(member) = overwrites
+            .iter()
+            .find(|o| o.kind == 1 && o.id == self.user_id)
+        {
+            permissions = apply(permissions, bits(&member.allow), bits(&member.deny));
+        }
+
+        permissions
+    }
+}
+
+fn apply(permissions: u64, allow: u64, deny: u64) -> u64 {
+    (permissions & !deny) | allow
+}
+
+// Permissions are sent as decimal strings since they don't fit in a javascript number
+fn bits(permissions: &str) -> u64 {
+    permissions.parse().unwrap_or(0)
+}
@@ -1,9 +1,19 @@
 use std::env;
 
+use crate::api;
+use crate::concurrency;
+use crate::connection;
+use crate::io::parity::ErasureCode;
+use crate::io::{Algorithm, Slicing};
+use crate::memory;
+use crate::model::{AuthMode, Concurrency, Job, Network, Queued, Stripe, ThreadMode, Throttle};
+use crate::progress::{self, Report};
+use crate::state::queue::Resume;
+use crate::throttle;
 use crate::{levenshtein::levenshtein, AppState};
 
-use serde::{Deserialize, Serialize};
-use tauri::{Manager, State};
+use serde::{Deserialize, Deserializer, Serialize};
+use tauri::State;
 
 #[tauri::command]
 pub async fn get_files(state: State<'_, AppState>) -> Result<String, ()> {
@@ -14,11 +24,25 @@ pub async fn get_files(state: State<'_, AppState>) -> Result<String, ()> {
 #[derive(Serialize)]
 pub struct Settings<'a> {
     token: &'a String,
+    auth_mode: AuthMode,
+    webhook_url: &'a String,
     channel: &'a String,
     guild: &'a String,
     do_encrypt: bool,
     do_checksum: bool,
     download_location: &'a String,
+    algorithm: Algorithm,
+    compression: Option<i32>,
+    erasure: Option<ErasureCode>,
+    mirrors: &'a Vec<String>,
+    stripes: &'a Vec<Stripe>,
+    thread_mode: ThreadMode,
+    network: &'a Network,
+    throttle: &'a Throttle,
+    concurrency: Concurrency,
+    slicing: Option<Slicing>,
+    memory: u64,
+    progress_interval: u64,
 }
 
 #[tauri::command]
@@ -26,24 +50,53 @@ pub async fn get_settings(state: State<'_, AppState>) -> Result<String, ()> {
     let state = state.read().await;
     let settings = Settings {
         token: &state.token,
+        auth_mode: state.auth_mode,
+        webhook_url: &state.webhook_url,
         channel: &state.channel_id,
         guild: &state.guild_id,
         do_encrypt: state.do_encrypt,
         do_checksum: state.do_checksum,
         download_location: &state.download_location,
+        algorithm: state.algorithm,
+        compression: state.compression,
+        erasure: state.erasure,
+        mirrors: &state.mirrors,
+        stripes: &state.stripes,
+        thread_mode: state.thread_mode,
+        network: &state.network,
+        throttle: &state.throttle,
+        concurrency: state.concurrency,
+        slicing: state.slicing,
+        memory: state.memory,
+        progress_interval: state.progress_interval,
     };
 
     Ok(serde_json::to_string(&settings).unwrap())
 }
 
 #[tauri::command]
-pub async fn upload_files(state: State<'_, AppState>, files: Vec<String>) -> Result<(), ()> {
+pub async fn upload_files(
+    state: State<'_, AppState>,
+    files: Vec<String>,
+    passphrase: Option<String>,
+    algorithm: Option<Algorithm>,
+    compression: Option<i32>,
+    snapshot: Option<bool>,
+) -> Result<(), ()> {
     let mut state = state.write().await;
     log::debug!("Adding files: {:?}", files);
-    state.extend_upload_queue(files);
+    let snapshot = snapshot.unwrap_or(false);
+    state.extend_upload_queue(files, passphrase, algorithm, compression, snapshot);
     Ok(())
 }
 
+// After an upload failed because its file was modified, either reads it again or copies it first
+#[tauri::command]
+pub async fn retry_upload(state: State<'_, AppState>, snapshot: bool) -> Result<(), String> {
+    let mut state = state.write().await;
+    state.retry_upload(snapshot)
+}
+
 #[tauri::command]
 pub async fn download_files(_state: State<'_, AppState>, files: Vec<u32>) -> Result<(), ()> {
     let mut state = _state.write().await;
@@ -63,43 +116,221 @@ pub async fn delete_files(state: State<'_, AppState>, files: Vec<u32>) -> Result
     Ok(())
 }
 
+#[tauri::command]
+pub async fn provide_passphrase(
+    state: State<'_, AppState>,
+    passphrase: Option<String>,
+) -> Result<(), ()> {
+    let mut state = state.write().await;
+    match state.rt.passphrase_tx.take() {
+        Some(passphrase_tx) => {
+            if passphrase_tx.send(passphrase).is_err() {
+                log::error!("failed to send passphrase");
+            }
+        }
+        None => log::warn!("No file is waiting for a passphrase"),
+    }
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn rekey_files(
+    state: State<'_, AppState>,
+    files: Vec<u32>,
+    delete_old: bool,
+) -> Result<(), ()> {
+    let mut state = state.write().await;
+    log::debug!("Rekeying files: {:?}", files);
+    state.extend_rekey_queue(files, delete_old);
+    Ok(())
+}
+
 #[derive(Deserialize)]
 pub struct PartialSettings {
     token: Option<String>,
+    auth_mode: Option<AuthMode>,
+    webhook_url: Option<String>,
     channel: Option<String>,
     guild: Option<String>,
     do_encrypt: Option<bool>,
     do_checksum: Option<bool>,
     download_location: Option<String>,
+    algorithm: Option<Algorithm>,
+    #[serde(default, deserialize_with = "nullable")]
+    compression: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "nullable")]
+    erasure: Option<Option<ErasureCode>>,
+    mirrors: Option<Vec<String>>,
+    stripes: Option<Vec<Stripe>>,
+    thread_mode: Option<ThreadMode>,
+    network: Option<Network>,
+    throttle: Option<Throttle>,
+    concurrency: Option<Concurrency>,
+    #[serde(default, deserialize_with = "nullable")]
+    slicing: Option<Option<Slicing>>,
+    memory: Option<u64>,
+    progress_interval: Option<u64>,
+}
+
+// Tells a null apart from a missing field, null clears the setting
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+// Checks that a mode has everything it needs, so a typo is caught before the first upload
+fn validate_auth(mode: AuthMode, token: &str, webhook_url: &str) -> Result<(), String> {
+    if token.is_empty() {
+        return Err(match mode {
+            AuthMode::User => "A user token is required".to_owned(),
+            AuthMode::Bot | AuthMode::Webhook => "A bot token is required".to_owned(),
+        });
+    }
+
+    if token.chars().any(char::is_whitespace) {
+        return Err("The token must not contain whitespace".to_owned());
+    }
+
+    if mode == AuthMode::Webhook && api::parse_webhook(webhook_url).is_none() {
+        return Err(
+            "The webhook url must look like https://discord.com/api/webhooks/{id}/{token}"
+                .to_owned(),
+        );
+    }
+
+    Ok(())
 }
 
 #[tauri::command]
-pub async fn set_settings(state: State<'_, AppState>, settings: PartialSettings) -> Result<(), ()> {
+pub async fn set_settings(
+    state: State<'_, AppState>,
+    mut settings: PartialSettings,
+) -> Result<(), String> {
     let mut state = state.write().await;
-    let mut earse_data = false;
+
+    // Nothing is applied unless every setting is valid
+    if settings.token.is_some() || settings.auth_mode.is_some() || settings.webhook_url.is_some() {
+        let mode = settings.auth_mode.unwrap_or(state.auth_mode);
+
+        // Bot tokens are often pasted along with the prefix of the header
+        settings.token = settings.token.map(|token| match mode {
+            AuthMode::User => token.trim().to_owned(),
+            AuthMode::Bot | AuthMode::Webhook => {
+                let token = token.trim();
+                token.strip_prefix("Bot ").unwrap_or(token).to_owned()
+            }
+        });
+
+        let token = settings.token.as_ref().unwrap_or(&state.token);
+        let webhook_url = settings.webhook_url.as_ref().unwrap_or(&state.webhook_url);
+        if let Err(err) = validate_auth(mode, token, webhook_url) {
+            log::warn!("Invalid credentials: {}", err);
+            return Err(err);
+        }
+    }
+
+    // The proxy and the CA bundle are checked before anything is applied as well
+    let network = match settings.network.take() {
+        Some(network) => match api::Network::new(&network) {
+            Ok(parsed) => Some((network, parsed)),
+            Err(err) => {
+                log::warn!("Invalid network settings: {}", err);
+                return Err(err);
+            }
+        },
+        None => None,
+    };
+
+    if let Some(throttle) = &settings.throttle
+        && let Err(err) = throttle::validate(throttle)
+    {
+        log::warn!("Invalid throttle settings: {}", err);
+        return Err(err);
+    }
+
+    if let Some(concurrency) = &settings.concurrency
+        && let Err(err) = concurrency::validate(concurrency)
+    {
+        log::warn!("Invalid concurrency settings: {}", err);
+        return Err(err);
+    }
+
+    if let Some(Some(slicing)) = &settings.slicing
+        && let Err(err) = slicing.validate()
+    {
+        log::warn!("Invalid slicing: {}", err);
+        return Err(err);
+    }
+
+    if let Some(limit) = settings.memory
+        && let Err(err) = memory::validate(limit)
+    {
+        log::warn!("Invalid memory limit: {}", err);
+        return Err(err);
+    }
+
+    if let Some(interval) = settings.progress_interval
+        && let Err(err) = progress::validate(interval)
+    {
+        log::warn!("Invalid progress interval: {}", err);
+        return Err(err);
+    }
+
+    if let Some(Some(code)) = &settings.erasure
+        && let Err(err) = code.validate()
+    {
+        log::warn!("Invalid erasure code: {}", err);
+        return Err(err);
+    }
+
+    // Another channel gets a library of its own, the old one is kept in its profile
+    let channel = match settings.channel.is_some() || settings.guild.is_some() {
+        true => {
+            let guild = settings
+                .guild
+                .take()
+                .unwrap_or_else(|| state.guild_id.clone());
+            let channel = settings
+                .channel
+                .take()
+                .unwrap_or_else(|| state.channel_id.clone());
+            if let Err(err) = state.check_channel(&guild, &channel) {
+                log::warn!("Could not change the channel: {}", err);
+                return Err(err);
+            }
+
+            Some((guild, channel))
+        }
+        false => None,
+    };
 
     if let Some(token) = settings.token {
         state.token = token;
     }
 
-    if let Some(channel) = settings.channel {
-        state.channel_id = channel;
-        earse_data = true;
+    if let Some(auth_mode) = settings.auth_mode {
+        state.auth_mode = auth_mode;
     }
 
-    if let Some(guild) = settings.guild {
-        state.guild_id = guild;
-        earse_data = true;
+    if let Some(webhook_url) = settings.webhook_url {
+        state.webhook_url = webhook_url.trim().to_owned();
     }
 
-    if earse_data {
-        let handle = unsafe { state.rt.app_handle.as_ref().unwrap() };
-        handle
-            .emit_all("erase_files", ())
-            .expect("failed to emit erase_files");
+    if let Some((guild, channel)) = channel {
+        let profile = state.profile.clone();
+
+        // Checked along with everything else, nothing is left to fail here
+        if let Err(err) = state.select_channel(guild, channel) {
+            log::error!("failed to change the channel: {}", err);
+        }
 
-        log::info!("Changed sensitive settings, erasing data");
-        state.files.clear();
+        if state.profile != profile {
+            state.emit_profile();
+        }
     }
 
     if let Some(do_encrypt) = settings.do_encrypt {
@@ -114,10 +345,189 @@ pub async fn set_settings(state: State<'_, AppState>, settings: PartialSettings)
         state.download_location = download_location;
     }
 
+    if let Some(algorithm) = settings.algorithm {
+        state.algorithm = algorithm;
+    }
+
+    if let Some(compression) = settings.compression {
+        state.compression = compression;
+    }
+
+    if let Some(erasure) = settings.erasure {
+        state.erasure = erasure;
+    }
+
+    if let Some(mirrors) = settings.mirrors {
+        let mut channels = Vec::with_capacity(mirrors.len());
+        for mirror in mirrors {
+            let mirror = mirror.trim().to_owned();
+            if !mirror.is_empty() && mirror != state.channel_id && !channels.contains(&mirror) {
+                channels.push(mirror);
+            }
+        }
+
+        state.mirrors = channels;
+    }
+
+    if let Some(stripes) = settings.stripes {
+        let mut pairs = Vec::with_capacity(stripes.len());
+        for stripe in stripes {
+            let stripe = Stripe {
+                token: stripe.token.trim().to_owned(),
+                channel_id: stripe.channel_id.trim().to_owned(),
+            };
+
+            if stripe.token.is_empty() || stripe.channel_id.is_empty() {
+                log::warn!("Ignoring incomplete stripe");
+                continue;
+            }
+
+            if state.auth_mode == AuthMode::Webhook && api::parse_webhook(&stripe.token).is_none() {
+                log::warn!(
+                    "Ignoring stripe without a webhook url: {}",
+                    stripe.channel_id
+                );
+                continue;
+            }
+
+            // The primary pair is always part of the pool
+            let is_primary = stripe.token == state.token && stripe.channel_id == state.channel_id;
+            if !is_primary && !pairs.contains(&stripe) {
+                pairs.push(stripe);
+            }
+        }
+
+        state.stripes = pairs;
+    }
+
+    if let Some(thread_mode) = settings.thread_mode {
+        state.thread_mode = thread_mode;
+    }
+
+    if let Some((network, parsed)) = network {
+        api::configure(parsed);
+        state.network = network;
+    }
+
+    if let Some(throttle) = settings.throttle {
+        throttle::configure(throttle.clone());
+        state.throttle = throttle;
+    }
+
+    if let Some(concurrency) = settings.concurrency {
+        concurrency::configure(concurrency);
+        state.concurrency = concurrency;
+    }
+
+    // Only new uploads are cut differently, stored files keep their own slicing
+    if let Some(slicing) = settings.slicing {
+        state.slicing = slicing;
+    }
+
+    if let Some(limit) = settings.memory {
+        memory::configure(limit);
+        state.memory = limit;
+    }
+
+    if let Some(interval) = settings.progress_interval {
+        progress::configure(interval);
+        state.progress_interval = interval;
+    }
+
     state.write();
     Ok(())
 }
 
+// Unsaved values from the settings form, anything missing is taken from the saved settings
+#[derive(Deserialize)]
+pub struct ConnectionSettings {
+    token: Option<String>,
+    auth_mode: Option<AuthMode>,
+    webhook_url: Option<String>,
+    channel: Option<String>,
+    guild: Option<String>,
+}
+
+#[tauri::command]
+pub async fn test_connection(
+    state: State<'_, AppState>,
+    settings: ConnectionSettings,
+) -> Result<String, ()> {
+    let state = state.read().await;
+    let mode = settings.auth_mode.unwrap_or(state.auth_mode);
+    let token = settings.token.as_deref().unwrap_or(&state.token).trim();
+    let token = match mode {
+        AuthMode::User => token,
+        AuthMode::Bot | AuthMode::Webhook => token.strip_prefix("Bot ").unwrap_or(token),
+    };
+
+    let webhook_url = settings
+        .webhook_url
+        .as_deref()
+        .unwrap_or(&state.webhook_url)
+        .trim();
+    let guild = settings.guild.as_deref().unwrap_or(&state.guild_id).trim();
+    let channel = settings
+        .channel
+        .as_deref()
+        .unwrap_or(&state.channel_id)
+        .trim();
+
+    let auth = validate_auth(mode, token, webhook_url).and_then(|()| {
+        mode.auth(token, token, webhook_url)
+            .map_err(|err| err.to_string())
+    });
+    let report = match auth {
+        Ok(auth) => connection::test(auth, guild, channel).await,
+        Err(err) => connection::Report::invalid(err),
+    };
+
+    Ok(serde_json::to_string(&report).unwrap())
+}
+
+#[tauri::command]
+pub async fn transfer_files(
+    state: State<'_, AppState>,
+    files: Vec<u32>,
+    target_profile: String,
+    delete_old: bool,
+) -> Result<(), ()> {
+    let mut state = state.write().await;
+    log::debug!("Transferring files to {}: {:?}", target_profile, files);
+    state.extend_transfer_queue(files, target_profile, delete_old);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_profiles(state: State<'_, AppState>) -> Result<String, ()> {
+    let state = state.read().await;
+    Ok(serde_json::to_string(&state.profiles()).unwrap())
+}
+
+#[tauri::command]
+pub async fn add_profile(
+    state: State<'_, AppState>,
+    name: String,
+    guild: String,
+    channel: String,
+) -> Result<(), String> {
+    let mut state = state.write().await;
+    state.add_profile(&name, &guild, &channel)?;
+    state.write();
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn switch_profile(state: State<'_, AppState>, name: String) -> Result<(), String> {
+    let mut state = state.write().await;
+    state.switch_profile(&name)?;
+    state.emit_profile();
+    state.write();
+
+    Ok(())
+}
+
 #[tauri::command]
 pub async fn cancel(state: State<'_, AppState>) -> Result<(), ()> {
     let mut state = state.write().await;
@@ -126,6 +536,34 @@ pub async fn cancel(state: State<'_, AppState>) -> Result<(), ()> {
     Ok(())
 }
 
+// Uploads and downloads left from the last run, for the frontend to offer resuming them
+#[tauri::command]
+pub async fn get_queue(state: State<'_, AppState>) -> Result<Vec<(Queued, Resume)>, ()> {
+    let state = state.read().await;
+    Ok(state.resumable())
+}
+
+// Hands back the entries that changed or vanished, none of them is queued
+#[tauri::command]
+pub async fn resume_queue(
+    state: State<'_, AppState>,
+    resume: bool,
+) -> Result<Vec<(Queued, Resume)>, String> {
+    let mut state = state.write().await;
+    state.resume_queue(resume)
+}
+
+// Polled instead of listening for the progress events, nothing while idle
+#[tauri::command]
+pub async fn get_progress(state: State<'_, AppState>) -> Result<Option<Report>, ()> {
+    let state = state.read().await;
+    if state.rt.job == Job::Idle {
+        return Ok(None);
+    }
+
+    Ok(progress::current())
+}
+
 #[tauri::command]
 pub async fn query(state: State<'_, AppState>, query: String) -> Result<Vec<u32>, ()> {
     let state = state.read().await;
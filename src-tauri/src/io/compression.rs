@@ -0,0 +1,53 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind};
+
+// A compressed copy of a file staged for upload, removed once dropped
+pub struct Compressed {
+    pub path: String,
+    pub level: i32,
+    pub original_size: u64,
+}
+
+impl Drop for Compressed {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path)
+            && err.kind() != ErrorKind::NotFound
+        {
+            log::error!("failed to remove staged file: {}", err);
+        }
+    }
+}
+
+pub fn compress(source: &str, target: String, level: i32) -> io::Result<Compressed> {
+    // The guard also cleans up a partially written file
+    let mut compressed = Compressed {
+        path: target,
+        level,
+        original_size: 0,
+    };
+
+    let mut reader = BufReader::new(File::open(source)?);
+    let writer = BufWriter::new(File::create(&compressed.path)?);
+
+    let mut encoder = zstd::Encoder::new(writer, level)?;
+    encoder.include_checksum(true)?;
+
+    compressed.original_size = io::copy(&mut reader, &mut encoder)?;
+    encoder
+        .finish()?
+        .into_inner()
+        .map_err(|err| err.into_error())?;
+
+    Ok(compressed)
+}
+
+pub fn decompress(source: &str, target: &str) -> io::Result<()> {
+    let reader = BufReader::new(File::open(source)?);
+    let mut writer = BufWriter::new(File::create(target)?);
+
+    let mut decoder = zstd::Decoder::new(reader)?;
+    io::copy(&mut decoder, &mut writer)?;
+    writer.into_inner().map_err(|err| err.into_error())?;
+
+    Ok(())
+}
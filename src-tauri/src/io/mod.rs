@@ -1,7 +1,14 @@
-use std::cell::UnsafeCell;
+use crate::throttle::Gate;
+
 use std::io;
+use std::ops::Deref;
 
-use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{self, AeadInPlace};
+use aes_gcm::{Aes256Gcm, KeyInit};
+use chacha20poly1305::ChaCha20Poly1305;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
 
 pub mod reader;
 pub mod writer;
@@ -9,15 +16,516 @@ pub mod writer;
 pub mod secure_reader;
 pub mod secure_writer;
 
+pub mod rekey;
+
+pub mod compression;
+pub mod parity;
+pub mod pool;
+pub mod positioned;
+pub mod snapshot;
+
 pub mod consts;
 
-pub struct Cipher(UnsafeCell<Aes256Gcm>);
+use consts::{AES_OVERHEAD, BUFFER_SIZE_I, CLUSTER_CAP, SLICE_SIZE};
+
+// Shared immutably, so the crypto threads can work on several buffers of a file at once
+pub struct Cipher(Aead);
+
+impl Cipher {
+    pub fn new(algorithm: Algorithm, key: &[u8; 32]) -> Self {
+        Self(Aead::new(algorithm, key))
+    }
+}
+
+impl Deref for Cipher {
+    type Target = Aead;
+
+    fn deref(&self) -> &Aead {
+        &self.0
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Algorithm {
+    #[default]
+    Aes256Gcm,
+    // Faster on machines without AES instructions
+    ChaCha20Poly1305,
+}
+
+// Both ciphers take 256 bit keys and 96 bit nonces and add a 128 bit tag, so the layout is the same
+pub enum Aead {
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+impl Aead {
+    pub fn new(algorithm: Algorithm, key: &[u8; 32]) -> Self {
+        match algorithm {
+            Algorithm::Aes256Gcm => Self::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))),
+            Algorithm::ChaCha20Poly1305 => {
+                Self::ChaCha20Poly1305(Box::new(ChaCha20Poly1305::new(key.into())))
+            }
+        }
+    }
+
+    pub fn encrypt_in_place(
+        &self,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), aead::Error> {
+        match self {
+            Self::Aes256Gcm(cipher) => cipher.encrypt_in_place(nonce.into(), aad, buffer),
+            Self::ChaCha20Poly1305(cipher) => cipher.encrypt_in_place(nonce.into(), aad, buffer),
+        }
+    }
+
+    pub fn decrypt_in_place(
+        &self,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), aead::Error> {
+        match self {
+            Self::Aes256Gcm(cipher) => cipher.decrypt_in_place(nonce.into(), aad, buffer),
+            Self::ChaCha20Poly1305(cipher) => cipher.decrypt_in_place(nonce.into(), aad, buffer),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    // Buffer counter as the nonce, no associated data
+    #[default]
+    V1,
+    // STREAM construction, the nonce flags the last buffer and the file id and size are authenticated
+    V2,
+}
+
+// How a file is cut into attachments and encrypted buffers, kept with every file so it is read
+// back the way it was written whatever the current settings are
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Slicing {
+    pub slice_size: u64,  // bytes per attachment, tags included
+    pub cluster_cap: u64, // attachments per message
+    pub buffer_size: u64, // bytes per encrypted buffer, tag included
+}
+
+// Every file stored before the slicing was recorded
+impl Default for Slicing {
+    fn default() -> Self {
+        Self {
+            slice_size: SLICE_SIZE,
+            cluster_cap: CLUSTER_CAP,
+            buffer_size: BUFFER_SIZE_I,
+        }
+    }
+}
+
+impl Slicing {
+    pub fn new(slice_size: u64) -> Self {
+        Self {
+            slice_size,
+            ..Self::default()
+        }
+    }
+
+    // Attachments are capped per guild, boosts raise the cap
+    pub fn for_tier(premium_tier: u8) -> Self {
+        match premium_tier {
+            0 | 1 => Self::default(),
+            2 => Self::new(SLICE_SIZE * 2),
+            _ => Self::new(SLICE_SIZE * 4),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.buffer_size <= AES_OVERHEAD || self.buffer_size > 1024 * 1024 * 16 {
+            return Err("The buffer size must be between 17 bytes and 16 MiB".to_owned());
+        }
+
+        if self.slice_size < self.buffer_size || self.slice_size > SLICE_SIZE * 20 {
+            return Err("The slice size must be between the buffer size and 500 MiB".to_owned());
+        }
+
+        if !(1..=CLUSTER_CAP).contains(&self.cluster_cap) {
+            return Err(format!(
+                "A message holds between 1 and {} attachments",
+                CLUSTER_CAP
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Total size of all attachments per message
+    pub fn cluster_size(&self) -> u64 {
+        self.slice_size * self.cluster_cap
+    }
+
+    // Plaintext bytes per encrypted buffer
+    pub fn raw_buffer_size(&self) -> u64 {
+        self.buffer_size - AES_OVERHEAD
+    }
+
+    // Number of buffers per slice (rounded up)
+    pub fn buffers_per_slice(&self) -> u64 {
+        self.slice_size.div_ceil(self.buffer_size)
+    }
+
+    // Number of plaintext bytes per encrypted slice
+    pub fn bytes_per_slice(&self) -> u64 {
+        self.slice_size - self.buffers_per_slice() * AES_OVERHEAD
+    }
+
+    // Plaintext bytes per encrypted cluster
+    pub fn bytes_per_cluster(&self) -> u64 {
+        self.bytes_per_slice() * self.cluster_cap
+    }
+
+    // Size of the uploaded stream, including the authentication tag of every buffer
+    pub fn encrypted_size(&self, size: u64) -> u64 {
+        size + self.buffer_count(size) * AES_OVERHEAD
+    }
+
+    // Number of encrypted buffers the file is split into
+    pub fn buffer_count(&self, size: u64) -> u64 {
+        let full_slices = size / self.bytes_per_slice();
+        let trailing_bytes = size - full_slices * self.bytes_per_slice();
+
+        full_slices * self.buffers_per_slice() + trailing_bytes.div_ceil(self.raw_buffer_size())
+    }
+}
+
+// Nonces and associated data of every encrypted buffer of a file
+#[derive(Debug)]
+pub struct Framing {
+    format: Format,
+    slicing: Slicing,
+    domain: u8, // tells apart streams that share a key, in the first byte of the nonce
+    aad: Vec<u8>,
+    size: u64,
+    buffers: u64,
+}
+
+impl Framing {
+    pub fn new(format: Format, id: u32, size: u64, slicing: Slicing) -> Self {
+        let aad = match format {
+            Format::V1 => Vec::new(),
+            Format::V2 => [
+                b"thunderstorm-v2".as_slice(),
+                &id.to_be_bytes(),
+                &size.to_be_bytes(),
+            ]
+            .concat(),
+        };
+
+        Self {
+            format,
+            slicing,
+            domain: 0,
+            aad,
+            size,
+            buffers: slicing.buffer_count(size),
+        }
+    }
+
+    // The parity stream is sealed with the key of the file it protects, so it needs its own nonces
+    pub fn parity(id: u32, size: u64, slicing: Slicing) -> Self {
+        let aad = [
+            b"thunderstorm-parity".as_slice(),
+            &id.to_be_bytes(),
+            &size.to_be_bytes(),
+        ]
+        .concat();
+
+        Self {
+            format: Format::V2,
+            slicing,
+            domain: 1,
+            aad,
+            size,
+            buffers: slicing.buffer_count(size),
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
 
-unsafe impl Send for Cipher {}
-unsafe impl Sync for Cipher {}
+    pub fn slicing(&self) -> Slicing {
+        self.slicing
+    }
+
+    pub fn aad(&self) -> &[u8] {
+        &self.aad
+    }
+
+    pub fn nonce(&self, index: u64) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        match self.format {
+            Format::V1 => nonce[4..].copy_from_slice(&index.to_be_bytes()),
+            Format::V2 => {
+                nonce[0] = self.domain;
+                nonce[3..11].copy_from_slice(&index.to_be_bytes());
+                nonce[11] = (index + 1 == self.buffers) as u8;
+            }
+        }
+
+        nonce
+    }
+
+    // Number of slices the file is stored in
+    pub fn slices(&self) -> u64 {
+        self.size.div_ceil(self.slicing.bytes_per_slice())
+    }
+
+    // Index one past the last buffer of the given slice
+    pub fn slice_end(&self, slice: u64) -> u64 {
+        std::cmp::min((slice + 1) * self.slicing.buffers_per_slice(), self.buffers)
+    }
+}
+
+// Bytes moved by a job, counted without a message queue that fills up or a task per buffer
+#[derive(Clone)]
+pub struct Progress(Option<mpsc::UnboundedSender<usize>>);
+
+impl Progress {
+    pub fn channel() -> (Self, mpsc::UnboundedReceiver<usize>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self(Some(tx)), rx)
+    }
+
+    // For copies whose progress isn't shown
+    pub fn none() -> Self {
+        Self(None)
+    }
+
+    pub fn add(&self, bytes: usize) {
+        if let Some(tx) = &self.0 {
+            // The job may be over already, nothing listens then
+            let _ = tx.send(bytes);
+        }
+    }
+}
 
 pub trait Cluster {
-    type Iter: Iterator<Item = Result<Vec<u8>, io::Error>>;
+    type Iter: Stream<Item = Result<Vec<u8>, io::Error>>;
 
     fn next_slice(&mut self) -> Option<Self::Iter>;
+
+    // Shared by every slice of the file, so its limit holds however many go at once
+    fn gate(&self) -> Gate;
+
+    // Every slice but the last one of the stream is this large
+    fn slice_size(&self) -> u64;
+
+    // Position of the cluster in the stream
+    fn index(&self) -> u64;
+
+    // Bytes of every slice together, as uploaded
+    fn size(&self) -> u64;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+
+    // Buffers don't divide the slice evenly, so the last one of every slice is short
+    const UNEVEN: Slicing = Slicing {
+        slice_size: 1000,
+        cluster_cap: 2,
+        buffer_size: 300,
+    };
+
+    #[test]
+    fn slicing_counts_buffers() {
+        let slicing = Slicing::default();
+        let raw = slicing.raw_buffer_size();
+
+        assert_eq!(slicing.buffer_count(0), 0);
+        assert_eq!(slicing.buffer_count(1), 1);
+        assert_eq!(slicing.buffer_count(raw), 1);
+        assert_eq!(slicing.buffer_count(raw + 1), 2);
+        assert_eq!(slicing.buffer_count(slicing.bytes_per_slice()), 25);
+        assert_eq!(slicing.buffer_count(slicing.bytes_per_slice() + 1), 26);
+    }
+
+    #[test]
+    fn slicing_fills_every_attachment() {
+        for slicing in [Slicing::default(), Slicing::for_tier(3), UNEVEN] {
+            let per_slice = slicing.bytes_per_slice();
+            assert_eq!(slicing.encrypted_size(per_slice), slicing.slice_size);
+            assert_eq!(
+                slicing.encrypted_size(per_slice * 3),
+                slicing.slice_size * 3
+            );
+            assert_eq!(
+                slicing.encrypted_size(slicing.bytes_per_cluster()),
+                slicing.cluster_size()
+            );
+        }
+    }
+
+    #[test]
+    fn slicing_with_a_short_last_buffer() {
+        assert_eq!(UNEVEN.buffers_per_slice(), 4);
+        assert_eq!(UNEVEN.bytes_per_slice(), 1000 - 4 * AES_OVERHEAD);
+        assert_eq!(UNEVEN.buffer_count(936), 4);
+        assert_eq!(UNEVEN.buffer_count(937), 5);
+        assert_eq!(UNEVEN.encrypted_size(937), 1000 + 1 + AES_OVERHEAD);
+    }
+
+    #[test]
+    fn slicing_validates_its_bounds() {
+        let slicing = |slice_size, cluster_cap, buffer_size| Slicing {
+            slice_size,
+            cluster_cap,
+            buffer_size,
+        };
+
+        assert!(Slicing::default().validate().is_ok());
+        assert!(UNEVEN.validate().is_ok());
+        assert!(slicing(17, 1, 17).validate().is_ok());
+        assert!(slicing(SLICE_SIZE * 20, CLUSTER_CAP, 1024 * 1024 * 16)
+            .validate()
+            .is_ok());
+
+        assert!(slicing(1000, 1, AES_OVERHEAD).validate().is_err());
+        assert!(slicing(SLICE_SIZE, 1, 1024 * 1024 * 16 + 1)
+            .validate()
+            .is_err());
+        assert!(slicing(299, 1, 300).validate().is_err());
+        assert!(slicing(SLICE_SIZE * 20 + 1, 1, 300).validate().is_err());
+        assert!(slicing(1000, 0, 300).validate().is_err());
+        assert!(slicing(1000, CLUSTER_CAP + 1, 300).validate().is_err());
+    }
+
+    #[test]
+    fn framing_flags_only_the_last_buffer() {
+        // Two full slices and a single byte in a third
+        let size = UNEVEN.bytes_per_slice() * 2 + 1;
+        let framing = Framing::new(Format::V2, 7, size, UNEVEN);
+        assert_eq!(framing.slices(), 3);
+        assert_eq!(framing.slice_end(2), 9);
+
+        for index in 0..8 {
+            let nonce = framing.nonce(index);
+            assert_eq!(nonce[0], 0);
+            assert_eq!(nonce[3..11], index.to_be_bytes());
+            assert_eq!(nonce[11], 0);
+        }
+
+        assert_eq!(framing.nonce(8)[11], 1);
+    }
+
+    #[test]
+    fn framing_keeps_streams_apart() {
+        let size = UNEVEN.bytes_per_slice();
+        let data = Framing::new(Format::V2, 7, size, UNEVEN);
+        let parity = Framing::parity(7, size, UNEVEN);
+        let other = Framing::new(Format::V2, 8, size, UNEVEN);
+
+        assert_eq!(parity.nonce(3)[0], 1);
+        assert_ne!(data.nonce(3), parity.nonce(3));
+        assert_ne!(data.aad(), parity.aad());
+        assert_ne!(data.aad(), other.aad());
+    }
+
+    #[test]
+    fn framing_v1_has_no_last_buffer_flag() {
+        let size = UNEVEN.bytes_per_slice();
+        let framing = Framing::new(Format::V1, 7, size, UNEVEN);
+
+        assert!(framing.aad().is_empty());
+        assert_eq!(framing.nonce(3)[4..], 3u64.to_be_bytes());
+        assert_eq!(framing.nonce(3)[..4], [0; 4]);
+    }
+
+    fn shared<T: Send + Sync>() {}
+
+    #[test]
+    fn engine_is_send_and_sync() {
+        shared::<Cipher>();
+        shared::<Framing>();
+        shared::<reader::InsecureReader>();
+        shared::<reader::InsecureSlice>();
+        shared::<secure_reader::SecureReader>();
+        shared::<secure_reader::SecureSlice>();
+        shared::<writer::InsecureWriter>();
+        shared::<secure_writer::SecureWriter>();
+        shared::<rekey::Rekeyer>();
+    }
+
+    #[test]
+    fn cipher_is_shared_across_threads() {
+        for algorithm in [Algorithm::Aes256Gcm, Algorithm::ChaCha20Poly1305] {
+            let cipher = Arc::new(Cipher::new(algorithm, &[7; 32]));
+            let framing = Arc::new(Framing::new(Format::V2, 7, 1 << 20, UNEVEN));
+
+            let threads = (0..8u64).map(|index| {
+                let cipher = cipher.clone();
+                let framing = framing.clone();
+                thread::spawn(move || {
+                    let plain = vec![index as u8; 100];
+                    let mut buffer = plain.clone();
+                    for _ in 0..100 {
+                        let nonce = framing.nonce(index);
+                        cipher
+                            .encrypt_in_place(&nonce, framing.aad(), &mut buffer)
+                            .unwrap();
+                        cipher
+                            .decrypt_in_place(&nonce, framing.aad(), &mut buffer)
+                            .unwrap();
+                    }
+
+                    buffer == plain
+                })
+            });
+
+            for thread in threads.collect::<Vec<_>>() {
+                assert!(thread.join().unwrap());
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn progress_adds_up_across_tasks() {
+        let (progress, mut rx) = Progress::channel();
+        let tasks = (0..16).map(|_| {
+            let progress = progress.clone();
+            tokio::spawn(async move {
+                for _ in 0..1000 {
+                    progress.add(3);
+                    tokio::task::yield_now().await;
+                }
+            })
+        });
+
+        for task in futures::future::join_all(tasks).await {
+            task.unwrap();
+        }
+
+        // The channel closes once the last sender is gone
+        drop(progress);
+        let mut done = 0;
+        while let Some(bytes) = rx.recv().await {
+            done += bytes;
+        }
+
+        assert_eq!(done, 16 * 1000 * 3);
+    }
+
+    #[test]
+    fn progress_outlives_its_receiver() {
+        let (progress, rx) = Progress::channel();
+        drop(rx);
+        progress.add(1);
+
+        Progress::none().add(1);
+    }
 }
@@ -0,0 +1,212 @@
+use super::consts::*;
+use super::Slicing;
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Error, Read, Seek, SeekFrom, Write};
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+
+// Every group of `data` clusters gets `parity` clusters, any `parity` of them can be lost
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct ErasureCode {
+    pub data: u8,
+    pub parity: u8,
+}
+
+impl ErasureCode {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.data == 0 || self.parity == 0 {
+            return Err("An erasure code needs at least one data and one parity shard".to_owned());
+        }
+
+        if self.data as u16 + self.parity as u16 > 256 {
+            return Err("An erasure code has at most 256 shards in total".to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+// Shards are whole clusters of the plaintext stream, the last cluster of a file is padded with zeros.
+// The parity stream holds the parity shards of every group one after another
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    code: ErasureCode,
+    size: u64,
+    cluster: u64, // plaintext bytes per cluster
+}
+
+impl Layout {
+    pub fn new(code: ErasureCode, size: u64, slicing: Slicing, encrypted: bool) -> Self {
+        let cluster = match encrypted {
+            true => slicing.bytes_per_cluster(),
+            false => slicing.cluster_size(),
+        };
+
+        Self {
+            code,
+            size,
+            cluster,
+        }
+    }
+
+    pub fn group(&self, cluster: u64) -> u64 {
+        cluster / self.code.data as u64
+    }
+
+    pub fn groups(&self) -> u64 {
+        self.size
+            .div_ceil(self.cluster)
+            .div_ceil(self.code.data as u64)
+    }
+
+    // Size of the parity stream
+    pub fn parity_size(&self) -> u64 {
+        let groups = self.groups();
+        let parity = self.code.parity as u64;
+
+        (groups - 1) * parity * self.cluster + parity * self.shard_len(groups - 1)
+    }
+
+    // Whether no group lost more clusters than it has parity for
+    pub fn is_recoverable(&self, erased: &[u64]) -> bool {
+        erased.iter().all(|cluster| {
+            let group = self.group(*cluster);
+            let lost = erased.iter().filter(|other| self.group(**other) == group);
+            lost.count() <= self.code.parity as usize
+        })
+    }
+
+    fn cluster_len(&self, cluster: u64) -> u64 {
+        cmp::min(
+            self.cluster,
+            self.size.saturating_sub(cluster * self.cluster),
+        )
+    }
+
+    // Only the last group can be shorter than a cluster, when the file fits in a single one
+    fn shard_len(&self, group: u64) -> u64 {
+        self.cluster_len(group * self.code.data as u64)
+    }
+
+    fn parity_offset(&self, group: u64, parity: u64) -> u64 {
+        group * self.code.parity as u64 * self.cluster + parity * self.shard_len(group)
+    }
+
+    fn codec(&self) -> io::Result<ReedSolomon> {
+        ReedSolomon::new(self.code.data as usize, self.code.parity as usize)
+            .map_err(|err| Error::other(err.to_string()))
+    }
+
+    // Reads a row of a data cluster, whatever lies past the end of the stream reads as zeros
+    fn read_row(
+        &self,
+        file: &mut File,
+        cluster: u64,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<Vec<u8>> {
+        let mut row = vec![0; len as usize];
+        let available = cmp::min(len, self.cluster_len(cluster).saturating_sub(offset));
+
+        if available > 0 {
+            file.seek(SeekFrom::Start(cluster * self.cluster + offset))?;
+            file.read_exact(&mut row[..available as usize])?;
+        }
+
+        Ok(row)
+    }
+
+    fn rows(&self, group: u64) -> impl Iterator<Item = (u64, u64)> {
+        let shard_len = self.shard_len(group);
+        (0..shard_len)
+            .step_by(BUFFER_SIZE_U)
+            .map(move |offset| (offset, cmp::min(BUFFER_SIZE_I, shard_len - offset)))
+    }
+}
+
+// Computes the parity stream of the plaintext stream at the source
+pub fn encode(layout: &Layout, source: &str, target: &str) -> io::Result<()> {
+    let codec = layout.codec()?;
+    let mut source = File::open(source)?;
+    let mut target = File::create(target)?;
+
+    let data = layout.code.data as u64;
+    for group in 0..layout.groups() {
+        for (offset, len) in layout.rows(group) {
+            let shards = (0..data)
+                .map(|shard| layout.read_row(&mut source, group * data + shard, offset, len))
+                .collect::<io::Result<Vec<_>>>()?;
+
+            let mut parity = vec![vec![0; len as usize]; layout.code.parity as usize];
+            codec
+                .encode_sep(&shards, &mut parity)
+                .map_err(|err| Error::other(err.to_string()))?;
+
+            for (index, shard) in parity.iter().enumerate() {
+                target.seek(SeekFrom::Start(
+                    layout.parity_offset(group, index as u64) + offset,
+                ))?;
+                target.write_all(shard)?;
+            }
+        }
+    }
+
+    target.flush()
+}
+
+// Rebuilds the erased clusters of the output in place from the rest of their group and the parity
+pub fn reconstruct(layout: &Layout, output: &str, parity: &str, erased: &[u64]) -> io::Result<()> {
+    let codec = layout.codec()?;
+    let mut output = OpenOptions::new().read(true).write(true).open(output)?;
+    let mut parity = File::open(parity)?;
+
+    let mut groups = erased
+        .iter()
+        .map(|cluster| layout.group(*cluster))
+        .collect::<Vec<_>>();
+    groups.sort_unstable();
+    groups.dedup();
+
+    let data = layout.code.data as u64;
+    for group in groups {
+        for (offset, len) in layout.rows(group) {
+            let mut shards = Vec::with_capacity(data as usize + layout.code.parity as usize);
+            for shard in 0..data {
+                let cluster = group * data + shard;
+                shards.push(match erased.contains(&cluster) {
+                    true => None,
+                    false => Some(layout.read_row(&mut output, cluster, offset, len)?),
+                });
+            }
+
+            for index in 0..layout.code.parity as u64 {
+                let mut row = vec![0; len as usize];
+                parity.seek(SeekFrom::Start(layout.parity_offset(group, index) + offset))?;
+                parity.read_exact(&mut row)?;
+                shards.push(Some(row));
+            }
+
+            codec
+                .reconstruct_data(&mut shards)
+                .map_err(|err| Error::other(err.to_string()))?;
+
+            for shard in 0..data {
+                let cluster = group * data + shard;
+                let available = cmp::min(len, layout.cluster_len(cluster).saturating_sub(offset));
+                if !erased.contains(&cluster) || available == 0 {
+                    continue;
+                }
+
+                let row = shards[shard as usize].as_ref().expect("shard is rebuilt");
+                output.seek(SeekFrom::Start(cluster * layout.cluster + offset))?;
+                output.write_all(&row[..available as usize])?;
+            }
+        }
+    }
+
+    output.set_len(layout.size)?;
+    output.flush()
+}
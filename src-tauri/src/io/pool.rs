@@ -0,0 +1,96 @@
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use tokio::sync::{mpsc, oneshot};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Encryption and decryption run here instead of on the runtime workers, one thread per core
+static POOL: OnceLock<mpsc::Sender<Job>> = OnceLock::new();
+
+// Jobs waiting for a thread, submitting more waits until one frees up
+const QUEUE_PER_THREAD: usize = 4;
+
+fn pool() -> &'static mpsc::Sender<Job> {
+    POOL.get_or_init(|| {
+        let threads = thread::available_parallelism().map_or(4, |threads| threads.get());
+        let (tx, rx) = mpsc::channel::<Job>(threads * QUEUE_PER_THREAD);
+        let rx = Arc::new(Mutex::new(rx));
+
+        log::debug!("Starting {} crypto threads", threads);
+        for index in 0..threads {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("crypto-{}", index))
+                .spawn(move || loop {
+                    // The lock is let go before the job runs, so the next thread can take one
+                    let job = rx
+                        .lock()
+                        .expect("failed to lock crypto queue")
+                        .blocking_recv();
+                    match job {
+                        Some(job) => job(),
+                        None => break,
+                    }
+                })
+                .expect("failed to spawn crypto thread");
+        }
+
+        tx
+    })
+}
+
+// Runs the job on the pool, several of them go in parallel across cores
+pub async fn run<T, F>(job: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let job: Job = Box::new(move || {
+        let _ = tx.send(job());
+    });
+
+    pool().send(job).await.expect("crypto pool stopped");
+
+    rx.await.expect("crypto job panicked")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Barrier;
+
+    use futures::future;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_job_hands_back_its_own_result() {
+        // Far more than the queue holds, so submitting has to wait for threads to free up
+        let jobs =
+            (0..1000u64).map(|job| run(move || (job, thread::current().name().map(str::to_owned))));
+
+        for (index, (job, name)) in future::join_all(jobs).await.into_iter().enumerate() {
+            assert_eq!(job, index as u64);
+            assert!(name.is_some_and(|name| name.starts_with("crypto-")));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn jobs_go_in_parallel() {
+        let threads = thread::available_parallelism().map_or(4, |threads| threads.get());
+        if threads < 2 {
+            return;
+        }
+
+        // Neither job gets past the barrier unless both run at once
+        let barrier = Arc::new(Barrier::new(2));
+        let jobs = (0..2).map(|_| {
+            let barrier = barrier.clone();
+            run(move || barrier.wait().is_leader())
+        });
+
+        let leaders = future::join_all(jobs).await;
+        assert_eq!(leaders.into_iter().filter(|leader| *leader).count(), 1);
+    }
+}
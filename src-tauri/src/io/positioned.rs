@@ -0,0 +1,282 @@
+use crate::memory;
+
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+
+use futures::FutureExt;
+use tokio::task;
+
+// Reads and writes at an offset without moving a shared cursor, so slices of the same file don't
+// take turns, and on the blocking pool so the runtime workers keep driving the network
+// The buffer keeps room for spare bytes after the data, so a tag doesn't reallocate it
+pub async fn read_at(
+    file: Arc<File>,
+    position: u64,
+    size: usize,
+    spare: usize,
+) -> io::Result<Vec<u8>> {
+    task::spawn_blocking(move || {
+        let mut buffer = memory::take(size + spare);
+        buffer.resize(size, 0);
+        read_exact_at(&file, &mut buffer, position)?;
+
+        Ok(buffer)
+    })
+    .map(flatten)
+    .await
+}
+
+// Hands the buffer back so it can be filled again
+pub async fn write_at(file: Arc<File>, buffer: Vec<u8>, position: u64) -> io::Result<Vec<u8>> {
+    task::spawn_blocking(move || {
+        write_all_at(&file, &buffer, position)?;
+
+        Ok(buffer)
+    })
+    .map(flatten)
+    .await
+}
+
+fn flatten<T>(result: Result<io::Result<T>, task::JoinError>) -> io::Result<T> {
+    result.unwrap_or_else(|err| Err(io::Error::other(err)))
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buffer: &mut [u8], position: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buffer, position)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buffer: &[u8], position: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buffer, position)
+}
+
+// seek_read and seek_write move the cursor too, but nothing relies on it
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buffer: &mut [u8], mut position: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buffer.is_empty() {
+        match file.seek_read(buffer, position) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => {
+                buffer = &mut buffer[read..];
+                position += read as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buffer: &[u8], mut position: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buffer.is_empty() {
+        match file.seek_write(buffer, position) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => {
+                buffer = &buffer[written..];
+                position += written as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::OpenOptions;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::mem;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    use futures::future;
+
+    const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+    const BUFFER: usize = 8 * 1024 * 1024;
+    const STREAMS: u64 = 8;
+
+    // Every stream goes over a part of its own, as the slices of a file do
+    fn parts(size: u64) -> impl Iterator<Item = (u64, u64)> {
+        let part = size / STREAMS;
+        (0..STREAMS).map(move |stream| (stream * part, (stream + 1) * part))
+    }
+
+    // How slices used to go, seeking a shared cursor under a lock on the runtime workers
+    async fn write_seeking(file: Arc<Mutex<File>>, size: u64) {
+        let tasks = parts(size).map(|(start, end)| {
+            let file = file.clone();
+            tokio::spawn(async move {
+                let buffer = vec![1u8; BUFFER];
+                for position in (start..end).step_by(BUFFER) {
+                    let mut file = file.lock().expect("failed to lock file");
+                    file.seek(SeekFrom::Start(position)).unwrap();
+                    file.write_all(&buffer).unwrap();
+                }
+            })
+        });
+
+        for task in future::join_all(tasks).await {
+            task.unwrap();
+        }
+    }
+
+    async fn read_seeking(file: Arc<Mutex<File>>, size: u64) {
+        let tasks = parts(size).map(|(start, end)| {
+            let file = file.clone();
+            tokio::spawn(async move {
+                let mut buffer = vec![0u8; BUFFER];
+                for position in (start..end).step_by(BUFFER) {
+                    let mut file = file.lock().expect("failed to lock file");
+                    file.seek(SeekFrom::Start(position)).unwrap();
+                    file.read_exact(&mut buffer).unwrap();
+                }
+            })
+        });
+
+        for task in future::join_all(tasks).await {
+            task.unwrap();
+        }
+    }
+
+    async fn write_positioned(file: Arc<File>, size: u64) {
+        let tasks = parts(size).map(|(start, end)| {
+            let file = file.clone();
+            tokio::spawn(async move {
+                let mut buffer = vec![2u8; BUFFER];
+                for position in (start..end).step_by(BUFFER) {
+                    buffer = write_at(file.clone(), buffer, position).await.unwrap();
+                }
+            })
+        });
+
+        for task in future::join_all(tasks).await {
+            task.unwrap();
+        }
+    }
+
+    async fn read_positioned(file: Arc<File>, size: u64) -> Vec<Vec<u8>> {
+        let tasks = parts(size).map(|(start, end)| {
+            let file = file.clone();
+            tokio::spawn(async move {
+                let mut last = Vec::new();
+                for position in (start..end).step_by(BUFFER) {
+                    let buffer = read_at(file.clone(), position, BUFFER, 0).await.unwrap();
+                    memory::give(mem::replace(&mut last, buffer));
+                }
+
+                last
+            })
+        });
+
+        future::join_all(tasks)
+            .await
+            .into_iter()
+            .map(|task| task.unwrap())
+            .collect()
+    }
+
+    // Megabytes a second
+    fn rate(size: u64, started: Instant) -> f64 {
+        size as f64 / started.elapsed().as_secs_f64() / 1_000_000.0
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn positioned_reads_back_its_writes() {
+        let path = env::temp_dir().join(format!(
+            "thunderstorm-positioned-small-{}",
+            std::process::id()
+        ));
+        let file = Arc::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .unwrap(),
+        );
+
+        let size = STREAMS * BUFFER as u64 * 2;
+        file.set_len(size).unwrap();
+        write_positioned(file.clone(), size).await;
+        for buffer in read_positioned(file, size).await {
+            assert_eq!(buffer, vec![2u8; BUFFER]);
+        }
+
+        assert!(read_at(Arc::new(File::open(&path).unwrap()), size, 1, 0)
+            .await
+            .is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // Run with `cargo test --release -- --ignored throughput`, the size in GiB can be set with
+    // THUNDERSTORM_BENCH_GIB
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn throughput_against_seeking() {
+        let gib = env::var("THUNDERSTORM_BENCH_GIB")
+            .ok()
+            .and_then(|gib| gib.parse().ok())
+            .unwrap_or(0.25);
+        let size = (gib * GIB) as u64 / (STREAMS * BUFFER as u64) * (STREAMS * BUFFER as u64);
+
+        let path = env::temp_dir().join(format!("thunderstorm-positioned-{}", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(size).unwrap();
+
+        let seeking = Arc::new(Mutex::new(file.try_clone().unwrap()));
+        let positioned = Arc::new(file);
+
+        let started = Instant::now();
+        write_seeking(seeking.clone(), size).await;
+        let write_seeking = rate(size, started);
+
+        let started = Instant::now();
+        write_positioned(positioned.clone(), size).await;
+        let write_positioned = rate(size, started);
+
+        let started = Instant::now();
+        read_seeking(seeking, size).await;
+        let read_seeking = rate(size, started);
+
+        let started = Instant::now();
+        read_positioned(positioned, size).await;
+        let read_positioned = rate(size, started);
+
+        std::fs::remove_file(path).unwrap();
+
+        // Only catches positioned I/O falling far behind. Against the page cache nothing waits on
+        // the disk, so seeking under the lock can still come out ahead there
+        assert!(
+            write_positioned >= write_seeking * 0.2,
+            "writes: {:.0} MB/s positioned, {:.0} MB/s seeking",
+            write_positioned,
+            write_seeking
+        );
+        assert!(
+            read_positioned >= read_seeking * 0.2,
+            "reads: {:.0} MB/s positioned, {:.0} MB/s seeking",
+            read_positioned,
+            read_seeking
+        );
+    }
+}
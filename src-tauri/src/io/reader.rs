@@ -1,46 +1,61 @@
 use super::consts::*;
-use super::Cluster;
+use super::{positioned, Cluster, Progress, Slicing};
+use crate::memory::{self, Reservation};
+use crate::throttle::Gate;
 
 use std::fs::File;
-use std::io::{self, Error, Read, Seek, SeekFrom};
-use std::sync::{Arc, Mutex};
+use std::io::{self, Error};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
 use std::{cmp, mem};
 
 use crc32fast::Hasher;
+use futures::future::BoxFuture;
+use futures::{FutureExt, Stream};
+use sync_wrapper::SyncFuture;
 use tokio::sync::mpsc;
 
 type CrcSender = mpsc::Sender<(u64, Hasher)>;
 
+// A buffer read from the file, along with the memory it holds
+type Buffer = (Vec<u8>, Reservation<'static>);
+
 pub struct InsecureReader {
-    file: Arc<Mutex<File>>,
+    file: Arc<File>,
+    slicing: Slicing,
     slices: usize,
     pub clusters: usize,
     cluster: usize,
     pub file_size: u64,
-    read_sender: mpsc::Sender<usize>,
+    read_sender: Progress,
     crc_sender: CrcSender,
+    gate: Gate,
 }
 
 impl InsecureReader {
     pub fn new<T: AsRef<str>>(
         path: T,
-        read_sender: mpsc::Sender<usize>,
+        slicing: Slicing,
+        read_sender: Progress,
         crc_sender: CrcSender,
     ) -> io::Result<Self> {
         let file = File::open(path.as_ref())?;
         let size = file.metadata()?.len();
 
-        let slices = (size + SLICE_SIZE - 1) / SLICE_SIZE;
-        let clusters = (slices + CLUSTER_CAP - 1) / CLUSTER_CAP;
+        let slices = size.div_ceil(slicing.slice_size);
+        let clusters = slices.div_ceil(slicing.cluster_cap);
 
         Ok(Self {
-            file: Arc::new(Mutex::new(file)),
+            file: Arc::new(file),
+            slicing,
             slices: slices as usize,
             clusters: clusters as usize,
             cluster: 0,
             file_size: size,
             read_sender,
             crc_sender,
+            gate: Gate::upload(),
         })
     }
 
@@ -49,42 +64,42 @@ impl InsecureReader {
             return None;
         }
 
-        let slices = cmp::min(
-            CLUSTER_CAP as usize,
-            self.slices - self.cluster * CLUSTER_CAP as usize,
-        );
+        let cap = self.slicing.cluster_cap as usize;
+        let slices = cmp::min(cap, self.slices - self.cluster * cap);
 
         self.cluster += 1;
         Some(InsecureClusterR {
             file: self.file.clone(),
+            slicing: self.slicing,
             file_size: self.file_size,
             local_total_slices: slices,
             slice_counter: 0,
             cluster_index: self.cluster as u64 - 1,
             read_sender: self.read_sender.clone(),
             crc_sender: self.crc_sender.clone(),
+            gate: self.gate.clone(),
         })
     }
 }
 
 pub struct InsecureClusterR {
-    file: Arc<Mutex<File>>,
+    file: Arc<File>,
+    slicing: Slicing,
     file_size: u64,
     local_total_slices: usize,
     slice_counter: usize,
     pub cluster_index: u64,
-    read_sender: mpsc::Sender<usize>,
+    read_sender: Progress,
     crc_sender: CrcSender,
+    gate: Gate,
 }
 
-unsafe impl Send for InsecureClusterR {}
-unsafe impl Sync for InsecureClusterR {}
-
 impl InsecureClusterR {
     pub fn get_size(&self) -> u64 {
+        let cluster_size = self.slicing.cluster_size();
         cmp::min(
-            CLUSTER_SIZE,
-            self.file_size - self.cluster_index * CLUSTER_SIZE,
+            cluster_size,
+            self.file_size - self.cluster_index * cluster_size,
         )
     }
 }
@@ -102,34 +117,52 @@ impl Cluster for InsecureClusterR {
             return None;
         }
 
-        let slice_index = self.cluster_index * CLUSTER_CAP + self.slice_counter as u64;
+        let slice_index = self.cluster_index * self.slicing.cluster_cap + self.slice_counter as u64;
         self.slice_counter += 1;
 
         Some(InsecureSlice {
             file: self.file.clone(),
+            slice_size: self.slicing.slice_size,
             position_in_slice: 0,
             file_size: self.file_size,
             slice_index,
             read_sender: self.read_sender.clone(),
             crc_sender: self.crc_sender.clone(),
             crc32: Hasher::new(),
+            pending: None,
         })
     }
+
+    fn gate(&self) -> Gate {
+        self.gate.clone()
+    }
+
+    fn slice_size(&self) -> u64 {
+        self.slicing.slice_size
+    }
+
+    fn index(&self) -> u64 {
+        self.cluster_index
+    }
+
+    fn size(&self) -> u64 {
+        self.get_size()
+    }
 }
 
 pub struct InsecureSlice {
-    file: Arc<Mutex<File>>,
+    file: Arc<File>,
+    slice_size: u64,
     position_in_slice: u64,
     file_size: u64,
     slice_index: u64,
-    read_sender: mpsc::Sender<usize>,
+    read_sender: Progress,
     crc_sender: CrcSender,
     crc32: Hasher,
+    // The buffer being read, the request body has to be Sync and it's only polled through &mut
+    pending: Option<SyncFuture<BoxFuture<'static, io::Result<Buffer>>>>,
 }
 
-unsafe impl Send for InsecureSlice {}
-unsafe impl Sync for InsecureSlice {}
-
 impl InsecureSlice {
     fn send_crc(&mut self) {
         let sender = mem::replace(&mut self.crc_sender, mpsc::channel(1).0);
@@ -144,45 +177,55 @@ impl InsecureSlice {
     }
 }
 
-impl Iterator for InsecureSlice {
+impl Stream for InsecureSlice {
     type Item = Result<Vec<u8>, Error>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.position_in_slice == SLICE_SIZE {
-            self.send_crc();
-            return None;
-        }
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.pending.is_none() {
+            if self.position_in_slice == self.slice_size {
+                self.send_crc();
+                return Poll::Ready(None);
+            }
 
-        let position = self.position_in_slice + self.slice_index * SLICE_SIZE;
+            let position = self.position_in_slice + self.slice_index * self.slice_size;
 
-        let buffer_size = cmp::min(self.file_size.saturating_sub(position), BUFFER_SIZE_I);
-        let buffer_size = cmp::min(SLICE_SIZE, buffer_size);
+            let buffer_size = cmp::min(self.file_size.saturating_sub(position), BUFFER_SIZE_I);
+            let buffer_size = cmp::min(self.slice_size - self.position_in_slice, buffer_size);
 
-        if buffer_size == 0 {
-            self.send_crc();
-            return None;
-        }
+            if buffer_size == 0 {
+                self.send_crc();
+                return Poll::Ready(None);
+            }
+
+            let file = self.file.clone();
+            let read = async move {
+                let reservation = memory::reserve(buffer_size as usize).await;
+                let buffer = positioned::read_at(file, position, buffer_size as usize, 0).await?;
 
-        let mut buffer = vec![0; buffer_size as usize];
-        let mut file = self.file.lock().unwrap();
+                Ok((buffer, reservation))
+            };
 
-        file.seek(SeekFrom::Start(position))
-            .expect("Failed to seek file");
+            self.pending = Some(SyncFuture::new(read.boxed()));
+            self.position_in_slice += buffer_size;
+        }
+
+        let read = ready!(self.pending.as_mut().unwrap().poll_unpin(cx));
+        self.pending = None;
 
-        file.read_exact(&mut buffer).expect("Failed to read file");
-        drop(file);
+        // The memory is let go as the buffer is handed to the request body
+        let buffer = match read {
+            Ok((buffer, _)) => buffer,
+            Err(err) => {
+                log::error!("Failed to read file: {:?}", err);
+                return Poll::Ready(Some(Err(err)));
+            }
+        };
 
         self.crc32.update(&buffer);
 
         let size = buffer.len();
-        let sender = self.read_sender.clone();
-        tokio::spawn(async move {
-            if let Err(err) = sender.send(size).await {
-                log::error!("Failed to send read size: {:?}", err);
-            }
-        });
+        self.read_sender.add(size);
 
-        self.position_in_slice += buffer_size;
-        Some(Ok(buffer))
+        Poll::Ready(Some(Ok(buffer)))
     }
 }
@@ -0,0 +1,207 @@
+use super::{pool, Cipher, Format, Framing, Progress};
+use crate::api;
+
+use std::collections::VecDeque;
+use std::io::{self, Error};
+use std::sync::Arc;
+use std::{cmp, mem};
+
+use bytes::Bytes;
+use crc32fast::Hasher;
+use futures::stream::{self, BoxStream};
+use futures::{Stream, StreamExt};
+use sync_wrapper::SyncStream;
+use tokio::sync::mpsc;
+
+type CrcSender = mpsc::Sender<(u64, Hasher)>;
+
+// Re-encrypts a stored file slice by slice, the plaintext never touches the disk.
+// The slicing does not change, so every slice maps onto a slice of the same size
+pub struct Rekeyer {
+    old: Arc<Cipher>,
+    old_framing: Arc<Framing>,
+    new: Arc<Cipher>,
+    new_framing: Arc<Framing>,
+    progress_tx: Progress,
+    crc_tx: CrcSender,
+}
+
+impl Rekeyer {
+    pub fn new(
+        old: Cipher,
+        old_framing: Framing,
+        new: Cipher,
+        new_framing: Framing,
+        progress_tx: Progress,
+        crc_tx: CrcSender,
+    ) -> Self {
+        Self {
+            old: Arc::new(old),
+            old_framing: Arc::new(old_framing),
+            new: Arc::new(new),
+            new_framing: Arc::new(new_framing),
+            progress_tx,
+            crc_tx,
+        }
+    }
+
+    // Streams the slice from the given url, yielding it encrypted with the new key
+    pub fn slice(
+        &self,
+        url: String,
+        cluster: u64,
+        slice: u64,
+    ) -> impl Stream<Item = Result<Vec<u8>, Error>> + Send + Sync + 'static {
+        let slicing = self.old_framing.slicing();
+        let slice = cluster * slicing.cluster_cap + slice;
+        let state = RekeySlice {
+            url: Some(url),
+            stream: None,
+            old: self.old.clone(),
+            old_framing: self.old_framing.clone(),
+            new: self.new.clone(),
+            new_framing: self.new_framing.clone(),
+            slice,
+            index: slice * slicing.buffers_per_slice(),
+            buffer_size: slicing.buffer_size as usize,
+            buffer: Vec::with_capacity(slicing.buffer_size as usize),
+            ready: VecDeque::new(),
+            done: false,
+            progress_tx: self.progress_tx.clone(),
+            crc_tx: self.crc_tx.clone(),
+            crc32: Hasher::new(),
+        };
+
+        let stream = stream::unfold(state, |mut state| async move {
+            let item = state.next().await?;
+            Some((item, state))
+        });
+
+        // The request body has to be Sync, the stream is only polled through a unique reference
+        SyncStream::new(stream.boxed())
+    }
+}
+
+struct RekeySlice {
+    url: Option<String>,
+    stream: Option<BoxStream<'static, reqwest::Result<Bytes>>>,
+    old: Arc<Cipher>,
+    old_framing: Arc<Framing>,
+    new: Arc<Cipher>,
+    new_framing: Arc<Framing>,
+    slice: u64, // index of this slice
+    index: u64, // index of the current buffer
+    buffer_size: usize,
+    buffer: Vec<u8>,
+    ready: VecDeque<Vec<u8>>,
+    done: bool,
+    progress_tx: Progress,
+    crc_tx: CrcSender,
+    crc32: Hasher,
+}
+
+impl RekeySlice {
+    async fn next(&mut self) -> Option<Result<Vec<u8>, Error>> {
+        loop {
+            if let Some(buffer) = self.ready.pop_front() {
+                return Some(Ok(buffer));
+            }
+
+            if self.done {
+                return None;
+            }
+
+            if let Some(url) = self.url.take() {
+                match api::download(url).await {
+                    Ok(response) => self.stream = Some(response.bytes_stream().boxed()),
+                    Err(err) => {
+                        self.done = true;
+                        return Some(Err(Error::other(err.to_string())));
+                    }
+                }
+            }
+
+            let stream = self.stream.as_mut().expect("stream is opened with the url");
+            match stream.next().await {
+                Some(Ok(chunk)) => {
+                    let mut cursor = 0;
+                    while cursor < chunk.len() {
+                        let available =
+                            cmp::min(self.buffer_size - self.buffer.len(), chunk.len() - cursor);
+                        self.buffer
+                            .extend_from_slice(&chunk[cursor..cursor + available]);
+                        cursor += available;
+
+                        if self.buffer.len() == self.buffer_size
+                            && let Err(err) = self.rekey().await
+                        {
+                            self.done = true;
+                            return Some(Err(err));
+                        }
+                    }
+                }
+                Some(Err(err)) => {
+                    self.done = true;
+                    return Some(Err(Error::other(err)));
+                }
+                None => {
+                    self.done = true;
+                    if !self.buffer.is_empty()
+                        && let Err(err) = self.rekey().await
+                    {
+                        return Some(Err(err));
+                    }
+
+                    if self.old_framing.format() == Format::V2
+                        && self.index != self.old_framing.slice_end(self.slice)
+                    {
+                        return Some(Err(Error::other(format!(
+                            "Slice {} is truncated",
+                            self.slice
+                        ))));
+                    }
+
+                    let hasher = mem::replace(&mut self.crc32, Hasher::new());
+                    if let Err(err) = self.crc_tx.send((self.slice, hasher)).await {
+                        log::error!("Failed to send crc: {:?}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn rekey(&mut self) -> io::Result<()> {
+        let mut buffer = mem::replace(&mut self.buffer, Vec::with_capacity(self.buffer_size));
+
+        let old = self.old.clone();
+        let old_framing = self.old_framing.clone();
+        let new = self.new.clone();
+        let new_framing = self.new_framing.clone();
+        let index = self.index;
+
+        // Both ciphers run on the crypto threads, the plaintext is only seen there
+        let (buffer, hasher, size) = pool::run(move || {
+            let nonce = old_framing.nonce(index);
+            old.decrypt_in_place(&nonce, old_framing.aad(), &mut buffer)
+                .map_err(|err| Error::other(format!("Failed to decrypt buffer: {:?}", err)))?;
+
+            let mut hasher = Hasher::new();
+            hasher.update(&buffer);
+            let size = buffer.len();
+
+            let nonce = new_framing.nonce(index);
+            new.encrypt_in_place(&nonce, new_framing.aad(), &mut buffer)
+                .map_err(|err| Error::other(format!("Failed to encrypt buffer: {:?}", err)))?;
+
+            Ok::<_, Error>((buffer, hasher, size))
+        })
+        .await?;
+
+        self.crc32.combine(&hasher);
+        self.progress_tx.add(size);
+
+        self.ready.push_back(buffer);
+        self.index += 1;
+        Ok(())
+    }
+}
@@ -1,67 +1,107 @@
-use super::consts::*;
-use super::Cluster;
+use super::consts::{AES_OVERHEAD, CRYPTO_DEPTH};
+use super::{pool, positioned, Cipher, Cluster, Format, Framing, Progress, Slicing};
+use crate::memory::{self, Reservation};
+use crate::throttle::Gate;
 
-use std::cell::UnsafeCell;
 use std::fs::File;
-use std::io::{self, Error, ErrorKind, Read, Seek, SeekFrom};
-use std::sync::{Arc, Mutex};
+use std::io::{self, Error};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
 use std::{cmp, mem};
 
-use aes_gcm::aead::AeadMutInPlace;
-use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
 use crc32fast::Hasher;
+use futures::future::BoxFuture;
+use futures::stream::FuturesOrdered;
+use futures::{FutureExt, Stream, StreamExt};
+use sync_wrapper::SyncWrapper;
 use tokio::sync::mpsc;
 
 type CrcSender = mpsc::Sender<(u64, Hasher)>;
 
+// A buffer ready to go, along with the checksum of its plaintext and the memory it holds
+type Encrypted = (Vec<u8>, Hasher, Reservation<'static>);
+
 pub struct SecureReader {
-    file: Arc<Mutex<File>>,
-    cipher: Arc<UnsafeCell<Aes256Gcm>>,
+    file: Arc<File>,
+    cipher: Arc<Cipher>,
+    framing: Arc<Framing>,
+    slicing: Slicing,
     slices: usize,
     pub clusters: usize,
     cluster: usize,
     pub file_size: u64,
     final_size: u64,
-    read_sender: mpsc::Sender<usize>,
+    read_sender: Progress,
     crc_sender: CrcSender,
+    gate: Gate,
 }
 
-unsafe impl Send for SecureReader {}
-unsafe impl Sync for SecureReader {}
-
 impl SecureReader {
     pub fn new<T: AsRef<str>>(
         path: T,
-        key: &[u8; 32],
-        read_sender: mpsc::Sender<usize>,
+        cipher: Cipher,
+        format: Format,
+        id: u32,
+        slicing: Slicing,
+        read_sender: Progress,
         crc_sender: CrcSender,
     ) -> io::Result<Self> {
-        let file = File::open(path.as_ref())?;
-        let size = file.metadata()?.len();
+        Self::open(
+            path,
+            cipher,
+            |size| Framing::new(format, id, size, slicing),
+            read_sender,
+            crc_sender,
+        )
+    }
 
-        let slices = (size + BYTES_PER_SLICE - 1) / BYTES_PER_SLICE;
-        let full_slices = size / BYTES_PER_SLICE;
-        let clusters = (slices + CLUSTER_CAP - 1) / CLUSTER_CAP;
+    // Reads the parity stream of the file with the given id
+    pub fn parity<T: AsRef<str>>(
+        path: T,
+        cipher: Cipher,
+        id: u32,
+        slicing: Slicing,
+        read_sender: Progress,
+        crc_sender: CrcSender,
+    ) -> io::Result<Self> {
+        Self::open(
+            path,
+            cipher,
+            |size| Framing::parity(id, size, slicing),
+            read_sender,
+            crc_sender,
+        )
+    }
 
-        let trailing_bytes = size - full_slices * BYTES_PER_SLICE;
-        let trailing_buffers = (trailing_bytes + RAW_BUFFER_SIZE - 1) / RAW_BUFFER_SIZE;
-        let encrypted_size =
-            size + full_slices * BUFFERS_PER_SLICE * AES_OVERHEAD + trailing_buffers * AES_OVERHEAD;
+    fn open<T: AsRef<str>>(
+        path: T,
+        cipher: Cipher,
+        framing: impl FnOnce(u64) -> Framing,
+        read_sender: Progress,
+        crc_sender: CrcSender,
+    ) -> io::Result<Self> {
+        let file = File::open(path.as_ref())?;
+        let size = file.metadata()?.len();
+        let framing = framing(size);
+        let slicing = framing.slicing();
 
-        let key = Key::<Aes256Gcm>::from_slice(key);
-        let cipher = Aes256Gcm::new(key);
+        let slices = size.div_ceil(slicing.bytes_per_slice());
+        let clusters = slices.div_ceil(slicing.cluster_cap);
 
         Ok(Self {
-            file: Arc::new(Mutex::new(file)),
-            #[allow(clippy::arc_with_non_send_sync)]
-            cipher: Arc::new(UnsafeCell::new(cipher)),
+            file: Arc::new(file),
+            cipher: Arc::new(cipher),
+            framing: Arc::new(framing),
+            slicing,
             slices: slices as usize,
             clusters: clusters as usize,
             cluster: 0,
             file_size: size,
-            final_size: encrypted_size,
+            final_size: slicing.encrypted_size(size),
             read_sender,
             crc_sender,
+            gate: Gate::upload(),
         })
     }
 
@@ -71,15 +111,15 @@ impl SecureReader {
             return None;
         }
 
-        let slices = cmp::min(
-            CLUSTER_CAP as usize,
-            self.slices - self.cluster * CLUSTER_CAP as usize,
-        );
+        let cap = self.slicing.cluster_cap as usize;
+        let slices = cmp::min(cap, self.slices - self.cluster * cap);
 
         self.cluster += 1;
         Some(SecureClusterR {
             file: self.file.clone(),
             cipher: self.cipher.clone(),
+            framing: self.framing.clone(),
+            slicing: self.slicing,
             file_size: self.file_size,
             slices,
             slice: 0,
@@ -87,28 +127,30 @@ impl SecureReader {
             final_size: self.final_size,
             read_sender: self.read_sender.clone(),
             crc_sender: self.crc_sender.clone(),
+            gate: self.gate.clone(),
         })
     }
 }
 
 pub struct SecureClusterR {
-    file: Arc<Mutex<File>>,
-    cipher: Arc<UnsafeCell<Aes256Gcm>>,
+    file: Arc<File>,
+    cipher: Arc<Cipher>,
+    framing: Arc<Framing>,
+    slicing: Slicing,
     file_size: u64,
     slices: usize,
     slice: usize,
     pub index: u64, // index of the current cluster
     final_size: u64,
-    read_sender: mpsc::Sender<usize>,
+    read_sender: Progress,
     crc_sender: CrcSender,
+    gate: Gate,
 }
 
-unsafe impl Send for SecureClusterR {}
-unsafe impl Sync for SecureClusterR {}
-
 impl SecureClusterR {
     pub fn get_size(&self) -> u64 {
-        cmp::min(CLUSTER_SIZE, self.final_size - self.index * CLUSTER_SIZE)
+        let cluster_size = self.slicing.cluster_size();
+        cmp::min(cluster_size, self.final_size - self.index * cluster_size)
     }
 }
 
@@ -121,40 +163,59 @@ impl Cluster for SecureClusterR {
             return None;
         }
 
-        let this_slice = self.index * CLUSTER_CAP + self.slice as u64;
+        let this_slice = self.index * self.slicing.cluster_cap + self.slice as u64;
         self.slice += 1;
 
         Some(SecureSlice {
             file: self.file.clone(),
             cipher: self.cipher.clone(),
-            position: this_slice * BYTES_PER_SLICE,
+            framing: self.framing.clone(),
+            slicing: self.slicing,
+            position: this_slice * self.slicing.bytes_per_slice(),
             file_size: self.file_size,
             slice: this_slice,
             index: 0,
-            nonce: [0; 12],
             read_sender: self.read_sender.clone(),
             crc_sender: self.crc_sender.clone(),
             crc32: Hasher::new(),
+            pending: SyncWrapper::new(FuturesOrdered::new()),
         })
     }
+
+    fn gate(&self) -> Gate {
+        self.gate.clone()
+    }
+
+    fn slice_size(&self) -> u64 {
+        self.slicing.slice_size
+    }
+
+    fn index(&self) -> u64 {
+        self.index
+    }
+
+    fn size(&self) -> u64 {
+        self.get_size()
+    }
 }
 
 pub struct SecureSlice {
-    file: Arc<Mutex<File>>,
-    cipher: Arc<UnsafeCell<Aes256Gcm>>,
+    file: Arc<File>,
+    cipher: Arc<Cipher>,
+    framing: Arc<Framing>,
+    slicing: Slicing,
     position: u64,
     file_size: u64,
     slice: u64, // index of this slice
-    index: u64, // index of the current buffer
-    nonce: [u8; 12],
-    read_sender: mpsc::Sender<usize>,
+    index: u64, // index of the next buffer to read
+    read_sender: Progress,
     crc_sender: CrcSender,
     crc32: Hasher,
+    // Buffers being read and encrypted, the request body has to be Sync and it's only touched
+    // through &mut
+    pending: SyncWrapper<FuturesOrdered<BoxFuture<'static, io::Result<Encrypted>>>>,
 }
 
-unsafe impl Send for SecureSlice {}
-unsafe impl Sync for SecureSlice {}
-
 impl SecureSlice {
     fn send_crc(&mut self) {
         let sender = mem::replace(&mut self.crc_sender, mpsc::channel(1).0);
@@ -167,22 +228,20 @@ impl SecureSlice {
             }
         });
     }
-}
-
-impl Iterator for SecureSlice {
-    type Item = Result<Vec<u8>, Error>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.index == BUFFERS_PER_SLICE {
-            self.send_crc();
+    // Reads the next buffer and encrypts it on the crypto threads
+    fn next_buffer(&mut self) -> Option<BoxFuture<'static, io::Result<Encrypted>>> {
+        if self.index == self.slicing.buffers_per_slice() {
             return None;
         }
 
-        let slice_position = self.index * RAW_BUFFER_SIZE;
+        let slice_position = self.index * self.slicing.raw_buffer_size();
 
         let buffer_size = cmp::min(
-            RAW_BUFFER_SIZE,
-            BYTES_PER_SLICE.saturating_sub(slice_position),
+            self.slicing.raw_buffer_size(),
+            self.slicing
+                .bytes_per_slice()
+                .saturating_sub(slice_position),
         );
 
         let buffer_size = cmp::min(
@@ -191,44 +250,71 @@ impl Iterator for SecureSlice {
         );
 
         if buffer_size == 0 {
-            self.send_crc();
             return None;
         }
 
-        let mut buffer = vec![0; buffer_size as usize];
-        let mut file = self.file.lock().expect("Failed to lock file");
-
-        file.seek(SeekFrom::Start(self.position + slice_position))
-            .expect("Failed to seek file");
-
-        file.read_exact(&mut buffer).expect("Failed to read file");
-        drop(file);
+        let file = self.file.clone();
+        let position = self.position + slice_position;
+        let size = buffer_size as usize;
 
-        self.crc32.update(&buffer);
+        let cipher = self.cipher.clone();
+        let framing = self.framing.clone();
+        let nonce = framing.nonce(self.slice * self.slicing.buffers_per_slice() + self.index);
+        self.index += 1;
 
-        let size = buffer.len();
-        let sender = self.read_sender.clone();
-        tokio::spawn(async move {
-            if let Err(err) = sender.send(size).await {
-                log::error!("Failed to send buffer size: {:?}", err);
+        Some(
+            async move {
+                let reservation = memory::reserve(size + AES_OVERHEAD as usize).await;
+                let read = positioned::read_at(file, position, size, AES_OVERHEAD as usize).await;
+                let mut buffer = read.inspect_err(|err| {
+                    log::error!("Failed to read file: {:?}", err);
+                })?;
+
+                pool::run(move || {
+                    let mut hasher = Hasher::new();
+                    hasher.update(&buffer);
+
+                    cipher
+                        .encrypt_in_place(&nonce, framing.aad(), &mut buffer)
+                        .map(|_| (buffer, hasher, reservation))
+                        .map_err(|err| {
+                            log::error!("Failed to encrypt buffer: {:?}", err);
+                            Error::other(format!("Failed to encrypt buffer: {:?}", err))
+                        })
+                })
+                .await
             }
-        });
+            .boxed(),
+        )
+    }
+}
 
-        self.nonce[4..]
-            .copy_from_slice(&(self.slice * BUFFERS_PER_SLICE + self.index).to_be_bytes());
-        let nonce = Nonce::from(self.nonce);
+impl Stream for SecureSlice {
+    type Item = Result<Vec<u8>, Error>;
 
-        if let Err(err) =
-            unsafe { &mut *self.cipher.get() }.encrypt_in_place(&nonce, b"", &mut buffer)
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // The next buffers are read and encrypted while the current one goes out
+        while self.pending.get_mut().len() < CRYPTO_DEPTH
+            && let Some(buffer) = self.next_buffer()
         {
-            log::error!("Failed to encrypt buffer: {:?}", err);
-            return Some(Err(Error::new(
-                ErrorKind::Other,
-                format!("Failed to encrypt buffer: {:?}", err),
-            )));
+            self.pending.get_mut().push_back(buffer);
         }
 
-        self.index += 1;
-        Some(Ok(buffer))
+        // The memory is let go as the buffer is handed to the request body
+        let (buffer, hasher, _) = match ready!(self.pending.get_mut().poll_next_unpin(cx)) {
+            Some(Ok(encrypted)) => encrypted,
+            Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+            None => {
+                self.send_crc();
+                return Poll::Ready(None);
+            }
+        };
+
+        self.crc32.combine(&hasher);
+
+        let size = buffer.len() - AES_OVERHEAD as usize;
+        self.read_sender.add(size);
+
+        Poll::Ready(Some(Ok(buffer)))
     }
 }
@@ -1,49 +1,47 @@
-use super::consts::*;
-use super::Cipher;
+use super::consts::CRYPTO_DEPTH;
+use super::{pool, positioned, Cipher, Format, Framing, Progress};
 use crate::api;
 use crate::errors::DownloadError;
+use crate::memory;
+use crate::throttle::Gate;
 
-use std::cell::UnsafeCell;
 use std::fs::File;
-use std::io::{Seek, SeekFrom, Write};
 use std::path::Path;
 use std::sync::Arc;
-use std::{cmp, io};
+use std::{cmp, io, mem};
 
-use aes_gcm::aead::AeadMutInPlace;
-use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
 use crc32fast::Hasher;
+use futures::stream::FuturesOrdered;
 use futures::{future, StreamExt};
-use tokio::sync::{mpsc, Mutex};
+use tokio::select;
+use tokio::sync::mpsc;
 
 type CrcSender = Option<mpsc::Sender<(u64, Hasher)>>;
 
 pub struct SecureWriter {
-    file: Arc<Mutex<File>>,
+    file: Arc<File>,
     cipher: Arc<Cipher>,
-    write_tx: mpsc::Sender<usize>,
+    framing: Arc<Framing>,
+    write_tx: Progress,
     crc_tx: CrcSender,
+    gate: Gate,
 }
 
-unsafe impl Send for SecureWriter {}
-unsafe impl Sync for SecureWriter {}
-
 impl SecureWriter {
     pub fn new<T: AsRef<Path>>(
         path: T,
-        key: &[u8; 32],
-        write_sender: mpsc::Sender<usize>,
+        cipher: Cipher,
+        framing: Framing,
+        write_sender: Progress,
         crc_sender: CrcSender,
     ) -> io::Result<Self> {
-        let key = Key::<Aes256Gcm>::from_slice(key);
-        let cipher = Aes256Gcm::new(key);
-
         Ok(Self {
-            file: Arc::new(Mutex::new(File::create(path)?)),
-            #[allow(clippy::arc_with_non_send_sync)]
-            cipher: Arc::new(Cipher(UnsafeCell::new(cipher))),
+            file: Arc::new(File::create(path)?),
+            cipher: Arc::new(cipher),
+            framing: Arc::new(framing),
             write_tx: write_sender,
             crc_tx: crc_sender,
+            gate: Gate::download(),
         })
     }
 
@@ -51,37 +49,63 @@ impl SecureWriter {
         SecureClusterW {
             file: self.file.clone(),
             cipher: self.cipher.clone(),
+            framing: self.framing.clone(),
             index,
             urls: download_urls,
             write_sender: self.write_tx.clone(),
             crc_sender: self.crc_tx.clone(),
+            gate: self.gate.clone(),
         }
     }
 }
 
 pub struct SecureClusterW {
-    file: Arc<Mutex<File>>,
+    file: Arc<File>,
     cipher: Arc<Cipher>,
+    framing: Arc<Framing>,
     index: usize,
     urls: Vec<String>,
-    write_sender: mpsc::Sender<usize>,
+    write_sender: Progress,
     crc_sender: CrcSender,
+    gate: Gate,
 }
 
-unsafe impl Send for SecureClusterW {}
-unsafe impl Sync for SecureClusterW {}
-
 impl SecureClusterW {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn slices(&self) -> usize {
+        self.urls.len()
+    }
+
     pub async fn download(&mut self) -> Result<(), DownloadError> {
+        // A missing attachment would otherwise leave a hole in the file
+        let cap = self.framing.slicing().cluster_cap;
+        let slices = self
+            .framing
+            .slices()
+            .saturating_sub(self.index as u64 * cap);
+        if self.framing.format() == Format::V2 && self.urls.len() as u64 != cmp::min(cap, slices) {
+            return Err(DownloadError::EncryptionError(format!(
+                "Cluster {} has {} slice(s), expected {}",
+                self.index,
+                self.urls.len(),
+                cmp::min(cap, slices)
+            )));
+        }
+
         let futures = self.urls.iter().enumerate().map(|(index, url)| {
             download(
                 self.file.clone(),
                 self.cipher.clone(),
+                self.framing.clone(),
                 url.clone(),
                 self.index as u64,
                 index as u64,
                 self.write_sender.clone(),
                 self.crc_sender.clone(),
+                self.gate.clone(),
             )
         });
 
@@ -92,90 +116,107 @@ impl SecureClusterW {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn download(
-    file: Arc<Mutex<File>>,
+    file: Arc<File>,
     cipher: Arc<Cipher>,
+    framing: Arc<Framing>,
     url: String,
     cluster: u64,
     slice: u64,
-    write_tx: mpsc::Sender<usize>,
+    write_tx: Progress,
     crc_tx: CrcSender,
+    gate: Gate,
 ) -> Result<(), DownloadError> {
-    let slice = cluster * CLUSTER_CAP + slice;
+    let slicing = framing.slicing();
+    let buffer_size = slicing.buffer_size as usize;
+    let slice = cluster * slicing.cluster_cap + slice;
 
-    let mut position = slice * BYTES_PER_SLICE;
-    let mut buffer_index = slice * BUFFERS_PER_SLICE;
-    let mut nonce = [0; 12];
-    let mut buffer = Vec::with_capacity(BUFFER_SIZE_U);
+    let mut position = slice * slicing.bytes_per_slice();
+    let mut buffer_index = slice * slicing.buffers_per_slice();
+    // Enough for the buffer being filled and every one being decrypted, held for the whole slice
+    let _reservation = memory::reserve(buffer_size * (CRYPTO_DEPTH + 1)).await;
+    let mut buffer = memory::take(buffer_size);
 
     let mut stream = api::download(url).await?.bytes_stream();
-    let cipher = unsafe { &mut *cipher.0.get() };
 
     let mut hasher = crc_tx.is_some().then(Hasher::new);
 
-    while let Some(chunk) = stream.next().await {
+    // Buffers being decrypted and written while the next ones arrive
+    let mut pending = FuturesOrdered::new();
+
+    loop {
+        // Finished buffers are written out while the next chunks arrive
+        let chunk = select! {
+            Some(written) = pending.next(), if !pending.is_empty() => {
+                let written: Vec<u8> = written?;
+                report(written, &mut hasher, &write_tx);
+                continue;
+            }
+            chunk = stream.next() => chunk,
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+
         let chunk = chunk.map_err(DownloadError::from)?;
+        gate.pass(chunk.len()).await;
         let mut cursor = 0;
 
         loop {
-            let available = cmp::min(BUFFER_SIZE_U - buffer.len(), chunk.len() - cursor);
+            let available = cmp::min(buffer_size - buffer.len(), chunk.len() - cursor);
             buffer.extend_from_slice(&chunk[cursor..cursor + available]);
 
-            if buffer.len() != BUFFER_SIZE_U {
+            if buffer.len() != buffer_size {
                 break;
             }
 
-            nonce[4..].copy_from_slice(&buffer_index.to_be_bytes());
-            let nonce = Nonce::from(nonce);
-
-            cipher
-                .decrypt_in_place(&nonce, b"", &mut buffer)
-                .map_err(DownloadError::from)?;
-
-            let mut file = file.lock().await;
-            file.seek(SeekFrom::Start(position))
-                .map_err(DownloadError::from)?;
-
-            file.write_all(&buffer).map_err(DownloadError::from)?;
-            drop(file);
-
-            if let Some(hasher) = &mut hasher {
-                hasher.update(&buffer);
-            }
-
-            if let Err(err) = write_tx.send(buffer.len()).await {
-                log::error!("Failed to send buffer size: {:?}", err);
+            let full = mem::replace(&mut buffer, memory::take(buffer_size));
+            pending.push_back(decrypt(
+                file.clone(),
+                cipher.clone(),
+                framing.clone(),
+                full,
+                buffer_index,
+                position,
+            ));
+
+            // Keeps the decryptions in flight bounded when a chunk holds several buffers
+            if pending.len() >= CRYPTO_DEPTH
+                && let Some(written) = pending.next().await
+            {
+                report(written?, &mut hasher, &write_tx);
             }
 
-            buffer.clear();
             buffer_index += 1;
             cursor += available;
-            position += RAW_BUFFER_SIZE;
+            position += slicing.raw_buffer_size();
         }
     }
 
     if !buffer.is_empty() {
-        nonce[4..].copy_from_slice(&buffer_index.to_be_bytes());
-        let nonce = Nonce::from(nonce);
-
-        cipher
-            .decrypt_in_place(&nonce, b"", &mut buffer)
-            .map_err(DownloadError::from)?;
-
-        let mut file = file.lock().await;
-        file.seek(SeekFrom::Start(position))
-            .map_err(DownloadError::from)?;
-
-        file.write_all(&buffer).map_err(DownloadError::from)?;
-        if let Err(err) = write_tx.send(buffer.len()).await {
-            log::error!("Failed to send buffer size: {:?}", err);
-        }
+        pending.push_back(decrypt(
+            file,
+            cipher,
+            framing.clone(),
+            buffer,
+            buffer_index,
+            position,
+        ));
+
+        buffer_index += 1;
+    }
 
-        drop(file);
+    while let Some(written) = pending.next().await {
+        report(written?, &mut hasher, &write_tx);
+    }
 
-        if let Some(hasher) = &mut hasher {
-            hasher.update(&buffer);
-        }
+    // Every buffer authenticates fine on its own, so a truncated slice is only noticed here
+    if framing.format() == Format::V2 && buffer_index != framing.slice_end(slice) {
+        return Err(DownloadError::EncryptionError(format!(
+            "Slice {} is truncated",
+            slice
+        )));
     }
 
     if let (Some(hasher), Some(crc_tx)) = (hasher, crc_tx) {
@@ -186,3 +227,36 @@ async fn download(
 
     Ok(())
 }
+
+// Decrypts on the crypto threads, then writes the plaintext where it belongs in the file
+async fn decrypt(
+    file: Arc<File>,
+    cipher: Arc<Cipher>,
+    framing: Arc<Framing>,
+    mut buffer: Vec<u8>,
+    index: u64,
+    position: u64,
+) -> Result<Vec<u8>, DownloadError> {
+    let buffer = pool::run(move || {
+        let nonce = framing.nonce(index);
+        cipher
+            .decrypt_in_place(&nonce, framing.aad(), &mut buffer)
+            .map(|_| buffer)
+    })
+    .await
+    .map_err(DownloadError::from)?;
+
+    positioned::write_at(file, buffer, position)
+        .await
+        .map_err(DownloadError::from)
+}
+
+// Buffers are reported in order, so the checksum covers the slice as stored
+fn report(buffer: Vec<u8>, hasher: &mut Option<Hasher>, write_tx: &Progress) {
+    if let Some(hasher) = hasher {
+        hasher.update(&buffer);
+    }
+
+    write_tx.add(buffer.len());
+    memory::give(buffer);
+}
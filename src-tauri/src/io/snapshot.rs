@@ -0,0 +1,26 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+
+// A private copy of a file staged for upload, so later changes to the original can't reach it.
+// Removed once dropped
+pub struct Snapshot {
+    pub path: String,
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path)
+            && err.kind() != ErrorKind::NotFound
+        {
+            log::error!("failed to remove staged file: {}", err);
+        }
+    }
+}
+
+pub fn take(source: &str, target: String) -> io::Result<Snapshot> {
+    // The guard also cleans up a partially written file
+    let snapshot = Snapshot { path: target };
+    fs::copy(source, &snapshot.path)?;
+
+    Ok(snapshot)
+}
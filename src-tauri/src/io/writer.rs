@@ -1,73 +1,88 @@
-use super::consts::*;
+use super::consts::{BUFFER_SIZE_I, BUFFER_SIZE_U};
+use super::{positioned, Progress, Slicing};
 use crate::api;
 use crate::errors::DownloadError;
+use crate::memory;
+use crate::throttle::Gate;
 
 use std::cmp;
 use std::fs::File;
-use std::io::{self, Seek, SeekFrom, Write};
+use std::io;
 use std::path::Path;
 use std::sync::Arc;
 
 use crc32fast::Hasher;
 use futures::{future, StreamExt};
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::mpsc;
 
 type CrcSender = Option<mpsc::Sender<(u64, Hasher)>>;
 
 pub struct InsecureWriter {
-    file: Arc<Mutex<File>>,
-    write_tx: mpsc::Sender<usize>,
+    file: Arc<File>,
+    slicing: Slicing,
+    write_tx: Progress,
     crc_tx: CrcSender,
+    gate: Gate,
 }
 
-unsafe impl Send for InsecureWriter {}
-unsafe impl Sync for InsecureWriter {}
-
 impl InsecureWriter {
     pub fn new<T: AsRef<Path>>(
         path: T,
-        write_sender: mpsc::Sender<usize>,
+        slicing: Slicing,
+        write_sender: Progress,
         crc_sender: CrcSender,
     ) -> io::Result<Self> {
         Ok(Self {
-            file: Arc::new(Mutex::new(File::create(path)?)),
+            file: Arc::new(File::create(path)?),
+            slicing,
             write_tx: write_sender,
             crc_tx: crc_sender,
+            gate: Gate::download(),
         })
     }
 
     pub fn cluster(&self, index: usize, download_urls: Vec<String>) -> InsecureClusterW {
         InsecureClusterW {
             file: self.file.clone(),
+            slicing: self.slicing,
             index,
             urls: download_urls,
             write_sender: self.write_tx.clone(),
             crc_sender: self.crc_tx.clone(),
+            gate: self.gate.clone(),
         }
     }
 }
 
 pub struct InsecureClusterW {
-    file: Arc<Mutex<File>>,
+    file: Arc<File>,
+    slicing: Slicing,
     index: usize,
     urls: Vec<String>,
-    write_sender: mpsc::Sender<usize>,
+    write_sender: Progress,
     crc_sender: CrcSender,
+    gate: Gate,
 }
 
-unsafe impl Send for InsecureClusterW {}
-unsafe impl Sync for InsecureClusterW {}
-
 impl InsecureClusterW {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn slices(&self) -> usize {
+        self.urls.len()
+    }
+
     pub async fn download(&mut self) -> Result<(), DownloadError> {
         let futures = self.urls.iter().enumerate().map(|(index, url)| {
             download(
                 self.file.clone(),
+                self.slicing,
                 url.clone(),
-                self.index as u64,
-                index as u64,
+                self.index as u64 * self.slicing.cluster_cap + index as u64,
                 self.write_sender.clone(),
                 self.crc_sender.clone(),
+                self.gate.clone(),
             )
         });
 
@@ -79,23 +94,26 @@ impl InsecureClusterW {
 }
 
 async fn download(
-    file: Arc<Mutex<File>>,
+    file: Arc<File>,
+    slicing: Slicing,
     url: String,
-    cluster: u64,
     slice: u64,
-    write_tx: mpsc::Sender<usize>,
+    write_tx: Progress,
     crc_tx: CrcSender,
+    gate: Gate,
 ) -> Result<(), DownloadError> {
-    let slice = cluster * CLUSTER_CAP + slice;
-    let mut position = slice * SLICE_SIZE;
+    let mut position = slice * slicing.slice_size;
     log::debug!("Downloading slice {} at position {}", slice, position);
 
-    let mut buffer = Vec::with_capacity(BUFFER_SIZE_U);
+    // Held for the whole slice, only one buffer of it is ever in flight
+    let _reservation = memory::reserve(BUFFER_SIZE_U).await;
+    let mut buffer = memory::take(BUFFER_SIZE_U);
     let mut stream = api::download(url).await?.bytes_stream();
     let mut hasher = crc_tx.is_some().then(Hasher::new);
 
     while let Some(chunk) = stream.next().await {
         let chunk = chunk.map_err(DownloadError::from)?;
+        gate.pass(chunk.len()).await;
         let mut cursor = 0;
 
         loop {
@@ -106,20 +124,15 @@ async fn download(
                 break;
             }
 
-            let mut file = file.lock().await;
-            file.seek(SeekFrom::Start(position))
+            buffer = positioned::write_at(file.clone(), buffer, position)
+                .await
                 .map_err(DownloadError::from)?;
 
-            file.write_all(&buffer).map_err(DownloadError::from)?;
-            drop(file);
-
             if let Some(hasher) = &mut hasher {
                 hasher.update(&buffer);
             }
 
-            if let Err(err) = write_tx.send(buffer.len()).await {
-                log::error!("Failed to send buffer size: {:?}", err);
-            }
+            write_tx.add(buffer.len());
 
             buffer.clear();
             cursor += available;
@@ -128,22 +141,19 @@ async fn download(
     }
 
     if !buffer.is_empty() {
-        let mut file = file.lock().await;
-        file.seek(SeekFrom::Start(position))
+        buffer = positioned::write_at(file, buffer, position)
+            .await
             .map_err(DownloadError::from)?;
 
-        file.write_all(&buffer).map_err(DownloadError::from)?;
-        drop(file);
-
-        if let Err(err) = write_tx.send(buffer.len()).await {
-            log::error!("Failed to send buffer size: {:?}", err);
-        }
+        write_tx.add(buffer.len());
 
         if let Some(hasher) = &mut hasher {
             hasher.update(&buffer);
         }
     }
 
+    memory::give(buffer);
+
     if let (Some(hasher), Some(crc_tx)) = (hasher, crc_tx) {
         if let Err(err) = crc_tx.send((slice, hasher)).await {
             log::error!("Failed to send crc: {:?}", err);
@@ -7,10 +7,15 @@ use std::sync::Arc;
 use tokio::sync::RwLock;
 
 mod api;
+mod concurrency;
+mod connection;
 mod invokes;
 mod io;
 mod levenshtein;
+mod memory;
+mod progress;
 mod state;
+mod throttle;
 mod utils;
 
 pub use state::{errors, model};
@@ -33,20 +38,36 @@ async fn main() {
     }
 
     let state = model::State::new();
+    if !state.queue.is_empty() {
+        log::info!(
+            "{} queued file(s) left from the last run",
+            state.queue.len()
+        );
+    }
+
+    match api::Network::new(&state.network) {
+        Ok(network) => api::configure(network),
+        Err(err) => log::error!("failed to apply network settings: {}", err),
+    }
+
+    throttle::configure(state.throttle.clone());
+    tokio::spawn(throttle::watch());
+    concurrency::configure(state.concurrency);
+    memory::configure(state.memory);
+    progress::configure(state.progress_interval);
+
     let state = Arc::new(RwLock::new(state));
 
     let mut app_state = state.write().await;
-    app_state.rt.this = &state;
+    app_state.rt.this = Arc::downgrade(&state);
     drop(app_state);
 
     let state2 = state.clone();
     tauri::Builder::default()
-        .setup(|app| {
-            let handle = Arc::new(app.handle());
-            tokio::spawn(async move {
-                let mut app_state = state2.write().await;
-                app_state.rt.app_handle = Arc::into_raw(handle) as *const _;
-            });
+        .setup(move |app| {
+            // Commands only run once setup is done, so the lock is free here
+            let mut app_state = state2.try_write().expect("state locked during setup");
+            app_state.rt.app_handle = Some(app.handle());
 
             Ok(())
         })
@@ -54,11 +75,22 @@ async fn main() {
         .invoke_handler(tauri::generate_handler![
             invokes::get_files,
             invokes::download_files,
+            invokes::provide_passphrase,
             invokes::delete_files,
+            invokes::rekey_files,
             invokes::get_settings,
             invokes::upload_files,
+            invokes::retry_upload,
             invokes::set_settings,
+            invokes::test_connection,
+            invokes::get_profiles,
+            invokes::add_profile,
+            invokes::switch_profile,
+            invokes::transfer_files,
             invokes::cancel,
+            invokes::get_progress,
+            invokes::get_queue,
+            invokes::resume_queue,
             invokes::query,
             invokes::rename_file,
         ])
@@ -0,0 +1,208 @@
+use std::sync::Mutex;
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+// Buffers being read, encrypted, decrypted or written by every job at once
+static BUDGET: Budget = Budget::new(DEFAULT_LIMIT);
+
+// Buffers kept between uses
+static FREE: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+pub const DEFAULT_LIMIT: u64 = 256; // MiB
+const POOLED: usize = 64;
+
+// Memory shared by the buffers in flight, in KiB so any limit fits the permits of a single acquire
+pub struct Budget {
+    permits: Semaphore,
+    limit: Mutex<u64>,
+}
+
+// Held while a buffer is in flight, the memory is free again once it's dropped
+pub struct Reservation<'a> {
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Budget {
+    pub const fn new(limit: u64) -> Self {
+        Self {
+            permits: Semaphore::const_new(limit as usize * 1024),
+            limit: Mutex::new(limit * 1024),
+        }
+    }
+
+    // Waits in line, so the buffers of a slice are let through in the order they were asked for
+    pub async fn reserve(&self, bytes: usize) -> Reservation<'_> {
+        // A buffer larger than the whole budget still goes, just alone
+        let limit = *self.limit.lock().expect("failed to lock memory limit");
+        let kib = (bytes as u64).div_ceil(1024).min(limit);
+
+        let permit = self
+            .permits
+            .acquire_many(kib as u32)
+            .await
+            .expect("memory budget closed");
+
+        Reservation { _permit: permit }
+    }
+
+    // Applies right away, a lower limit waits for the buffers over it to be done
+    pub fn configure(&'static self, limit: u64) {
+        let mut current = self.limit.lock().expect("failed to lock memory limit");
+        let limit = limit * 1024;
+
+        if limit > *current {
+            self.permits.add_permits((limit - *current) as usize);
+        } else if limit < *current {
+            let excess = (*current - limit) as u32;
+            tokio::spawn(async move {
+                if let Ok(permit) = self.permits.acquire_many(excess).await {
+                    permit.forget();
+                }
+            });
+        }
+
+        *current = limit;
+    }
+}
+
+pub async fn reserve(bytes: usize) -> Reservation<'static> {
+    BUDGET.reserve(bytes).await
+}
+
+pub fn configure(limit: u64) {
+    BUDGET.configure(limit);
+}
+
+// An empty buffer with room for the given size, reused when one is free
+pub fn take(size: usize) -> Vec<u8> {
+    let mut free = FREE.lock().expect("failed to lock buffers");
+    match free.iter().rposition(|buffer| buffer.capacity() >= size) {
+        Some(index) => free.swap_remove(index),
+        None => Vec::with_capacity(size),
+    }
+}
+
+// Buffers handed to a request body are gone for good, only the ones written to disk come back
+pub fn give(mut buffer: Vec<u8>) {
+    let mut free = FREE.lock().expect("failed to lock buffers");
+    if free.len() < POOLED {
+        buffer.clear();
+        free.push(buffer);
+    }
+}
+
+pub fn validate(limit: u64) -> Result<(), String> {
+    if !(16..=1024 * 16).contains(&limit) {
+        return Err("The memory limit must be between 16 MiB and 16 GiB".to_owned());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    use futures::{future, poll, FutureExt};
+    use tokio::task;
+
+    const MIB: usize = 1024 * 1024;
+
+    // Configuring needs a budget that outlives the task it may spawn, as the global one does
+    fn budget(limit: u64) -> &'static Budget {
+        Box::leak(Box::new(Budget::new(limit)))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reservations_stay_within_the_budget() {
+        let budget = budget(16);
+        let held = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..16 * 4).map(|_| {
+            let held = held.clone();
+            let most = most.clone();
+            tokio::spawn(async move {
+                let _reservation = budget.reserve(MIB).await;
+                let now = held.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+
+                task::yield_now().await;
+                held.fetch_sub(1, Ordering::SeqCst);
+            })
+        });
+
+        for task in future::join_all(tasks).await {
+            task.unwrap();
+        }
+
+        assert!(most.load(Ordering::SeqCst) <= 16);
+        assert_eq!(held.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn reservations_wait_in_line() {
+        let budget = Budget::new(16);
+
+        // Larger than the whole budget, so it holds all of it
+        let all = budget.reserve(32 * MIB).now_or_never().unwrap();
+
+        let mut first = Box::pin(budget.reserve(16 * MIB));
+        let mut second = Box::pin(budget.reserve(1));
+        assert!(poll!(&mut first).is_pending());
+        assert!(poll!(&mut second).is_pending());
+
+        // The small one doesn't get ahead of the large one queued before it
+        drop(all);
+        let first = first.now_or_never().unwrap();
+        assert!(poll!(&mut second).is_pending());
+
+        drop(first);
+        assert!(second.now_or_never().is_some());
+    }
+
+    #[tokio::test]
+    async fn limits_apply_right_away() {
+        let budget = budget(16);
+
+        budget.configure(32);
+        let held = budget.reserve(8 * MIB).now_or_never().unwrap();
+
+        // The memory over the new limit is taken out of what's still free
+        budget.configure(16);
+        task::yield_now().await;
+        assert!(budget.reserve(9 * MIB).now_or_never().is_none());
+
+        let rest = budget.reserve(8 * MIB).now_or_never().unwrap();
+        drop((held, rest));
+        assert!(budget.reserve(16 * MIB).now_or_never().is_some());
+    }
+
+    #[test]
+    fn buffers_are_shared_between_threads() {
+        let threads = (0..8).map(|_| {
+            thread::spawn(|| {
+                for size in (1..=200).map(|size| size * 1024) {
+                    let mut buffer = take(size);
+                    assert!(buffer.is_empty());
+                    assert!(buffer.capacity() >= size);
+
+                    buffer.resize(size, 1);
+                    give(buffer);
+                }
+            })
+        });
+
+        for thread in threads.collect::<Vec<_>>() {
+            thread.join().unwrap();
+        }
+
+        let free = FREE.lock().unwrap();
+        assert!(free.len() <= POOLED);
+        assert!(free.iter().all(Vec::is_empty));
+    }
+}
@@ -0,0 +1,209 @@
+use crate::io::Progress;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tauri::{AppHandle, Manager};
+use tokio::sync::mpsc;
+use tokio::time::{self, MissedTickBehavior};
+
+// Milliseconds between two reports of the same file
+static INTERVAL: AtomicU64 = AtomicU64::new(DEFAULT_INTERVAL);
+static NEXT_TRANSFER: AtomicU64 = AtomicU64::new(1);
+
+// Latest report of the file being worked on, for the frontend to poll
+static CURRENT: Mutex<Option<Report>> = Mutex::new(None);
+
+pub const DEFAULT_INTERVAL: u64 = 250;
+// Seconds the throughput is averaged over
+const SMOOTHING: f64 = 5.0;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Upload,
+    Download,
+    Rekey,
+    Transfer,
+}
+
+impl Kind {
+    // Bytes done so far, still sent for the footer
+    fn event(self) -> &'static str {
+        match self {
+            Self::Upload => "upload_progress",
+            Self::Download => "download_progress",
+            Self::Rekey => "rekey_progress",
+            Self::Transfer => "transfer_progress",
+        }
+    }
+}
+
+// In the order a file goes through them, a phase is never gone back to
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    FetchingMetadata,
+    Preupload,
+    Downloading,
+    Uploading,
+    Finalizing,
+    Verifying,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Report {
+    pub transfer: u64,
+    pub file: u32,
+    pub kind: Kind,
+    pub phase: Phase,
+    pub done: u64,
+    pub total: u64,
+    pub rate: f64,        // bytes per second
+    pub eta: Option<f64>, // seconds, none until there's a rate to go by
+}
+
+// Follows a single file through a job, the reports stop once its progress senders are dropped
+#[derive(Clone)]
+pub struct Tracker {
+    handle: AppHandle,
+    transfer: u64,
+}
+
+impl Tracker {
+    pub fn start(
+        handle: AppHandle,
+        kind: Kind,
+        file: u32,
+        total: u64,
+        phase: Phase,
+    ) -> (Self, Progress) {
+        let transfer = NEXT_TRANSFER.fetch_add(1, Ordering::Relaxed);
+        let report = Report {
+            transfer,
+            file,
+            kind,
+            phase,
+            done: 0,
+            total,
+            rate: 0.0,
+            eta: None,
+        };
+
+        *CURRENT.lock().expect("failed to lock progress") = Some(report.clone());
+        emit(&handle, &report);
+
+        let (tx, rx) = Progress::channel();
+        tokio::spawn(watch(handle.clone(), report, rx));
+
+        (Self { handle, transfer }, tx)
+    }
+
+    pub fn phase(&self, phase: Phase) {
+        let report = update(self.transfer, |report| {
+            if phase <= report.phase {
+                return false;
+            }
+
+            report.phase = phase;
+            true
+        });
+
+        if let Some(report) = report {
+            emit(&self.handle, &report);
+        }
+    }
+}
+
+pub fn current() -> Option<Report> {
+    CURRENT.lock().expect("failed to lock progress").clone()
+}
+
+pub fn validate(interval: u64) -> Result<(), String> {
+    if !(50..=10_000).contains(&interval) {
+        return Err("The progress interval must be between 50 ms and 10 s".to_owned());
+    }
+
+    Ok(())
+}
+
+// Applies from the next file on
+pub fn configure(interval: u64) {
+    INTERVAL.store(interval, Ordering::Relaxed);
+}
+
+// Changes the report if it's still the one of this transfer
+fn update(transfer: u64, change: impl FnOnce(&mut Report) -> bool) -> Option<Report> {
+    let mut current = CURRENT.lock().expect("failed to lock progress");
+    match current.as_mut() {
+        Some(report) if report.transfer == transfer => change(report).then(|| report.clone()),
+        _ => None,
+    }
+}
+
+fn emit(handle: &AppHandle, report: &Report) {
+    handle
+        .emit_all("progress", report)
+        .expect("failed to emit progress");
+    handle
+        .emit_all(report.kind.event(), report.done)
+        .expect("failed to emit progress");
+}
+
+// Counts the bytes as they come, but reports at most once per interval
+async fn watch(handle: AppHandle, report: Report, mut rx: mpsc::UnboundedReceiver<usize>) {
+    let interval = Duration::from_millis(INTERVAL.load(Ordering::Relaxed));
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut done = 0;
+    let mut reported = 0;
+    let mut rate = None;
+    let mut last = Instant::now();
+
+    loop {
+        let open = tokio::select! {
+            bytes = rx.recv() => match bytes {
+                Some(bytes) => {
+                    done += bytes as u64;
+                    continue;
+                }
+                None => false,
+            },
+            _ = ticker.tick() => true,
+        };
+
+        // Weighted by the time since the last tick, so a short interval smooths just as much, and
+        // seeded by the first bytes instead of ramping up from nothing
+        let now = Instant::now();
+        let elapsed = (now - last).as_secs_f64();
+        if elapsed > 0.0 && (rate.is_some() || done > reported) {
+            let instant = (done - reported) as f64 / elapsed;
+            let weight = 1.0 - (-elapsed / SMOOTHING).exp();
+            rate = Some(rate.map_or(instant, |rate: f64| rate + (instant - rate) * weight));
+        }
+
+        let rate = rate.unwrap_or(0.0);
+        let changed = done != reported || !open;
+        reported = done;
+        last = now;
+
+        let eta = (rate > 0.0).then(|| report.total.saturating_sub(done) as f64 / rate);
+        let latest = update(report.transfer, |report| {
+            report.done = done;
+            report.rate = rate;
+            report.eta = eta;
+            changed
+        });
+
+        if let Some(latest) = &latest {
+            emit(&handle, latest);
+        }
+
+        if !open {
+            break;
+        }
+    }
+}
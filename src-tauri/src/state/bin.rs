@@ -69,6 +69,306 @@ mod v2 {
     }
 }
 
+mod v3 {
+    use crate::state::bin::v2;
+
+    use bincode::{deserialize, serialize, Result};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Deserialize, Serialize)]
+    pub struct State {
+        pub next_id: u32,
+        pub channel_id: String,
+        pub guild_id: String,
+        pub token: String,
+        pub auth_mode: AuthMode,
+        pub webhook_url: String,
+        pub do_encrypt: bool,
+        pub do_checksum: bool,
+        pub download_location: String,
+        pub algorithm: Algorithm,
+        pub compression: Option<i32>,
+        pub erasure: Option<ErasureCode>,
+        pub mirrors: Vec<String>,
+        pub stripes: Vec<Stripe>,
+        pub thread_mode: ThreadMode,
+        pub threads: Vec<Thread>,
+        pub network: Network,
+        pub throttle: Throttle,
+        pub concurrency: Concurrency,
+        pub slicing: Option<Slicing>,
+        pub memory: u64,
+        pub progress_interval: u64,
+        pub queue: Vec<Queued>,
+        pub files: Vec<File>,
+        pub profile: String,
+        pub profiles: Vec<Profile>,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct File {
+        pub id: u32,
+        pub path: String,
+        pub name: Option<String>,
+        pub size: u64,
+        pub download_ids: Vec<u64>,
+        pub created_at: u64,
+        pub updated_at: u64,
+        pub crc32: u32,
+        #[serde(with = "serde_bytes")]
+        pub encryption_key: Option<[u8; 32]>,
+        pub vault: Option<Vault>,
+        pub format: Format,
+        pub algorithm: Algorithm,
+        pub compression: Option<Compression>,
+        pub erasure: Option<Erasure>,
+        pub mirrors: Vec<Mirror>,
+        pub locations: Vec<String>,
+        pub slicing: Slicing,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub enum Format {
+        V1,
+        V2,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub enum Algorithm {
+        Aes256Gcm,
+        ChaCha20Poly1305,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Compression {
+        pub level: i32,
+        pub size: u64,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct ErasureCode {
+        pub data: u8,
+        pub parity: u8,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Erasure {
+        pub code: ErasureCode,
+        pub size: u64,
+        pub download_ids: Vec<u64>,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Mirror {
+        pub channel_id: String,
+        pub download_ids: Vec<u64>,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Stripe {
+        pub token: String,
+        pub channel_id: String,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub enum AuthMode {
+        User,
+        Bot,
+        Webhook,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub enum ThreadMode {
+        Off,
+        Folder,
+        Batch,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Thread {
+        pub channel_id: String,
+        pub name: String,
+        pub id: String,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Network {
+        pub proxy_url: String,
+        pub proxy_username: String,
+        pub proxy_password: String,
+        pub ca_bundle: String,
+        pub timeout: Option<u64>,
+        pub user_agent: String,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Limits {
+        pub upload: Option<u64>,
+        pub download: Option<u64>,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Throttle {
+        pub global: Limits,
+        pub transfer: Limits,
+        pub schedule: Vec<Window>,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Window {
+        pub start: u16,
+        pub end: u16,
+        pub global: Limits,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Bounds {
+        pub min: usize,
+        pub max: usize,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Concurrency {
+        pub upload: Bounds,
+        pub download: Bounds,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Slicing {
+        pub slice_size: u64,
+        pub cluster_cap: u64,
+        pub buffer_size: u64,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Fingerprint {
+        pub size: u64,
+        pub modified: u64,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub enum Queued {
+        Upload {
+            path: String,
+            fingerprint: Fingerprint,
+            profile: String,
+            channel_id: String,
+            algorithm: Algorithm,
+            compression: Option<i32>,
+            thread: Option<String>,
+            snapshot: bool,
+            vault: bool,
+        },
+        Download {
+            id: u32,
+            profile: String,
+            updated_at: u64,
+        },
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Profile {
+        pub name: String,
+        pub guild_id: String,
+        pub channel_id: String,
+        pub files: Vec<File>,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct Vault {
+        pub salt: [u8; 16],
+        pub memory: u32,
+        pub iterations: u32,
+        pub parallelism: u32,
+        pub check: [u8; 32],
+    }
+
+    pub fn from_v2(state: &[u8]) -> Result<Vec<u8>> {
+        log::info!("upgrading state file from v2 to v3");
+        let state = deserialize::<v2::State>(state)?;
+        let files = state.files.into_iter().map(|file| File {
+            id: file.id,
+            path: file.path,
+            name: file.name,
+            size: file.size,
+            download_ids: file.download_ids,
+            created_at: file.created_at,
+            updated_at: file.updated_at,
+            crc32: file.crc32,
+            encryption_key: file.encryption_key,
+            vault: None,
+            format: Format::V1,
+            algorithm: Algorithm::Aes256Gcm,
+            compression: None,
+            erasure: None,
+            mirrors: Vec::new(),
+            locations: Vec::new(),
+            // Every file so far was cut with the constants that used to be fixed
+            slicing: Slicing {
+                slice_size: 1024 * 1024 * 25,
+                cluster_cap: 10,
+                buffer_size: 1024 * 1024,
+            },
+        });
+
+        let state = State {
+            next_id: state.next_id,
+            channel_id: state.channel_id,
+            guild_id: state.guild_id,
+            token: state.token,
+            auth_mode: AuthMode::User,
+            webhook_url: String::new(),
+            do_encrypt: state.do_encrypt,
+            do_checksum: state.do_checksum,
+            download_location: state.download_location,
+            algorithm: Algorithm::Aes256Gcm,
+            compression: None,
+            erasure: None,
+            mirrors: Vec::new(),
+            stripes: Vec::new(),
+            thread_mode: ThreadMode::Off,
+            threads: Vec::new(),
+            network: Network {
+                proxy_url: String::new(),
+                proxy_username: String::new(),
+                proxy_password: String::new(),
+                ca_bundle: String::new(),
+                timeout: None,
+                user_agent: String::new(),
+            },
+            throttle: Throttle {
+                global: Limits {
+                    upload: None,
+                    download: None,
+                },
+                transfer: Limits {
+                    upload: None,
+                    download: None,
+                },
+                schedule: Vec::new(),
+            },
+            concurrency: Concurrency {
+                upload: Bounds { min: 1, max: 8 },
+                download: Bounds { min: 1, max: 6 },
+            },
+            slicing: None,
+            memory: 256,
+            progress_interval: 250,
+            queue: Vec::new(),
+            files: files.collect(),
+            profile: "Default".to_owned(),
+            profiles: Vec::new(),
+        };
+
+        serialize(&state)
+    }
+}
+
+type Upgrade = fn(&[u8]) -> bincode::Result<Vec<u8>>;
+
+// Version the upgrade starts from, upgrade to the next version
+const UPGRADES: [(u16, Upgrade); 2] = [(1, v2::from_v1), (2, v3::from_v2)];
+
 pub fn upgrade() -> io::Result<()> {
     let mut version = [0u8; 2];
     let path = format!("{}/state.bin", path());
@@ -86,8 +386,12 @@ pub fn upgrade() -> io::Result<()> {
     log::info!("state file version: {}", version);
     let mut state = file[2..].to_vec();
 
-    if version == 1 {
-        state = match v2::from_v1(&state) {
+    for (from, upgrade) in UPGRADES {
+        if version > from {
+            continue;
+        }
+
+        state = match upgrade(&state) {
             Ok(state) => state,
             Err(err) => {
                 log::error!("failed to upgrade state file: {}", err);
@@ -99,3 +403,115 @@ pub fn upgrade() -> io::Result<()> {
     let state = [CURRENT_VERSION.to_be_bytes().to_vec(), state].concat();
     fs::write(&path, state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{Algorithm, Format, Slicing};
+    use crate::model::{AuthMode, State, ThreadMode};
+
+    fn upgrade_from(version: u16, state: &[u8]) -> State {
+        let state = UPGRADES
+            .iter()
+            .filter(|(from, _)| *from >= version)
+            .try_fold(state.to_vec(), |state, (_, upgrade)| upgrade(&state))
+            .expect("failed to upgrade state");
+
+        bincode::deserialize(&state).expect("failed to read upgraded state")
+    }
+
+    fn first() -> v1::State {
+        v1::State {
+            next_id: 3,
+            channel_id: "10".to_owned(),
+            guild_id: "20".to_owned(),
+            token: "token".to_owned(),
+            do_encrypt: true,
+            do_checksum: false,
+            files: vec![v1::File {
+                id: 2,
+                path: "/tmp/file".to_owned(),
+                name: Some("file".to_owned()),
+                size: 1234,
+                download_ids: vec![5, 6],
+                created_at: 7,
+                updated_at: 8,
+                crc32: 9,
+                encryption_key: Some([1; 32]),
+            }],
+        }
+    }
+
+    #[test]
+    fn every_version_has_an_upgrade() {
+        for (i, (from, _)) in UPGRADES.iter().enumerate() {
+            assert_eq!(*from, i as u16 + 1);
+        }
+
+        assert_eq!(UPGRADES.len() as u16 + 1, CURRENT_VERSION);
+    }
+
+    #[test]
+    fn first_state_upgrades_to_the_current_one() {
+        let state = upgrade_from(1, &bincode::serialize(&first()).unwrap());
+
+        assert_eq!(state.next_id, 3);
+        assert_eq!(state.channel_id, "10");
+        assert_eq!(state.guild_id, "20");
+        assert_eq!(state.token, "token");
+        assert!(state.do_encrypt);
+        assert!(!state.do_checksum);
+        assert_eq!(state.algorithm, Algorithm::Aes256Gcm);
+        assert!(state.compression.is_none());
+        assert!(state.erasure.is_none());
+        assert!(state.mirrors.is_empty());
+        assert!(state.stripes.is_empty());
+        assert!(matches!(state.auth_mode, AuthMode::User));
+        assert!(state.webhook_url.is_empty());
+        assert_eq!(state.profile, "Default");
+        assert!(state.profiles.is_empty());
+        assert!(matches!(state.thread_mode, ThreadMode::Off));
+        assert!(state.threads.is_empty());
+        assert!(state.network.proxy_url.is_empty());
+        assert!(state.network.timeout.is_none());
+        assert!(state.network.user_agent.is_empty());
+        assert!(state.throttle.global.upload.is_none());
+        assert!(state.throttle.schedule.is_empty());
+        assert_eq!(state.concurrency.upload.max, 8);
+        assert_eq!(state.concurrency.download.max, 6);
+        assert!(state.slicing.is_none());
+        assert_eq!(state.memory, crate::memory::DEFAULT_LIMIT);
+        assert_eq!(state.progress_interval, crate::progress::DEFAULT_INTERVAL);
+        assert!(state.queue.is_empty());
+
+        let [file] = state.files.as_slice() else {
+            panic!("expected a single file");
+        };
+        assert_eq!(file.id, 2);
+        assert_eq!(file.path, "/tmp/file");
+        assert_eq!(file.name.as_deref(), Some("file"));
+        assert_eq!(file.size, 1234);
+        assert_eq!(file.download_ids, [5, 6]);
+        assert_eq!((file.created_at, file.updated_at, file.crc32), (7, 8, 9));
+        assert_eq!(file.encryption_key, Some([1; 32]));
+        assert!(file.vault.is_none());
+        assert_eq!(file.format, Format::V1);
+        assert_eq!(file.algorithm, Algorithm::Aes256Gcm);
+        assert!(file.compression.is_none());
+        assert!(file.erasure.is_none());
+        assert!(file.mirrors.is_empty());
+        assert!(file.locations.is_empty());
+        assert_eq!(file.slicing, Slicing::default());
+    }
+
+    #[test]
+    fn released_state_keeps_its_download_location() {
+        let state = v2::from_v1(&bincode::serialize(&first()).unwrap()).unwrap();
+        let mut state = bincode::deserialize::<v2::State>(&state).unwrap();
+        state.download_location = "/downloads".to_owned();
+
+        let state = upgrade_from(2, &bincode::serialize(&state).unwrap());
+        assert_eq!(state.download_location, "/downloads");
+        assert_eq!(state.files.len(), 1);
+    }
+}
@@ -4,6 +4,16 @@ use std::io;
 use serde::ser::SerializeStruct;
 use serde::{Serialize, Serializer};
 
+// A webhook url that does not parse while webhook mode is on
+#[derive(Debug)]
+pub struct InvalidWebhook;
+
+impl Display for InvalidWebhook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid Webhook")
+    }
+}
+
 #[derive(Debug)]
 pub enum UploadError {
     Io(io::Error),
@@ -13,6 +23,9 @@ pub enum UploadError {
     NotFound,     // 404
     Unknown((u16, String)),
     JoinError,
+    EncryptionError(String),
+    InvalidWebhook,
+    SourceChanged(String), // path of the file modified while it was uploaded
 }
 
 impl From<reqwest::Error> for UploadError {
@@ -21,6 +34,12 @@ impl From<reqwest::Error> for UploadError {
     }
 }
 
+impl From<InvalidWebhook> for UploadError {
+    fn from(_: InvalidWebhook) -> Self {
+        Self::InvalidWebhook
+    }
+}
+
 impl Serialize for UploadError {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -69,6 +88,24 @@ impl Serialize for UploadError {
                 state.serialize_field("type", "JoinError")?;
                 state.serialize_field("message", "")?;
             }
+            UploadError::EncryptionError(ref message) => {
+                state.serialize_field("type", "EncryptionError")?;
+                state.serialize_field("message", message)?;
+            }
+            UploadError::InvalidWebhook => {
+                state.serialize_field("type", "InvalidWebhook")?;
+                state.serialize_field(
+                    "message",
+                    "A webhook url in the settings is not valid, check the channel and stripes",
+                )?;
+            }
+            UploadError::SourceChanged(ref path) => {
+                state.serialize_field("type", "SourceChanged")?;
+                state.serialize_field(
+                    "message",
+                    &format!("{} was modified while it was uploaded", path),
+                )?;
+            }
         }
         state.end()
     }
@@ -90,6 +127,9 @@ impl Display for UploadError {
             Self::NotFound => write!(f, "Not Found"),
             Self::Unknown((status, message)) => write!(f, "Unknown: {} - {}", status, message),
             Self::JoinError => write!(f, "Join Error"),
+            Self::EncryptionError(err) => write!(f, "Encryption Error: {}", err),
+            Self::InvalidWebhook => write!(f, "Invalid Webhook"),
+            Self::SourceChanged(path) => write!(f, "Source Changed: {}", path),
         }
     }
 }
@@ -107,6 +147,9 @@ pub enum DownloadError {
     ChecksumMismatch(u32, u32),
     NotFoundRemote,
     EncryptionError(String),
+    WrongPassphrase,
+    Unrecoverable(usize), // clusters lost
+    InvalidWebhook,
 }
 
 impl From<reqwest::Error> for DownloadError {
@@ -127,6 +170,12 @@ impl From<io::Error> for DownloadError {
     }
 }
 
+impl From<InvalidWebhook> for DownloadError {
+    fn from(_: InvalidWebhook) -> Self {
+        Self::InvalidWebhook
+    }
+}
+
 impl Serialize for DownloadError {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -194,6 +243,24 @@ impl Serialize for DownloadError {
                 state.serialize_field("type", "EncryptionError")?;
                 state.serialize_field("message", message)?;
             }
+            DownloadError::WrongPassphrase => {
+                state.serialize_field("type", "WrongPassphrase")?;
+                state.serialize_field("message", "")?;
+            }
+            DownloadError::Unrecoverable(lost) => {
+                state.serialize_field("type", "Unrecoverable")?;
+                state.serialize_field(
+                    "message",
+                    &format!("{} cluster(s) lost, more than the parity can rebuild", lost),
+                )?;
+            }
+            DownloadError::InvalidWebhook => {
+                state.serialize_field("type", "InvalidWebhook")?;
+                state.serialize_field(
+                    "message",
+                    "A webhook url in the settings is not valid, check the channel and stripes",
+                )?;
+            }
         }
         state.end()
     }
@@ -225,6 +292,147 @@ impl Display for DownloadError {
             }
             Self::NotFoundRemote => write!(f, "Not Found Remotely"),
             Self::EncryptionError(err) => write!(f, "Encryption Error: {}", err),
+            Self::WrongPassphrase => write!(f, "Wrong Passphrase"),
+            Self::Unrecoverable(lost) => write!(f, "Unrecoverable: {} cluster(s) lost", lost),
+            Self::InvalidWebhook => write!(f, "Invalid Webhook"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub enum RekeyError {
+    Upload(UploadError),
+    Download(DownloadError),
+    ChecksumMismatch(u32, u32),
+    HasParity(u32), // file id
+    #[default]
+    JoinError,
+}
+
+impl From<UploadError> for RekeyError {
+    fn from(value: UploadError) -> Self {
+        Self::Upload(value)
+    }
+}
+
+impl From<DownloadError> for RekeyError {
+    fn from(value: DownloadError) -> Self {
+        Self::Download(value)
+    }
+}
+
+impl Serialize for RekeyError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match *self {
+            RekeyError::Upload(ref err) => err.serialize(serializer),
+            RekeyError::Download(ref err) => err.serialize(serializer),
+            RekeyError::ChecksumMismatch(expected, actual) => {
+                let mut state = serializer.serialize_struct("RekeyError", 2)?;
+                state.serialize_field("type", "ChecksumMismatch")?;
+                state.serialize_field(
+                    "message",
+                    &format!("Expected: {:x}\nActual: {:x}", expected, actual),
+                )?;
+                state.end()
+            }
+            RekeyError::HasParity(id) => {
+                let mut state = serializer.serialize_struct("RekeyError", 2)?;
+                state.serialize_field("type", "HasParity")?;
+                state.serialize_field(
+                    "message",
+                    &format!(
+                        "File {} has parity, which can only be rebuilt from the whole file. Download it and upload it again instead",
+                        id
+                    ),
+                )?;
+                state.end()
+            }
+            RekeyError::JoinError => {
+                let mut state = serializer.serialize_struct("RekeyError", 2)?;
+                state.serialize_field("type", "JoinError")?;
+                state.serialize_field("message", "")?;
+                state.end()
+            }
+        }
+    }
+}
+
+impl Display for RekeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Upload(err) => write!(f, "Upload: {}", err),
+            Self::Download(err) => write!(f, "Download: {}", err),
+            Self::ChecksumMismatch(expected, actual) => {
+                write!(
+                    f,
+                    "Checksum Mismatch: Expected: {:x}, Actual: {:x}",
+                    expected, actual
+                )
+            }
+            Self::HasParity(id) => write!(f, "Has Parity: {}", id),
+            Self::JoinError => write!(f, "Join Error"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub enum TransferError {
+    Upload(UploadError),
+    Download(DownloadError),
+    Layout(usize), // cluster whose attachments don't match the slice layout
+    #[default]
+    JoinError,
+}
+
+impl From<UploadError> for TransferError {
+    fn from(value: UploadError) -> Self {
+        Self::Upload(value)
+    }
+}
+
+impl From<DownloadError> for TransferError {
+    fn from(value: DownloadError) -> Self {
+        Self::Download(value)
+    }
+}
+
+impl Serialize for TransferError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match *self {
+            TransferError::Upload(ref err) => err.serialize(serializer),
+            TransferError::Download(ref err) => err.serialize(serializer),
+            TransferError::Layout(cluster) => {
+                let mut state = serializer.serialize_struct("TransferError", 2)?;
+                state.serialize_field("type", "Layout")?;
+                state.serialize_field(
+                    "message",
+                    &format!("Cluster {} doesn't match the slice layout", cluster),
+                )?;
+                state.end()
+            }
+            TransferError::JoinError => {
+                let mut state = serializer.serialize_struct("TransferError", 2)?;
+                state.serialize_field("type", "JoinError")?;
+                state.serialize_field("message", "")?;
+                state.end()
+            }
+        }
+    }
+}
+
+impl Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Upload(err) => write!(f, "Upload: {}", err),
+            Self::Download(err) => write!(f, "Download: {}", err),
+            Self::Layout(cluster) => write!(f, "Layout: cluster {}", cluster),
+            Self::JoinError => write!(f, "Join Error"),
         }
     }
 }
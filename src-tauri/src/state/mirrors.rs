@@ -0,0 +1,245 @@
+use super::errors::{DownloadError, UploadError};
+use super::model::{File, Mirror};
+use super::parity::{self, Recovery, Seal};
+use super::stripes::Location;
+use crate::api::{self, Auth, Take};
+use crate::io::reader::InsecureReader;
+use crate::io::secure_reader::SecureReader;
+use crate::io::{Cipher, Format, Progress, Slicing};
+
+use std::cmp;
+use std::future::Future;
+use std::iter;
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+
+// Uploads a copy of the stored stream to every mirror, once the primary channel has it
+pub async fn upload(
+    auth: Arc<Auth>,
+    mirrors: Vec<String>,
+    source: String,
+    slicing: Slicing,
+    seal: Option<Seal>,
+) -> Result<Vec<Mirror>, UploadError> {
+    let mut uploaded = Vec::with_capacity(mirrors.len());
+    for channel_id in mirrors {
+        log::info!("Mirroring file to channel: {}", channel_id);
+        let channel = Arc::new(channel_id.clone());
+
+        // Progress and checksums only cover the primary copy
+        let read_tx = Progress::none();
+        let (crc_tx, crc_rx) = mpsc::channel(4);
+        parity::drain(crc_rx);
+
+        // The nonces are derived from the position, so every copy encrypts to the same bytes
+        let download_ids = match seal {
+            Some(seal) => {
+                let cipher = Cipher::new(seal.algorithm, &seal.key);
+                let mut reader = SecureReader::new(
+                    &source,
+                    cipher,
+                    Format::V2,
+                    seal.id,
+                    slicing,
+                    read_tx,
+                    crc_tx,
+                )
+                .map_err(UploadError::Io)?;
+
+                let clusters = iter::from_fn(|| reader.next_cluster())
+                    .map(|cluster| (cluster.get_size(), cluster))
+                    .collect();
+                parity::upload_clusters(&auth, &channel, clusters).await?
+            }
+            None => {
+                let mut reader = InsecureReader::new(&source, slicing, read_tx, crc_tx)
+                    .map_err(UploadError::Io)?;
+
+                let clusters = iter::from_fn(|| reader.next_cluster())
+                    .map(|cluster| (cluster.get_size(), cluster))
+                    .collect();
+                parity::upload_clusters(&auth, &channel, clusters).await?
+            }
+        };
+
+        uploaded.push(Mirror {
+            channel_id,
+            download_ids,
+        });
+    }
+
+    Ok(uploaded)
+}
+
+// The copies a file can be downloaded from, the primary one first and its mirrors after it
+pub struct Sources {
+    sources: Vec<(Vec<Location>, Vec<u64>)>,
+}
+
+impl Sources {
+    // Mirrors are written with the primary credentials, the primary copy may be striped
+    pub fn new(auth: Arc<Auth>, locations: Vec<Location>, file: &File) -> Self {
+        let mirrors = file.mirrors.iter().map(|mirror| {
+            let location = Location {
+                auth: auth.clone(),
+                channel: Arc::new(mirror.channel_id.clone()),
+            };
+
+            (
+                vec![location; mirror.download_ids.len()],
+                mirror.download_ids.clone(),
+            )
+        });
+
+        Self {
+            sources: iter::once((locations, file.download_ids.clone()))
+                .chain(mirrors)
+                .collect(),
+        }
+    }
+
+    // Fetches the attachments of every cluster and hands them over, taking the clusters of a
+    // channel that is gone from the mirrors. Returns early when `dispatch` reports a cancel
+    pub async fn fetch<F>(
+        &self,
+        recovery: Option<&Recovery>,
+        mut dispatch: F,
+    ) -> Result<(), DownloadError>
+    where
+        F: FnMut(usize, Vec<String>) -> bool,
+    {
+        let (locations, ids) = &self.sources[0];
+        let message_count = cmp::min(ids.len() * 2, 100);
+        let mut pending = (0..ids.len()).collect::<Vec<_>>();
+
+        while let Some(&cluster) = pending.first() {
+            let Location { auth, channel } = &locations[cluster];
+            let mut messages =
+                match api::fetch_messages(auth, channel, ids[cluster], message_count).await {
+                    Ok(messages) => messages,
+                    Err(err) if is_lost(&err) && self.sources.len() > 1 => {
+                        log::warn!("Channel {} is unavailable, failing over: {}", channel, err);
+                        let (lost, rest) = pending
+                            .into_iter()
+                            .partition::<Vec<_>, _>(|idx| locations[*idx].channel == *channel);
+                        pending = rest;
+
+                        for cluster in lost {
+                            if !self.failover(cluster, recovery, &mut dispatch).await? {
+                                return Ok(());
+                            }
+                        }
+
+                        continue;
+                    }
+                    Err(err) => {
+                        log::error!("failed to fetch messages: {}", err);
+                        return Err(err);
+                    }
+                };
+
+            // Message ids are unique across channels, so they are enough to find the cluster
+            for message in messages.iter_mut() {
+                let message_id = message
+                    .id
+                    .parse::<u64>()
+                    .expect("failed to parse message ID");
+
+                if let Some(idx) = pending.iter().position(|idx| ids[*idx] == message_id) {
+                    let index = pending.remove(idx);
+                    if !dispatch(index, message.attachments.take()) {
+                        return Ok(());
+                    }
+                }
+            }
+
+            if pending.first() != Some(&cluster) {
+                continue;
+            }
+
+            log::warn!("Message not found: {}", ids[cluster]);
+            pending.remove(0);
+
+            if !self.failover(cluster, recovery, &mut dispatch).await? {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Takes a cluster the primary copy lost from the mirrors, or leaves it to the parity
+    async fn failover<F>(
+        &self,
+        cluster: usize,
+        recovery: Option<&Recovery>,
+        dispatch: &mut F,
+    ) -> Result<bool, DownloadError>
+    where
+        F: FnMut(usize, Vec<String>) -> bool,
+    {
+        let mut err = DownloadError::NotFoundRemote;
+        for (locations, ids) in self.sources.iter().skip(1) {
+            let Location { auth, channel } = &locations[cluster];
+            match api::fetch_attachments(auth, channel, &ids[cluster..=cluster]).await {
+                Ok(mut attachments) => {
+                    log::info!("Cluster {} found in channel {}", cluster, channel);
+                    return Ok(dispatch(cluster, attachments.remove(0)));
+                }
+                Err(other) => err = other,
+            }
+        }
+
+        match recovery {
+            Some(recovery) => {
+                recovery.erase(cluster, &err);
+                Ok(true)
+            }
+            None => Err(err),
+        }
+    }
+
+    // Downloads a cluster that failed again from each mirror in turn until one of them decodes
+    pub async fn retry<F, Fut>(
+        &self,
+        cluster: usize,
+        mut err: DownloadError,
+        mut download: F,
+    ) -> Result<(), DownloadError>
+    where
+        F: FnMut(Vec<String>) -> Fut,
+        Fut: Future<Output = Result<(), DownloadError>>,
+    {
+        for (locations, ids) in self.sources.iter().skip(1) {
+            let Location { auth, channel } = &locations[cluster];
+            log::warn!(
+                "Cluster {} failed, retrying from channel {}: {}",
+                cluster,
+                channel,
+                err
+            );
+
+            let result = match api::fetch_attachments(auth, channel, &ids[cluster..=cluster]).await
+            {
+                Ok(mut attachments) => download(attachments.remove(0)).await,
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(other) => err = other,
+            }
+        }
+
+        Err(err)
+    }
+}
+
+// A channel that was deleted or can no longer be read
+fn is_lost(err: &DownloadError) -> bool {
+    matches!(
+        err,
+        DownloadError::NotFound | DownloadError::Forbidden | DownloadError::NotFoundRemote
+    )
+}
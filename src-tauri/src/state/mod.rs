@@ -2,5 +2,14 @@ pub mod bin;
 pub mod errors;
 pub mod model;
 
+mod mirrors;
+mod parity;
+mod profiles;
+pub mod queue;
 mod readers;
+mod rekey;
+mod slicing;
+mod stripes;
+mod threads;
+mod transfer;
 mod writers;
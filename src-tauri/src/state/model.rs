@@ -1,17 +1,26 @@
+use super::errors::InvalidWebhook;
+use crate::api::{self, Auth};
+use crate::io::parity::ErasureCode;
+use crate::io::{Algorithm, Format, Slicing};
+use crate::memory;
+use crate::progress;
 use crate::utils::{download_path, path};
 use crate::AppState;
 
 use std::collections::VecDeque;
+use std::fs;
+use std::io;
 use std::ops::Not;
 use std::path::Path;
-use std::{fs, ptr};
+use std::sync::Weak;
 
+use argon2::{Argon2, Params, Version};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use tauri::{AppHandle, Manager};
-use tokio::sync::oneshot;
+use tokio::sync::{oneshot, RwLock};
 
-pub const CURRENT_VERSION: u16 = 2;
+pub const CURRENT_VERSION: u16 = 3;
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -19,6 +28,8 @@ pub enum Job {
     Idle,
     Upload { cancel_tx: oneshot::Sender<()> },
     Download { cancel_tx: oneshot::Sender<()> },
+    Rekey { cancel_tx: oneshot::Sender<()> },
+    Transfer { cancel_tx: oneshot::Sender<()> },
 }
 
 impl Default for Job {
@@ -34,6 +45,8 @@ impl PartialEq for Job {
             (Self::Idle, Self::Idle)
                 | (Self::Upload { .. }, Self::Upload { .. })
                 | (Self::Download { .. }, Self::Download { .. })
+                | (Self::Rekey { .. }, Self::Rekey { .. })
+                | (Self::Transfer { .. }, Self::Transfer { .. })
         )
     }
 }
@@ -44,6 +57,8 @@ impl Job {
             Self::Idle => true,
             Self::Upload { .. } => true,
             Self::Download { .. } => false,
+            Self::Rekey { .. } => false,
+            Self::Transfer { .. } => false,
         }
     }
 
@@ -52,6 +67,28 @@ impl Job {
             Self::Idle => true,
             Self::Upload { .. } => false,
             Self::Download { .. } => true,
+            Self::Rekey { .. } => false,
+            Self::Transfer { .. } => false,
+        }
+    }
+
+    pub fn is_rekey_extendable(&self) -> bool {
+        match self {
+            Self::Idle => true,
+            Self::Upload { .. } => false,
+            Self::Download { .. } => false,
+            Self::Rekey { .. } => true,
+            Self::Transfer { .. } => false,
+        }
+    }
+
+    pub fn is_transfer_extendable(&self) -> bool {
+        match self {
+            Self::Idle => true,
+            Self::Upload { .. } => false,
+            Self::Download { .. } => false,
+            Self::Rekey { .. } => false,
+            Self::Transfer { .. } => true,
         }
     }
 
@@ -60,44 +97,228 @@ impl Job {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub path: String,
+    pub fingerprint: Fingerprint, // of the file when it was queued
+    pub passphrase: Option<String>,
+    pub algorithm: Algorithm,
+    pub compression: Option<i32>, // zstd level
+    pub thread: Option<String>,   // name of the thread the clusters go to
+    pub snapshot: bool,           // read from a private copy, so the original may change meanwhile
+}
+
+// Tells whether a source file changed, without reading it
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub size: u64,
+    pub modified: u64, // nanoseconds since the epoch, zero where the platform has no mtime
+}
+
+impl Fingerprint {
+    pub fn of(meta: &fs::Metadata) -> Self {
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |modified| modified.as_nanos() as u64);
+
+        Self {
+            size: meta.len(),
+            modified,
+        }
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        fs::metadata(path).map(|meta| Self::of(&meta))
+    }
+}
+
+// An upload or download left in a queue, kept so it can be resumed after a restart. Passphrases
+// are never written to disk, so vault uploads can't be resumed as they are
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Queued {
+    Upload {
+        path: String,
+        fingerprint: Fingerprint,
+        profile: String,
+        channel_id: String,
+        algorithm: Algorithm,
+        compression: Option<i32>,
+        thread: Option<String>,
+        snapshot: bool,
+        vault: bool,
+    },
+    Download {
+        id: u32,
+        profile: String,
+        updated_at: u64, // of the file when it was queued
+    },
+}
+
 #[derive(Debug)]
 pub struct RtState {
-    pub this: *const AppState,
-    pub app_handle: *const AppHandle,
-    pub upload_queue: VecDeque<String>,
+    pub this: Weak<RwLock<State>>, // the state itself, for the tasks a job spawns
+    pub app_handle: Option<AppHandle>,
+    pub upload_queue: VecDeque<Upload>,
+    pub upload: Option<Upload>,  // the one in flight
+    pub changed: Option<Upload>, // stopped by its source changing, kept for a retry
     pub download_queue: VecDeque<u32>,
+    pub rekey_queue: VecDeque<(u32, bool)>, // file id, delete old messages
+    pub transfer_queue: VecDeque<(u32, String, bool)>, // file id, target profile, move
+    pub passphrase_tx: Option<oneshot::Sender<Option<String>>>,
+    pub premium_tier: Option<(String, u8)>, // guild id, boost level
     pub job: Job,
 }
 
 impl Default for RtState {
     fn default() -> Self {
         Self {
-            this: ptr::null(),
-            app_handle: ptr::null(),
+            this: Weak::new(),
+            app_handle: None,
             upload_queue: VecDeque::new(),
+            upload: None,
+            changed: None,
             download_queue: VecDeque::new(),
+            rekey_queue: VecDeque::new(),
+            transfer_queue: VecDeque::new(),
+            passphrase_tx: None,
+            premium_tier: None,
             job: Job::default(),
         }
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AuthMode {
+    #[default]
+    User,
+    Bot,
+    // Posts through a webhook, the token is a bot token used for everything else
+    Webhook,
+}
+
+impl AuthMode {
+    // `bot` reads for a webhook. Posting as the bot when the webhook url does not parse would
+    // silently bypass the webhook, so it is an error instead
+    pub fn auth(self, token: &str, bot: &str, webhook_url: &str) -> Result<Auth, InvalidWebhook> {
+        match (self, api::parse_webhook(webhook_url)) {
+            (Self::User, _) => Ok(Auth::User(token.to_owned())),
+            (Self::Bot, _) => Ok(Auth::Bot(token.to_owned())),
+            (Self::Webhook, None) => Err(InvalidWebhook),
+            (Self::Webhook, Some((id, token))) => Ok(Auth::Webhook {
+                bot: bot.to_owned(),
+                id,
+                token,
+            }),
+        }
+    }
+}
+
+// How requests reach Discord, empty strings leave a setting out
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Network {
+    pub proxy_url: String, // http, https, socks5 or socks5h
+    pub proxy_username: String,
+    pub proxy_password: String,
+    pub ca_bundle: String,    // path to a PEM file of extra root certificates
+    pub timeout: Option<u64>, // seconds, overrides the read timeout of every request
+    pub user_agent: String,
+}
+
+// Bytes per second, none is unlimited
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+pub struct Limits {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Throttle {
+    pub global: Limits,   // shared by every transfer
+    pub transfer: Limits, // each file on its own
+    pub schedule: Vec<Window>,
+}
+
+// Replaces the global limits between two times of the day, in local time
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Window {
+    pub start: u16, // minutes since midnight
+    pub end: u16,
+    pub global: Limits,
+}
+
+// How many clusters may go at once, the actual number is tuned in between
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Bounds {
+    pub min: usize,
+    pub max: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Concurrency {
+    pub upload: Bounds,
+    pub download: Bounds,
+}
+
+impl Default for Concurrency {
+    fn default() -> Self {
+        Self {
+            upload: Bounds { min: 1, max: 8 },
+            download: Bounds { min: 1, max: 6 },
+        }
+    }
+}
+
+// Where the clusters of an upload are finalized
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ThreadMode {
+    #[default]
+    Off,
+    Folder, // a thread per top-level folder of the uploaded files
+    Batch,  // a thread per batch of files added to the queue at once
+}
+
+// A thread created in a storage channel, reused by every upload with the same name
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Thread {
+    pub channel_id: String,
+    pub name: String,
+    pub id: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct State {
     pub next_id: u32,
     pub channel_id: String,
     pub guild_id: String,
     pub token: String,
+    pub auth_mode: AuthMode,
+    pub webhook_url: String,
     pub do_encrypt: bool,
     pub do_checksum: bool,
     pub download_location: String,
+    pub algorithm: Algorithm,
+    pub compression: Option<i32>, // default zstd level, none disables compression
+    pub erasure: Option<ErasureCode>,
+    pub mirrors: Vec<String>, // channel ids every upload is replicated to
+    pub stripes: Vec<Stripe>, // pairs clusters are spread across besides the primary one
+    pub thread_mode: ThreadMode,
+    pub threads: Vec<Thread>,
+    pub network: Network,
+    pub throttle: Throttle,
+    pub concurrency: Concurrency,
+    pub slicing: Option<Slicing>, // none follows the upload limit of the guild
+    pub memory: u64,              // MiB of buffers in flight across every job
+    pub progress_interval: u64,   // ms between two progress reports of a file
+    pub queue: Vec<Queued>,       // left when the app closed, the entry in flight first
     pub files: Vec<File>,
+    pub profile: String, // name of the profile the channel and files belong to
+    pub profiles: Vec<Profile>, // every other profile
     #[serde(skip)]
     pub rt: RtState,
 }
 
-unsafe impl Send for State {}
-unsafe impl Sync for State {}
-
 impl Default for State {
     fn default() -> Self {
         Self {
@@ -105,16 +326,43 @@ impl Default for State {
             channel_id: String::new(),
             guild_id: String::new(),
             token: String::new(),
+            auth_mode: AuthMode::default(),
+            webhook_url: String::new(),
             do_encrypt: true,
             do_checksum: true,
             download_location: download_path().to_string(),
+            algorithm: Algorithm::default(),
+            compression: None,
+            erasure: None,
+            mirrors: Vec::new(),
+            stripes: Vec::new(),
+            thread_mode: ThreadMode::default(),
+            threads: Vec::new(),
+            network: Network::default(),
+            throttle: Throttle::default(),
+            concurrency: Concurrency::default(),
+            slicing: None,
+            memory: memory::DEFAULT_LIMIT,
+            progress_interval: progress::DEFAULT_INTERVAL,
+            queue: Vec::new(),
             files: Vec::new(),
+            profile: "Default".to_owned(),
+            profiles: Vec::new(),
             rt: RtState::default(),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+// A library of its own for a guild and channel, kept while another one is active
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub guild_id: String,
+    pub channel_id: String,
+    pub files: Vec<File>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct File {
     pub id: u32,
     pub path: String,
@@ -126,6 +374,101 @@ pub struct File {
     pub crc32: u32,
     #[serde(with = "serde_bytes")]
     pub encryption_key: Option<[u8; 32]>,
+    pub vault: Option<Vault>,
+    pub format: Format,
+    pub algorithm: Algorithm,
+    pub compression: Option<Compression>,
+    pub erasure: Option<Erasure>,
+    pub mirrors: Vec<Mirror>,
+    pub locations: Vec<String>, // channel id of every cluster, empty when not striped
+    pub slicing: Slicing,
+}
+
+impl File {
+    // Length of the stream that is actually stored, which all of the layout math is based on
+    pub fn stored_size(&self) -> u64 {
+        self.compression
+            .map_or(self.size, |compression| compression.size)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Compression {
+    pub level: i32,
+    pub size: u64, // length of the compressed stream
+}
+
+// Parity of the stored stream, uploaded as a stream of its own after the data
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Erasure {
+    pub code: ErasureCode,
+    pub size: u64, // length of the parity stream
+    pub download_ids: Vec<u64>,
+}
+
+// Another token and channel to upload through, each pair has its own rate limit.
+// In webhook mode the token is the webhook url of the channel
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Stripe {
+    pub token: String,
+    pub channel_id: String,
+}
+
+// A copy of the data stream in another channel, tried when the primary one fails
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Mirror {
+    pub channel_id: String,
+    pub download_ids: Vec<u64>,
+}
+
+// Key derivation parameters of a file whose key comes from a passphrase and is never stored
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Vault {
+    pub salt: [u8; 16],
+    pub memory: u32, // KiB
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub check: [u8; 32], // derived along with the key, tells a wrong passphrase apart
+}
+
+impl Vault {
+    // Generates the parameters and derives the key a new file is encrypted with
+    pub fn seal(passphrase: &str) -> Result<(Self, [u8; 32]), argon2::Error> {
+        let mut rng = rand::thread_rng();
+        let mut salt = [0; 16];
+        rng.fill(&mut salt[..]);
+
+        let mut vault = Self {
+            salt,
+            memory: 64 * 1024,
+            iterations: 3,
+            parallelism: 4,
+            check: [0; 32],
+        };
+
+        let (key, check) = vault.stretch(passphrase)?;
+        vault.check = check;
+
+        Ok((vault, key))
+    }
+
+    // None when the passphrase is not the one the vault was sealed with
+    pub fn derive(&self, passphrase: &str) -> Result<Option<[u8; 32]>, argon2::Error> {
+        let (key, check) = self.stretch(passphrase)?;
+        Ok((check == self.check).then_some(key))
+    }
+
+    // The key and the check are two halves of the same output, one tells nothing about the other
+    fn stretch(&self, passphrase: &str) -> Result<([u8; 32], [u8; 32]), argon2::Error> {
+        let params = Params::new(self.memory, self.iterations, self.parallelism, Some(64))?;
+        let argon2 = Argon2::new(argon2::Algorithm::Argon2id, Version::V0x13, params);
+
+        let mut output = [0; 64];
+        argon2.hash_password_into(passphrase.as_bytes(), &self.salt, &mut output)?;
+
+        let (key, check) = output.split_at(32);
+        Ok((key.try_into().unwrap(), check.try_into().unwrap()))
+    }
 }
 
 impl State {
@@ -193,6 +536,16 @@ impl State {
         id
     }
 
+    pub fn auth(&self) -> Result<Auth, InvalidWebhook> {
+        self.auth_mode
+            .auth(&self.token, &self.token, &self.webhook_url)
+    }
+
+    pub fn stripe_auth(&self, stripe: &Stripe) -> Result<Auth, InvalidWebhook> {
+        self.auth_mode
+            .auth(&stripe.token, &self.token, &stripe.token)
+    }
+
     pub fn aes_key(&self) -> [u8; 32] {
         let mut rng = rand::thread_rng();
         let mut key = [0; 32];
@@ -201,6 +554,16 @@ impl State {
         key
     }
 
+    // Set up before any command runs
+    pub fn handle(&self) -> AppHandle {
+        self.rt.app_handle.clone().expect("app handle not set")
+    }
+
+    // The tasks a job spawns lock the state again once they are done
+    pub fn shared(&self) -> AppState {
+        self.rt.this.upgrade().expect("state dropped")
+    }
+
     pub async fn cancel(&mut self) {
         match self.rt.job.take() {
             Job::Idle => {
@@ -211,6 +574,7 @@ impl State {
                 log::info!("Canceling upload job");
 
                 self.rt.upload_queue.clear();
+                self.forget_queue();
                 if cancel_tx.send(()).is_err() {
                     log::error!("failed to send cancel signal");
                 }
@@ -219,13 +583,31 @@ impl State {
                 log::info!("Canceling download job");
 
                 self.rt.download_queue.clear();
+                self.rt.passphrase_tx = None;
+                self.forget_queue();
+                if cancel_tx.send(()).is_err() {
+                    log::error!("failed to send cancel signal");
+                }
+            }
+            Job::Rekey { cancel_tx } => {
+                log::info!("Canceling rekey job");
+
+                self.rt.rekey_queue.clear();
+                if cancel_tx.send(()).is_err() {
+                    log::error!("failed to send cancel signal");
+                }
+            }
+            Job::Transfer { cancel_tx } => {
+                log::info!("Canceling transfer job");
+
+                self.rt.transfer_queue.clear();
                 if cancel_tx.send(()).is_err() {
                     log::error!("failed to send cancel signal");
                 }
             }
         }
 
-        let handle = unsafe { self.rt.app_handle.as_ref().unwrap() };
+        let handle = self.handle();
         handle
             .emit_all("job_canceled", ())
             .expect("failed to emit job_canceled");
@@ -243,3 +625,37 @@ impl State {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    #[test]
+    fn vault_tells_a_wrong_passphrase_apart() {
+        let (vault, key) = Vault::seal("correct horse").unwrap();
+
+        assert_eq!(vault.derive("correct horse").unwrap(), Some(key));
+        assert_eq!(vault.derive("battery staple").unwrap(), None);
+    }
+
+    fn shared<T: Send + Sync>() {}
+
+    #[test]
+    fn state_is_shared_without_a_cycle() {
+        shared::<State>();
+
+        let state = Arc::new(RwLock::new(State::default()));
+        state.try_write().unwrap().rt.this = Arc::downgrade(&state);
+
+        let this = state.try_read().unwrap().shared();
+        assert!(Arc::ptr_eq(&this, &state));
+        drop(this);
+
+        // The state only reaches itself weakly, so it goes once the app lets go of it
+        let weak = Arc::downgrade(&state);
+        drop(state);
+        assert!(weak.upgrade().is_none());
+    }
+}
@@ -0,0 +1,307 @@
+use super::errors::{DownloadError, UploadError};
+use super::model::{Erasure, File};
+use crate::api::{self, Auth};
+use crate::concurrency;
+use crate::io::consts::BUFFER_SIZE_U;
+use crate::io::parity::{self, ErasureCode, Layout};
+use crate::io::reader::InsecureReader;
+use crate::io::secure_reader::SecureReader;
+use crate::io::secure_writer::SecureWriter;
+use crate::io::writer::InsecureWriter;
+use crate::io::{Algorithm, Cipher, Cluster, Framing, Progress, Slicing};
+use crate::utils::{remove_staged, staging_target};
+
+use std::fmt::Display;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use std::{fs, iter, mem};
+
+use crc32fast::Hasher;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use tokio::sync::mpsc;
+use tokio::task;
+
+// Key of an encrypted file, its parity stream is sealed with the same key
+#[derive(Clone, Copy)]
+pub struct Seal {
+    pub algorithm: Algorithm,
+    pub key: [u8; 32],
+    pub id: u32,
+}
+
+// Computes the parity of the stream that was just uploaded and uploads it after the data
+pub async fn upload(
+    auth: Arc<Auth>,
+    channel: Arc<String>,
+    code: ErasureCode,
+    source: String,
+    size: u64,
+    slicing: Slicing,
+    seal: Option<Seal>,
+) -> Result<Erasure, UploadError> {
+    let layout = Layout::new(code, size, slicing, seal.is_some());
+    let target = staging_target();
+
+    let result = upload_staged(
+        &auth,
+        &channel,
+        layout,
+        slicing,
+        source,
+        target.clone(),
+        seal,
+    )
+    .await;
+    remove_staged(&target);
+
+    Ok(Erasure {
+        code,
+        size: layout.parity_size(),
+        download_ids: result?,
+    })
+}
+
+async fn upload_staged(
+    auth: &Arc<Auth>,
+    channel: &Arc<String>,
+    layout: Layout,
+    slicing: Slicing,
+    source: String,
+    target: String,
+    seal: Option<Seal>,
+) -> Result<Vec<u64>, UploadError> {
+    let now = Instant::now();
+    let target2 = target.clone();
+    task::spawn_blocking(move || parity::encode(&layout, &source, &target2))
+        .await
+        .map_err(|_| UploadError::JoinError)?
+        .map_err(UploadError::Io)?;
+
+    log::info!(
+        "Computed {} bytes of parity in {:.2}s",
+        layout.parity_size(),
+        now.elapsed().as_secs_f64()
+    );
+
+    // Progress and checksums only cover the data stream
+    let read_tx = Progress::none();
+    let (crc_tx, crc_rx) = mpsc::channel(4);
+    drain(crc_rx);
+
+    match seal {
+        Some(seal) => {
+            let cipher = Cipher::new(seal.algorithm, &seal.key);
+            let mut reader =
+                SecureReader::parity(&target, cipher, seal.id, slicing, read_tx, crc_tx)
+                    .map_err(UploadError::Io)?;
+
+            let clusters = iter::from_fn(|| reader.next_cluster())
+                .map(|cluster| (cluster.get_size(), cluster))
+                .collect();
+            upload_clusters(auth, channel, clusters).await
+        }
+        None => {
+            let mut reader =
+                InsecureReader::new(&target, slicing, read_tx, crc_tx).map_err(UploadError::Io)?;
+
+            let clusters = iter::from_fn(|| reader.next_cluster())
+                .map(|cluster| (cluster.get_size(), cluster))
+                .collect();
+            upload_clusters(auth, channel, clusters).await
+        }
+    }
+}
+
+pub async fn upload_clusters<C>(
+    auth: &Arc<Auth>,
+    channel: &Arc<String>,
+    clusters: Vec<(u64, C)>,
+) -> Result<Vec<u64>, UploadError>
+where
+    C: Cluster + Send + Sync,
+    <C as Cluster>::Iter: Send + Sync + 'static,
+{
+    stream::iter(clusters)
+        .map(|(size, cluster)| async move {
+            let permit = concurrency::UPLOAD.acquire().await;
+            let details = api::preupload(auth, channel, size, cluster.slice_size()).await?;
+            api::upload(&details, cluster).await?;
+            let id = api::finalize(auth, channel, &details).await?;
+
+            permit.done(size);
+            Ok(id)
+        })
+        .buffered(concurrency::UPLOAD.max())
+        .try_collect()
+        .await
+}
+
+// Collects the clusters that could not be downloaded and rebuilds them from the parity
+pub struct Recovery {
+    auth: Arc<Auth>,
+    channel: Arc<String>,
+    erasure: Erasure,
+    layout: Layout,
+    slicing: Slicing,
+    seal: Option<Seal>,
+    erased: Mutex<Vec<u64>>,
+}
+
+impl Recovery {
+    // Files uploaded without parity can't be recovered
+    pub fn new(
+        auth: Arc<Auth>,
+        channel: Arc<String>,
+        file: &File,
+        key: Option<[u8; 32]>,
+    ) -> Option<Self> {
+        let erasure = file.erasure.clone()?;
+        let seal = key.map(|key| Seal {
+            algorithm: file.algorithm,
+            key,
+            id: file.id,
+        });
+
+        Some(Self {
+            auth,
+            channel,
+            layout: Layout::new(
+                erasure.code,
+                file.stored_size(),
+                file.slicing,
+                seal.is_some(),
+            ),
+            slicing: file.slicing,
+            erasure,
+            seal,
+            erased: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn erase(&self, cluster: usize, reason: &dyn Display) {
+        log::warn!(
+            "Lost cluster {}, rebuilding it from parity: {}",
+            cluster,
+            reason
+        );
+        self.erased
+            .lock()
+            .expect("failed to lock erased clusters")
+            .push(cluster as u64);
+    }
+
+    // Returns the checksum of the rebuilt stream when there was one to begin with
+    pub async fn recover(
+        &self,
+        output: &str,
+        crc: Option<u32>,
+    ) -> Result<Option<u32>, DownloadError> {
+        let erased = mem::take(&mut *self.erased.lock().expect("failed to lock erased clusters"));
+        if erased.is_empty() {
+            return Ok(crc);
+        }
+
+        if !self.layout.is_recoverable(&erased) {
+            log::error!("Lost {} cluster(s), too many to rebuild", erased.len());
+            return Err(DownloadError::Unrecoverable(erased.len()));
+        }
+
+        let now = Instant::now();
+        let parity = staging_target();
+        let lost = erased.len();
+
+        let result = self
+            .rebuild(output.to_owned(), parity.clone(), erased, crc.is_some())
+            .await;
+        remove_staged(&parity);
+
+        log::info!(
+            "Rebuilt {} cluster(s) in {:.2}s",
+            lost,
+            now.elapsed().as_secs_f64()
+        );
+        result
+    }
+
+    async fn rebuild(
+        &self,
+        output: String,
+        parity: String,
+        erased: Vec<u64>,
+        checksum: bool,
+    ) -> Result<Option<u32>, DownloadError> {
+        self.download(&parity).await?;
+
+        let layout = self.layout;
+        task::spawn_blocking(move || {
+            parity::reconstruct(&layout, &output, &parity, &erased)?;
+            checksum.then(|| crc32(&output)).transpose()
+        })
+        .await
+        .map_err(|_| DownloadError::JoinError)?
+        .map_err(DownloadError::Io)
+    }
+
+    async fn download(&self, target: &str) -> Result<(), DownloadError> {
+        let ids = &self.erasure.download_ids;
+        let attachments = api::fetch_attachments(&self.auth, &self.channel, ids).await?;
+
+        let tx = Progress::none();
+
+        let clusters = stream::iter(attachments.into_iter().enumerate()).map(Ok);
+        let download_threads = concurrency::DOWNLOAD.max();
+        let slice_size = self.slicing.slice_size;
+        match self.seal {
+            Some(seal) => {
+                let cipher = Cipher::new(seal.algorithm, &seal.key);
+                let framing = Framing::parity(seal.id, self.erasure.size, self.slicing);
+                let writer = SecureWriter::new(target, cipher, framing, tx, None)?;
+
+                let writer = &writer;
+                clusters
+                    .try_for_each_concurrent(download_threads, |(index, urls)| async move {
+                        let permit = concurrency::DOWNLOAD.acquire().await;
+                        let size = urls.len() as u64 * slice_size;
+                        writer.cluster(index, urls).download().await?;
+
+                        permit.done(size);
+                        Ok(())
+                    })
+                    .await
+            }
+            None => {
+                let writer = InsecureWriter::new(target, self.slicing, tx, None)?;
+
+                let writer = &writer;
+                clusters
+                    .try_for_each_concurrent(download_threads, |(index, urls)| async move {
+                        let permit = concurrency::DOWNLOAD.acquire().await;
+                        let size = urls.len() as u64 * slice_size;
+                        writer.cluster(index, urls).download().await?;
+
+                        permit.done(size);
+                        Ok(())
+                    })
+                    .await
+            }
+        }
+    }
+}
+
+fn crc32(path: &str) -> io::Result<u32> {
+    let mut file = fs::File::open(path)?;
+    let mut buffer = vec![0; BUFFER_SIZE_U];
+    let mut hasher = Hasher::new();
+
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(hasher.finalize()),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}
+
+pub fn drain<T: Send + 'static>(mut rx: mpsc::Receiver<T>) {
+    tokio::spawn(async move { while rx.recv().await.is_some() {} });
+}
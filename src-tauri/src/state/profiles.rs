@@ -0,0 +1,178 @@
+use super::model::{Job, Profile, State};
+
+use serde::Serialize;
+use tauri::Manager;
+
+#[derive(Debug, Serialize)]
+pub struct Summary<'a> {
+    name: &'a str,
+    guild: &'a str,
+    channel: &'a str,
+    files: usize,
+    active: bool,
+}
+
+impl State {
+    // Every profile, the active one first
+    pub fn profiles(&self) -> Vec<Summary<'_>> {
+        let active = Summary {
+            name: &self.profile,
+            guild: &self.guild_id,
+            channel: &self.channel_id,
+            files: self.files.len(),
+            active: true,
+        };
+
+        let stashed = self.profiles.iter().map(|profile| Summary {
+            name: &profile.name,
+            guild: &profile.guild_id,
+            channel: &profile.channel_id,
+            files: profile.files.len(),
+            active: false,
+        });
+
+        [active].into_iter().chain(stashed).collect()
+    }
+
+    pub fn add_profile(&mut self, name: &str, guild: &str, channel: &str) -> Result<(), String> {
+        let (name, guild, channel) = (name.trim(), guild.trim(), channel.trim());
+        if name.is_empty() {
+            return Err("A profile needs a name".to_owned());
+        }
+
+        if self.has_profile(name) {
+            return Err(format!("A profile named {} already exists", name));
+        }
+
+        if guild.is_empty() || channel.is_empty() {
+            return Err("A profile needs a guild and a channel".to_owned());
+        }
+
+        if let Some(other) = self.find_profile(guild, channel) {
+            return Err(format!("The channel is already used by profile {}", other));
+        }
+
+        log::info!("Adding profile: {}", name);
+        self.profiles.push(Profile {
+            name: name.to_owned(),
+            guild_id: guild.to_owned(),
+            channel_id: channel.to_owned(),
+            files: Vec::new(),
+        });
+
+        Ok(())
+    }
+
+    // Puts the active library aside and restores the one of the profile
+    pub fn switch_profile(&mut self, name: &str) -> Result<(), String> {
+        if self.profile == name {
+            return Ok(());
+        }
+
+        // Finished uploads and rekeys write into the active library
+        if self.rt.job != Job::Idle {
+            return Err("Profiles can't be switched while a job is running".to_owned());
+        }
+
+        let idx = self
+            .profiles
+            .iter()
+            .position(|profile| profile.name == name)
+            .ok_or_else(|| format!("No profile named {}", name))?;
+
+        log::info!("Switching from profile {} to {}", self.profile, name);
+        let profile = self.profiles.remove(idx);
+        self.stash();
+
+        self.profile = profile.name;
+        self.guild_id = profile.guild_id;
+        self.channel_id = profile.channel_id;
+        self.files = profile.files;
+
+        Ok(())
+    }
+
+    // Moves the active profile to another channel, the library of that channel is restored if it
+    // has one and a new one is started otherwise
+    pub fn select_channel(&mut self, guild: String, channel: String) -> Result<(), String> {
+        self.check_channel(&guild, &channel)?;
+        if self.guild_id == guild && self.channel_id == channel {
+            return Ok(());
+        }
+
+        if let Some(name) = self.find_profile(&guild, &channel) {
+            let name = name.to_owned();
+            return self.switch_profile(&name);
+        }
+
+        // Nothing was stored yet, so there is nothing to put aside
+        if self.files.is_empty() {
+            self.guild_id = guild;
+            self.channel_id = channel;
+            return Ok(());
+        }
+
+        // Named after the channel, numbered if the user already took that name
+        let mut name = channel.clone();
+        let mut n = 2;
+        while self.has_profile(&name) {
+            name = format!("{} ({})", channel, n);
+            n += 1;
+        }
+
+        log::info!("Starting profile {} for channel {}", name, channel);
+        self.stash();
+
+        self.profile = name;
+        self.guild_id = guild;
+        self.channel_id = channel;
+
+        Ok(())
+    }
+
+    // Whether the active profile can move to the channel, so settings can be checked as a whole
+    pub fn check_channel(&self, guild: &str, channel: &str) -> Result<(), String> {
+        if self.guild_id == guild && self.channel_id == channel {
+            return Ok(());
+        }
+
+        // Finished uploads and rekeys write into the active library
+        if self.rt.job != Job::Idle {
+            return Err("The channel can't be changed while a job is running".to_owned());
+        }
+
+        Ok(())
+    }
+
+    // Hands the restored library to the frontend
+    pub fn emit_profile(&self) {
+        let handle = self.handle();
+        handle
+            .emit_all("profile_switched", &self.files)
+            .expect("failed to emit profile_switched");
+    }
+
+    fn has_profile(&self, name: &str) -> bool {
+        self.profile == name || self.profiles.iter().any(|profile| profile.name == name)
+    }
+
+    fn find_profile(&self, guild: &str, channel: &str) -> Option<&str> {
+        if self.guild_id == guild && self.channel_id == channel {
+            return Some(&self.profile);
+        }
+
+        self.profiles
+            .iter()
+            .find(|profile| profile.guild_id == guild && profile.channel_id == channel)
+            .map(|profile| profile.name.as_str())
+    }
+
+    fn stash(&mut self) {
+        self.profiles.push(Profile {
+            name: std::mem::take(&mut self.profile),
+            guild_id: std::mem::take(&mut self.guild_id),
+            channel_id: std::mem::take(&mut self.channel_id),
+            files: std::mem::take(&mut self.files),
+        });
+    }
+}
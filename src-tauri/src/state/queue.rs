@@ -0,0 +1,306 @@
+use super::model::{Fingerprint, Job, Queued, State, Upload};
+
+use std::mem;
+
+use serde::Serialize;
+
+// Whether an entry left from the last run can go as it was queued
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    Ready,
+    Changed,    // the source was modified since it was queued
+    Missing,    // the source or the stored file is gone
+    Passphrase, // a vault upload, its passphrase was never kept
+    Elsewhere,  // queued for another profile or channel
+}
+
+impl State {
+    pub fn resumable(&self) -> Vec<(Queued, Resume)> {
+        self.queue
+            .iter()
+            .map(|entry| (entry.clone(), self.check_queued(entry)))
+            .collect()
+    }
+
+    // Queues again whatever can go as it is, everything else is handed back instead of dropped
+    pub fn resume_queue(&mut self, resume: bool) -> Result<Vec<(Queued, Resume)>, String> {
+        if self.rt.job != Job::Idle {
+            return Err("Another job is running".to_owned());
+        }
+
+        let entries = mem::take(&mut self.queue);
+        if !resume {
+            log::info!("Discarding {} queued entries", entries.len());
+            self.write();
+            return Ok(Vec::new());
+        }
+
+        let mut uploads = Vec::new();
+        let mut downloads = Vec::new();
+        let mut flagged = Vec::new();
+        for entry in entries {
+            match (self.check_queued(&entry), entry) {
+                (
+                    Resume::Ready,
+                    Queued::Upload {
+                        path,
+                        fingerprint,
+                        algorithm,
+                        compression,
+                        thread,
+                        snapshot,
+                        ..
+                    },
+                ) => uploads.push(Upload {
+                    path,
+                    fingerprint,
+                    passphrase: None,
+                    algorithm,
+                    compression,
+                    thread,
+                    snapshot,
+                }),
+                (Resume::Ready, Queued::Download { id, .. }) => downloads.push(id),
+                (status, entry) => {
+                    log::warn!("Not resuming {:?}: {:?}", entry, status);
+                    flagged.push((entry, status));
+                }
+            }
+        }
+
+        log::info!(
+            "Resuming {} upload(s) and {} download(s)",
+            uploads.len(),
+            downloads.len()
+        );
+
+        // Only one kind is ever queued at once, so at most one of them starts a job
+        self.write();
+        if !uploads.is_empty() {
+            self.enqueue_uploads(uploads);
+        }
+
+        if !downloads.is_empty() {
+            self.extend_download_queue(downloads);
+        }
+
+        Ok(flagged)
+    }
+
+    // Written as each entry starts, so a restart picks up from the one in flight
+    pub fn remember_uploads(&mut self, current: Option<&Upload>) {
+        self.queue = current
+            .into_iter()
+            .chain(&self.rt.upload_queue)
+            .map(|upload| self.queued_upload(upload))
+            .collect();
+        self.write();
+    }
+
+    pub fn remember_downloads(&mut self, current: Option<u32>) {
+        self.queue = current
+            .into_iter()
+            .chain(self.rt.download_queue.iter().copied())
+            .filter_map(|id| self.queued_download(id))
+            .collect();
+        self.write();
+    }
+
+    pub fn forget_queue(&mut self) {
+        self.queue.clear();
+        self.write();
+    }
+
+    pub fn queued_upload(&self, upload: &Upload) -> Queued {
+        Queued::Upload {
+            path: upload.path.clone(),
+            fingerprint: upload.fingerprint,
+            profile: self.profile.clone(),
+            channel_id: self.channel_id.clone(),
+            algorithm: upload.algorithm,
+            compression: upload.compression,
+            thread: upload.thread.clone(),
+            snapshot: upload.snapshot,
+            vault: upload.passphrase.is_some(),
+        }
+    }
+
+    pub fn queued_download(&self, id: u32) -> Option<Queued> {
+        let file = self.files.iter().find(|file| file.id == id)?;
+        Some(Queued::Download {
+            id,
+            profile: self.profile.clone(),
+            updated_at: file.updated_at,
+        })
+    }
+
+    fn check_queued(&self, entry: &Queued) -> Resume {
+        match entry {
+            Queued::Upload {
+                path,
+                fingerprint,
+                profile,
+                channel_id,
+                vault,
+                ..
+            } => {
+                if *profile != self.profile || *channel_id != self.channel_id {
+                    return Resume::Elsewhere;
+                }
+
+                if *vault {
+                    return Resume::Passphrase;
+                }
+
+                match Fingerprint::read(path) {
+                    Ok(current) if current == *fingerprint => Resume::Ready,
+                    Ok(_) => Resume::Changed,
+                    Err(_) => Resume::Missing,
+                }
+            }
+            Queued::Download {
+                id,
+                profile,
+                updated_at,
+            } => {
+                if *profile != self.profile {
+                    return Resume::Elsewhere;
+                }
+
+                // Renamed, rekeyed or moved files all count as changed
+                match self.files.iter().find(|file| file.id == *id) {
+                    Some(file) if file.updated_at == *updated_at => Resume::Ready,
+                    Some(_) => Resume::Changed,
+                    None => Resume::Missing,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::Algorithm;
+    use crate::state::model::File;
+
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn state() -> State {
+        State {
+            profile: "Default".to_owned(),
+            channel_id: "1".to_owned(),
+            files: vec![File {
+                id: 7,
+                updated_at: 100,
+                ..File::default()
+            }],
+            ..State::default()
+        }
+    }
+
+    fn source(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "thunderstorm-queue-{}-{}",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, contents).expect("failed to write source");
+        path
+    }
+
+    fn upload(path: &Path, fingerprint: Fingerprint, channel_id: &str, vault: bool) -> Queued {
+        Queued::Upload {
+            path: path.to_string_lossy().into_owned(),
+            fingerprint,
+            profile: "Default".to_owned(),
+            channel_id: channel_id.to_owned(),
+            algorithm: Algorithm::default(),
+            compression: None,
+            thread: None,
+            snapshot: false,
+            vault,
+        }
+    }
+
+    fn download(id: u32, profile: &str, updated_at: u64) -> Queued {
+        Queued::Download {
+            id,
+            profile: profile.to_owned(),
+            updated_at,
+        }
+    }
+
+    #[test]
+    fn upload_resumes_while_the_source_is_unchanged() {
+        let state = state();
+        let path = source("unchanged", b"hello");
+        let fingerprint = Fingerprint::read(&path).unwrap();
+
+        assert_eq!(
+            state.check_queued(&upload(&path, fingerprint, "1", false)),
+            Resume::Ready
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn upload_of_a_modified_or_missing_source_is_flagged() {
+        let state = state();
+        let path = source("modified", b"hello");
+        let fingerprint = Fingerprint {
+            size: 4,
+            ..Fingerprint::read(&path).unwrap()
+        };
+
+        assert_eq!(
+            state.check_queued(&upload(&path, fingerprint, "1", false)),
+            Resume::Changed
+        );
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            state.check_queued(&upload(&path, fingerprint, "1", false)),
+            Resume::Missing
+        );
+    }
+
+    #[test]
+    fn upload_needs_the_same_channel_and_no_vault() {
+        let state = state();
+        let path = source("elsewhere", b"hello");
+        let fingerprint = Fingerprint::read(&path).unwrap();
+
+        assert_eq!(
+            state.check_queued(&upload(&path, fingerprint, "2", false)),
+            Resume::Elsewhere
+        );
+        assert_eq!(
+            state.check_queued(&upload(&path, fingerprint, "1", true)),
+            Resume::Passphrase
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn download_follows_the_stored_file() {
+        let state = state();
+
+        assert_eq!(
+            state.check_queued(&download(7, "Default", 100)),
+            Resume::Ready
+        );
+        assert_eq!(
+            state.check_queued(&download(7, "Default", 101)),
+            Resume::Changed
+        );
+        assert_eq!(
+            state.check_queued(&download(8, "Default", 100)),
+            Resume::Missing
+        );
+        assert_eq!(
+            state.check_queued(&download(7, "Other", 100)),
+            Resume::Elsewhere
+        );
+    }
+}
@@ -1,12 +1,23 @@
 use super::errors::UploadError;
-use super::model::{File, Job, State};
+use super::mirrors;
+use super::model::{Compression, File, Fingerprint, Job, State, Upload, Vault};
+use super::parity::{self, Seal};
+use super::slicing;
+use super::stripes::{self, Location};
+use super::threads;
 use crate::api;
+use crate::concurrency;
+use crate::io::compression::{self, Compressed};
 use crate::io::consts::UPLOAD_THREADS;
-use crate::io::reader::{InsecureClusterR, InsecureReader};
-use crate::io::secure_reader::{SecureClusterR, SecureReader};
-use crate::utils::Flatten;
+use crate::io::reader::InsecureReader;
+use crate::io::secure_reader::SecureReader;
+use crate::io::snapshot::{self, Snapshot};
+use crate::io::{Algorithm, Cipher, Cluster, Format, Slicing};
+use crate::progress::{Kind, Phase, Tracker};
+use crate::utils::{staging_target, Flatten};
 
 use std::fs;
+use std::iter;
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -16,16 +27,24 @@ use futures::stream::{self, StreamExt, TryStreamExt};
 use tauri::Manager;
 use tokio::select;
 use tokio::sync::{mpsc, oneshot};
+use tokio::task;
 
 impl State {
-    pub fn extend_upload_queue(&mut self, files: Vec<String>) {
+    pub fn extend_upload_queue(
+        &mut self,
+        files: Vec<String>,
+        passphrase: Option<String>,
+        algorithm: Option<Algorithm>,
+        compression: Option<i32>,
+        snapshot: bool,
+    ) {
         if !self.rt.job.is_upload_extendable() {
             log::warn!("Not uploading, ignoring files");
             return;
         }
 
         let mut queue = Vec::with_capacity(files.len());
-        let handle = unsafe { self.rt.app_handle.as_ref().unwrap() };
+        let handle = self.handle();
 
         for file in files {
             let meta = match fs::metadata(&file) {
@@ -47,7 +66,7 @@ impl State {
             }
 
             if meta.is_file() {
-                queue.push((file.clone(), len));
+                queue.push((file.clone(), Fingerprint::of(&meta)));
             }
         }
 
@@ -56,271 +75,556 @@ impl State {
             return;
         }
 
-        handle
+        let algorithm = algorithm.unwrap_or(self.algorithm);
+        let levels = zstd::compression_level_range();
+        let compression = compression
+            .or(self.compression)
+            .map(|level| level.clamp(*levels.start(), *levels.end()));
+        let paths = queue
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        let threads = self.thread_names(&paths);
+        let uploads = queue
+            .into_iter()
+            .zip(threads)
+            .map(|((path, fingerprint), thread)| Upload {
+                path,
+                fingerprint,
+                passphrase: passphrase.clone(),
+                algorithm,
+                compression,
+                thread,
+                snapshot,
+            })
+            .collect();
+
+        self.enqueue_uploads(uploads);
+    }
+
+    pub fn enqueue_uploads(&mut self, uploads: Vec<Upload>) {
+        let queue = uploads
+            .iter()
+            .map(|upload| (&upload.path, upload.fingerprint.size))
+            .collect::<Vec<_>>();
+        self.handle()
             .emit_all("extend_upload_queue", &queue)
             .expect("failed to emit extend_upload_queue");
 
-        log::info!("Extending the queue with {} files", queue.len());
-        self.rt
-            .upload_queue
-            .extend(queue.into_iter().map(|(file, _)| file));
+        log::info!("Extending the queue with {} files", uploads.len());
+        let queued = uploads
+            .iter()
+            .map(|upload| self.queued_upload(upload))
+            .collect::<Vec<_>>();
+        self.rt.upload_queue.extend(uploads);
 
         if self.rt.job == Job::Idle {
             log::info!("Starting uploading {} files", self.rt.upload_queue.len());
             self.upload();
+        } else {
+            self.queue.extend(queued);
+            self.write();
         }
     }
 
+    // Uploads the file whose source changed once more, read afresh or from a copy taken first
+    pub fn retry_upload(&mut self, snapshot: bool) -> Result<(), String> {
+        if self.rt.job != Job::Idle {
+            return Err("Another job is running".to_owned());
+        }
+
+        let mut upload = match self.rt.changed.take() {
+            Some(upload) => upload,
+            None => return Err("No upload to retry".to_owned()),
+        };
+
+        upload.fingerprint = Fingerprint::read(&upload.path).map_err(|err| err.to_string())?;
+        upload.snapshot = snapshot;
+
+        log::info!("Retrying upload of {}", upload.path);
+        self.enqueue_uploads(vec![upload]);
+        Ok(())
+    }
+
     fn upload(&mut self) {
-        let file = match self.rt.upload_queue.pop_front() {
-            Some(file) => file,
+        let mut upload = match self.rt.upload_queue.pop_front() {
+            Some(upload) => upload,
             None => {
                 log::info!("No more files to upload, stopping");
 
                 self.rt.job = Job::Idle;
+                self.rt.upload = None;
+                self.forget_queue();
                 return;
             }
         };
 
-        log::info!("Uploading file: {}", file);
+        // Changes before the upload starts are fine, only the ones while it's read are caught.
+        // A missing file fails to open further on
+        if let Ok(fingerprint) = Fingerprint::read(&upload.path) {
+            upload.fingerprint = fingerprint;
+        }
+
+        self.rt.upload = Some(upload.clone());
+        self.remember_uploads(Some(&upload));
+
+        log::info!("Uploading file: {}", upload.path);
         let (cancel_tx, cancel_rx) = oneshot::channel::<()>();
         self.rt.job = Job::Upload { cancel_tx };
 
-        if self.do_encrypt {
-            self.upload_secure(file, cancel_rx);
-        } else {
-            self.upload_insecure(file, cancel_rx);
+        match self.needs_premium_tier() {
+            true => self.upload_tier(upload, cancel_rx),
+            false => self.upload_routed(upload, cancel_rx),
         }
     }
 
-    fn upload_secure(&mut self, file: String, cancel_rx: oneshot::Receiver<()>) {
-        let (tx, mut rx) = mpsc::channel::<usize>(10);
-        let handle = unsafe { self.rt.app_handle.as_ref().unwrap() };
-
-        tokio::spawn(async move {
-            let mut bytes = 0;
-            while let Some(read) = rx.recv().await {
-                bytes += read;
-                handle
-                    .emit_all("upload_progress", bytes)
-                    .expect("failed to emit upload_progress");
-            }
-        });
+    fn upload_routed(&mut self, upload: Upload, cancel_rx: oneshot::Receiver<()>) {
+        match &upload.thread {
+            Some(name) if self.thread_id(name).is_none() => self.upload_thread(upload, cancel_rx),
+            _ => self.upload_prepared(upload, cancel_rx),
+        }
+    }
 
-        let (crc_tx, mut crc_rx) = mpsc::channel::<(u64, Hasher)>(4);
+    // Looks up how large the attachments of the guild can be, the first time it uploads
+    fn upload_tier(&mut self, upload: Upload, mut cancel_rx: oneshot::Receiver<()>) {
+        // The upload itself reports credentials that don't resolve
+        let Ok(auth) = self.auth() else {
+            return self.upload_routed(upload, cancel_rx);
+        };
+        let guild = self.guild_id.clone();
 
-        let crc_handle = tokio::spawn(async move {
-            let mut hashers = Vec::new();
-            while let Some((idx, hasher)) = crc_rx.recv().await {
-                let idx = idx as usize;
-                if hashers.len() <= idx {
-                    hashers.resize(idx + 1, unsafe { std::mem::zeroed() });
+        let state = self.shared();
+        tokio::spawn(async move {
+            let tier = select! {
+                tier = slicing::premium_tier(auth, &guild) => tier,
+                _ = &mut cancel_rx => {
+                    log::debug!("Upload canceled");
+                    return;
                 }
+            };
 
-                hashers.insert(idx, hasher);
-            }
-
-            let mut hasher = Hasher::new();
-            for other in hashers {
-                hasher.combine(&other);
-            }
-
-            Ok(hasher.finalize())
+            let mut state = state.write().await;
+            state.rt.premium_tier = Some((guild, tier));
+            state.upload_routed(upload, cancel_rx);
         });
+    }
 
-        let key = self.aes_key();
-        let mut reader = match SecureReader::new(&file, &key, tx, crc_tx) {
-            Ok(reader) => reader,
+    fn upload_prepared(&mut self, upload: Upload, cancel_rx: oneshot::Receiver<()>) {
+        // A compressed copy is a snapshot already
+        match (upload.compression, upload.snapshot) {
+            (Some(level), _) => self.upload_compressed(upload, cancel_rx, level),
+            (None, true) => self.upload_snapshot(upload, cancel_rx),
+            (None, false) => self.upload_file(upload, cancel_rx, None),
+        }
+    }
+
+    // Creates the thread the upload goes to, the first time its name comes up
+    fn upload_thread(&mut self, upload: Upload, mut cancel_rx: oneshot::Receiver<()>) {
+        let name = upload.thread.clone().unwrap_or_default();
+        let Location { auth, channel } = match self.pool() {
+            Ok(mut pool) => pool.remove(0),
             Err(err) => {
-                log::error!("failed to open file: {}", file);
+                log::error!("failed to resolve credentials: {}", err);
+                let handle = self.handle();
                 handle
-                    .emit_all("upload_error", &UploadError::Io(err))
+                    .emit_all("upload_error", &UploadError::from(err))
                     .expect("failed to emit upload_error");
 
                 self.rt.job = Job::Idle;
                 self.rt.upload_queue.clear();
+                self.forget_queue();
                 return;
             }
         };
+        let auth = Arc::new(auth.bot());
 
-        let clusters = reader.clusters as usize;
-        let file_size = reader.file_size;
+        let state = self.shared();
+        tokio::spawn(async move {
+            let thread = select! {
+                thread = api::create_thread(&auth, &channel, &name) => thread,
+                _ = &mut cancel_rx => {
+                    log::debug!("Upload canceled");
+                    return;
+                }
+            };
 
-        // Channel ID, cluster index
-        type Sender = (u64, usize);
-        // Upload details, current cluster, finish sender
-        type OneShot = (
-            Vec<api::UploadDetailsInner>,
-            SecureClusterR,
-            mpsc::Sender<Sender>,
-        );
+            let mut state = state.write().await;
+            match thread {
+                Ok(id) => {
+                    state.add_thread(name, id);
+                    state.write();
+                    state.upload_prepared(upload, cancel_rx);
+                }
+                Err(err) => {
+                    log::error!("failed to create thread: {}", err);
+                    let handle = state.handle();
+                    handle
+                        .emit_all("upload_error", &err)
+                        .expect("failed to emit upload_error");
 
-        let (tx, mut rx) = mpsc::channel::<Sender>(UPLOAD_THREADS);
+                    state.rt.upload_queue.clear();
+                    state.forget_queue();
+                    state.rt.job = Job::Idle;
+                }
+            }
+        });
+    }
 
-        let mut senders = Vec::with_capacity(clusters);
-        let mut receivers = Vec::with_capacity(clusters);
+    fn upload_file(
+        &mut self,
+        upload: Upload,
+        cancel_rx: oneshot::Receiver<()>,
+        staged: Option<Staged>,
+    ) {
+        let Upload {
+            path,
+            fingerprint,
+            passphrase,
+            algorithm,
+            thread,
+            ..
+        } = upload;
+
+        let source = Source {
+            path,
+            fingerprint,
+            staged,
+        };
 
-        for _ in 0..clusters {
-            let (sender, receiver) = oneshot::channel::<OneShot>();
-            senders.push(sender);
-            receivers.push(receiver);
+        let thread = thread.and_then(|name| self.thread_id(&name).map(str::to_owned));
+        match passphrase {
+            Some(passphrase) => self.upload_vault(source, cancel_rx, passphrase, algorithm, thread),
+            None if self.do_encrypt => {
+                self.upload_secure(source, cancel_rx, None, algorithm, thread)
+            }
+            None => self.upload_insecure(source, cancel_rx, thread),
         }
+    }
 
-        let token = Arc::new(self.token.clone());
-        let channel = Arc::new(self.channel_id.clone());
-
-        let token2 = token.clone();
-        let channel2 = channel.clone();
-
-        let stream = stream::iter(receivers);
-        let uploaders = stream
-            .map(Ok)
-            .try_for_each_concurrent(UPLOAD_THREADS, move |rx| {
-                let token2 = Arc::clone(&token2);
-                let channel2 = Arc::clone(&channel2);
-
-                async move {
-                    let (details, cluster, sender) = match rx.await {
-                        Ok(result) => result,
-                        Err(_) => return Ok(()), // TODO: comment why returning Ok(()) is actually ok
-                    };
-
-                    let index = cluster.index as usize;
-                    api::upload(&details, cluster).await?;
+    // Deriving the key of a vault file is too slow to do with the state locked
+    fn upload_vault(
+        &mut self,
+        source: Source,
+        mut cancel_rx: oneshot::Receiver<()>,
+        passphrase: String,
+        algorithm: Algorithm,
+        thread: Option<String>,
+    ) {
+        let sealing = task::spawn_blocking(move || Vault::seal(&passphrase));
+
+        let state = self.shared();
+        tokio::spawn(async move {
+            let sealed = select! {
+                sealed = sealing => sealed,
+                _ = &mut cancel_rx => {
+                    log::debug!("Upload canceled");
+                    return;
+                }
+            };
 
-                    let id = api::finalize(&token2, &channel2, &details).await?;
-                    sender
-                        .send((id, index))
-                        .await
-                        .expect("failed to send finish signal");
-                    Ok::<(), UploadError>(())
+            let mut state = state.write().await;
+            let handle = state.handle();
+            let err = match sealed {
+                Ok(Ok(sealed)) => {
+                    state.upload_secure(source, cancel_rx, Some(sealed), algorithm, thread);
+                    return;
                 }
-            });
+                Ok(Err(err)) => {
+                    log::error!("failed to derive key: {}", err);
+                    UploadError::EncryptionError(err.to_string())
+                }
+                Err(_) => UploadError::JoinError,
+            };
 
-        let futures = tokio::spawn(async move {
-            let mut ids = vec![0; clusters];
-            while let Some((id, index)) = rx.recv().await {
-                ids[index] = id;
-            }
+            handle
+                .emit_all("upload_error", &err)
+                .expect("failed to emit upload_error");
 
-            Ok::<_, UploadError>(ids)
+            state.rt.upload_queue.clear();
+            state.forget_queue();
+            state.rt.job = Job::Idle;
         });
+    }
 
-        let token = Arc::clone(&token);
-        let channel = Arc::clone(&channel);
-        let preuploads = tokio::spawn(async move {
-            while let Some(cluster) = reader.next_cluster() {
-                let details = api::preupload(&token, &channel, cluster.get_size()).await;
-                let details = match details {
-                    Ok(details) => details,
-                    Err(err) => return Err(err),
-                };
-
-                let sender = senders.pop().unwrap();
+    // Compresses the file into the staging directory and uploads the compressed copy instead
+    fn upload_compressed(
+        &mut self,
+        upload: Upload,
+        mut cancel_rx: oneshot::Receiver<()>,
+        level: i32,
+    ) {
+        let source = upload.path.clone();
+        let target = staging_target();
+        let compressing =
+            task::spawn_blocking(move || compression::compress(&source, target, level));
+
+        let state = self.shared();
+        tokio::spawn(async move {
+            let now = Instant::now();
+            let compressed = select! {
+                compressed = compressing => compressed,
+                _ = &mut cancel_rx => {
+                    log::debug!("Upload canceled");
+                    return;
+                }
+            };
 
-                // When the receiver is dropped, uploading was canceled
-                if sender.send((details, cluster, tx.clone())).is_err() {
-                    break;
+            // The copy is only as good as the read of the original it was made from
+            let mut state = state.write().await;
+            let handle = state.handle();
+            let err = match compressed {
+                Ok(Ok(compressed)) => match unchanged(&upload.path, upload.fingerprint) {
+                    Ok(()) => {
+                        log::info!(
+                            "Compressed {} bytes in {:.2}s",
+                            compressed.original_size,
+                            now.elapsed().as_secs_f64()
+                        );
+
+                        let staged = Staged::Compressed(compressed);
+                        state.upload_file(upload, cancel_rx, Some(staged));
+                        return;
+                    }
+                    Err(err) => err,
+                },
+                Ok(Err(err)) => {
+                    log::error!("failed to compress file: {}", err);
+                    unchanged(&upload.path, upload.fingerprint)
+                        .err()
+                        .unwrap_or(UploadError::Io(err))
                 }
-            }
+                Err(_) => UploadError::JoinError,
+            };
 
-            Ok(())
+            handle
+                .emit_all("upload_error", &err)
+                .expect("failed to emit upload_error");
+
+            state.upload_failed(&err);
         });
+    }
+
+    // Copies the file into the staging directory and uploads the copy, which nothing else writes to
+    fn upload_snapshot(&mut self, upload: Upload, mut cancel_rx: oneshot::Receiver<()>) {
+        let source = upload.path.clone();
+        let target = staging_target();
+        let copying = task::spawn_blocking(move || snapshot::take(&source, target));
 
-        let state = unsafe { &*self.rt.this };
+        let state = self.shared();
         tokio::spawn(async move {
             let now = Instant::now();
-
-            let futures = future::try_join4(
-                Flatten::flatten(futures),
-                uploaders,
-                Flatten::flatten(preuploads),
-                Flatten::flatten(crc_handle),
-            );
-            let futures = select! {
-                futures = futures => futures,
-                _ = cancel_rx => {
+            let snapshot = select! {
+                snapshot = copying => snapshot,
+                _ = &mut cancel_rx => {
                     log::debug!("Upload canceled");
                     return;
                 }
             };
 
+            // A write to the original while it was copied would leave a mix in the copy
             let mut state = state.write().await;
-            let handle = unsafe { state.rt.app_handle.as_ref().unwrap() };
-            let (ids, crc) = match futures {
-                Ok((ids, _, _, crc)) => (ids, crc),
-                Err(err) => {
-                    log::error!("Failed to upload a file, reason: {}", err);
+            let handle = state.handle();
+            let err = match snapshot {
+                Ok(Ok(snapshot)) => match unchanged(&upload.path, upload.fingerprint) {
+                    Ok(()) => {
+                        log::info!(
+                            "Took a snapshot of {} in {:.2}s",
+                            upload.path,
+                            now.elapsed().as_secs_f64()
+                        );
+
+                        let staged = Staged::Snapshot(snapshot);
+                        state.upload_file(upload, cancel_rx, Some(staged));
+                        return;
+                    }
+                    Err(err) => err,
+                },
+                Ok(Err(err)) => {
+                    log::error!("failed to take a snapshot: {}", err);
+                    UploadError::Io(err)
+                }
+                Err(_) => UploadError::JoinError,
+            };
 
-                    handle
-                        .emit_all("upload_error", &err)
-                        .expect("failed to emit upload_error");
+            handle
+                .emit_all("upload_error", &err)
+                .expect("failed to emit upload_error");
 
-                    state.rt.upload_queue.clear();
-                    state.rt.job = Job::Idle;
+            state.upload_failed(&err);
+        });
+    }
 
-                    return;
-                }
-            };
+    // Stops the queue, the upload stopped by its source changing is kept so it can be retried
+    fn upload_failed(&mut self, err: &UploadError) {
+        if matches!(err, UploadError::SourceChanged(_)) {
+            self.rt.changed = self.rt.upload.take();
+        }
 
-            let took = now.elapsed().as_secs_f64();
-            log::info!(
-                "Uploaded {} cluster(s) in {:.2}s; crc32: {:x}",
-                clusters,
-                took,
-                crc,
-            );
+        self.rt.upload_queue.clear();
+        self.forget_queue();
+        self.rt.job = Job::Idle;
+    }
 
-            let timestamp = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .expect("failed to get timestamp")
-                .as_secs();
+    fn upload_secure(
+        &mut self,
+        source: Source,
+        cancel_rx: oneshot::Receiver<()>,
+        vault: Option<(Vault, [u8; 32])>,
+        algorithm: Algorithm,
+        thread: Option<String>,
+    ) {
+        let handle = self.handle();
+        let (crc_tx, crc_rx) = mpsc::channel::<(u64, Hasher)>(4);
+
+        // Only the KDF parameters of a vault file are stored, never its key
+        let (vault, key) = match vault {
+            Some((vault, key)) => (Some(vault), key),
+            None => (None, self.aes_key()),
+        };
 
-            let file = File {
-                id: state.next_id(),
-                path: file,
-                name: None,
-                size: file_size,
-                download_ids: ids,
-                created_at: timestamp,
-                updated_at: timestamp,
-                crc32: crc,
-                encryption_key: Some(key),
-            };
+        // The id is authenticated along with the data, so it has to be known upfront
+        let id = self.next_id();
+        let cipher = Cipher::new(algorithm, &key);
+        let path = source.read_path().to_owned();
+        let slicing = self.slicing();
+        let (tracker, tx) = Tracker::start(
+            handle.clone(),
+            Kind::Upload,
+            id,
+            source_size(&path),
+            Phase::Preupload,
+        );
+        let reader = SecureReader::new(&path, cipher, Format::V2, id, slicing, tx, crc_tx);
+        let mut reader = match reader {
+            Ok(reader) => reader,
+            Err(err) => {
+                log::error!("failed to open file: {}", path);
+                handle
+                    .emit_all("upload_error", &UploadError::Io(err))
+                    .expect("failed to emit upload_error");
 
-            handle
-                .emit_all("file_uploaded", &file)
-                .expect("failed to emit file_uploaded");
+                self.rt.job = Job::Idle;
+                self.rt.upload_queue.clear();
+                self.forget_queue();
+                return;
+            }
+        };
 
-            state.files.push(file);
+        let file = File {
+            encryption_key: vault.is_none().then_some(key),
+            vault,
+            format: Format::V2,
+            algorithm,
+            ..stored(id, &source, reader.file_size, slicing)
+        };
+        let opened = Opened {
+            file,
+            size: reader.file_size,
+            count: reader.clusters as usize,
+            clusters: iter::from_fn(move || reader.next_cluster()),
+            source,
+            seal: Some(Seal { algorithm, key, id }),
+            tracker,
+            crc_rx,
+        };
 
-            state.write();
-            state.upload();
-        });
+        self.upload_opened(opened, thread, cancel_rx);
     }
 
-    fn upload_insecure(&mut self, file: String, cancel_rx: oneshot::Receiver<()>) {
-        let (tx, mut rx) = mpsc::channel::<usize>(10);
-        let handle = unsafe { self.rt.app_handle.as_ref().unwrap() };
-
-        tokio::spawn(async move {
-            let mut bytes = 0;
-            while let Some(read) = rx.recv().await {
-                bytes += read;
+    fn upload_insecure(
+        &mut self,
+        source: Source,
+        cancel_rx: oneshot::Receiver<()>,
+        thread: Option<String>,
+    ) {
+        let handle = self.handle();
+        let (crc_tx, crc_rx) = mpsc::channel::<(u64, Hasher)>(4);
+
+        // Known upfront like every other upload, so the progress can name the file
+        let id = self.next_id();
+        let path = source.read_path().to_owned();
+        let slicing = self.slicing();
+        let (tracker, tx) = Tracker::start(
+            handle.clone(),
+            Kind::Upload,
+            id,
+            source_size(&path),
+            Phase::Preupload,
+        );
+        let mut reader = match InsecureReader::new(&path, slicing, tx, crc_tx) {
+            Ok(reader) => reader,
+            Err(err) => {
+                log::error!("failed to open file: {}", path);
                 handle
-                    .emit_all("upload_progress", bytes)
-                    .expect("failed to emit upload_progress");
+                    .emit_all("upload_error", &UploadError::Io(err))
+                    .expect("failed to emit upload_error");
+
+                self.rt.job = Job::Idle;
+                self.rt.upload_queue.clear();
+                self.forget_queue();
+                return;
             }
-        });
+        };
+
+        let opened = Opened {
+            file: stored(id, &source, reader.file_size, slicing),
+            size: reader.file_size,
+            count: reader.clusters as usize,
+            clusters: iter::from_fn(move || reader.next_cluster()),
+            source,
+            seal: None,
+            tracker,
+            crc_rx,
+        };
+
+        self.upload_opened(opened, thread, cancel_rx);
+    }
+
+    // Everything past opening the file goes the same with or without encryption
+    fn upload_opened<I, C>(
+        &mut self,
+        opened: Opened<I>,
+        thread: Option<String>,
+        mut cancel_rx: oneshot::Receiver<()>,
+    ) where
+        I: Iterator<Item = C> + Send + 'static,
+        C: Cluster + Send + Sync + 'static,
+        C::Iter: Send + 'static,
+    {
+        let Opened {
+            file,
+            size: file_size,
+            count: clusters,
+            clusters: mut reader,
+            source,
+            seal,
+            tracker,
+            mut crc_rx,
+        } = opened;
+
+        let handle = self.handle();
+        let primary = match self.pool() {
+            Ok(primary) => primary,
+            Err(err) => {
+                log::error!("failed to resolve credentials: {}", err);
+                handle
+                    .emit_all("upload_error", &UploadError::from(err))
+                    .expect("failed to emit upload_error");
 
-        let (crc_tx, mut crc_rx) = mpsc::channel::<(u64, Hasher)>(4);
+                self.rt.job = Job::Idle;
+                self.rt.upload_queue.clear();
+                self.forget_queue();
+                return;
+            }
+        };
 
+        // Slices finish out of order, their checksums are combined in order once all are in
         let crc_handle = tokio::spawn(async move {
             let mut hashers = Vec::new();
             while let Some((idx, hasher)) = crc_rx.recv().await {
                 let idx = idx as usize;
                 if hashers.len() <= idx {
-                    hashers.resize(idx + 1, unsafe { std::mem::zeroed() });
+                    hashers.resize(idx + 1, Hasher::new());
                 }
 
                 hashers[idx] = hasher;
@@ -334,29 +638,13 @@ impl State {
             Ok(hasher.finalize())
         });
 
-        let mut reader = match InsecureReader::new(&file, tx, crc_tx) {
-            Ok(reader) => reader,
-            Err(err) => {
-                log::error!("failed to open file: {}", file);
-                handle
-                    .emit_all("upload_error", &UploadError::Io(err))
-                    .expect("failed to emit upload_error");
-
-                self.rt.job = Job::Idle;
-                self.rt.upload_queue.clear();
-                return;
-            }
-        };
-
-        let clusters = reader.clusters as usize;
-        let file_size = reader.file_size;
-
         // Channel ID, cluster index
         type Sender = (u64, usize);
-        // Upload details, current cluster, finish sender
-        type OneShot = (
+        // Upload details, current cluster, where it goes, finish sender
+        type OneShot<C> = (
             Vec<api::UploadDetailsInner>,
-            InsecureClusterR,
+            C,
+            Location,
             mpsc::Sender<Sender>,
         );
 
@@ -366,34 +654,73 @@ impl State {
         let mut receivers = Vec::with_capacity(clusters);
 
         for _ in 0..clusters {
-            let (sender, receiver) = oneshot::channel::<OneShot>();
+            let (sender, receiver) = oneshot::channel::<OneShot<C>>();
             senders.push(sender);
             receivers.push(receiver);
         }
 
-        let token = Arc::new(self.token.clone());
-        let channel = Arc::new(self.channel_id.clone());
+        // The parity and the mirrors always go through the primary pair, only data is threaded
+        let pool = Arc::new(threads::threaded(&primary, thread.as_deref()));
+        let Location { auth, channel } = primary[0].clone();
+        let slicing = file.slicing;
+
+        // Futures are lazy, the parity and the mirrors are uploaded only once the data is stored
+        let path = source.read_path().to_owned();
+        let parity = self.erasure.map(|code| {
+            parity::upload(
+                auth.clone(),
+                channel.clone(),
+                code,
+                path.clone(),
+                file_size,
+                slicing,
+                seal,
+            )
+        });
+        let mirrors = mirrors::upload(
+            Arc::new(auth.bot()),
+            self.mirrors.clone(),
+            path,
+            slicing,
+            seal,
+        );
+        let extras = async move {
+            let erasure = match parity {
+                Some(parity) => Some(parity.await?),
+                None => None,
+            };
 
-        let token2 = token.clone();
-        let channel2 = channel.clone();
+            Ok::<_, UploadError>((erasure, mirrors.await?))
+        };
 
+        let upload_threads = concurrency::UPLOAD.max();
         let stream = stream::iter(receivers);
+        let tracker2 = tracker.clone();
+        let source = Arc::new(source);
+        let source2 = source.clone();
         let uploaders = stream
             .map(Ok)
-            .try_for_each_concurrent(UPLOAD_THREADS, move |rx| {
-                let token2 = Arc::clone(&token2);
-                let channel2 = Arc::clone(&channel2);
-
+            .try_for_each_concurrent(upload_threads, move |rx| {
+                let tracker = tracker2.clone();
+                let source = source2.clone();
                 async move {
-                    let (details, cluster, sender) = match rx.await {
+                    // The preuploads stopped early and dropped the sender, they report why
+                    let (details, cluster, location, sender) = match rx.await {
                         Ok(result) => result,
-                        Err(_) => return Ok(()), // TODO: comment why returning Ok(()) is actually ok
+                        Err(_) => return Ok(()),
                     };
 
-                    let index = cluster.cluster_index as usize;
+                    let permit = concurrency::UPLOAD.acquire().await;
+                    let size = cluster.size();
+                    tracker.phase(Phase::Uploading);
+
+                    let index = cluster.index() as usize;
                     api::upload(&details, cluster).await?;
 
-                    let id = api::finalize(&token2, &channel2, &details).await?;
+                    // Nothing read from a file that changed meanwhile is kept
+                    source.unchanged()?;
+                    let id = api::finalize(&location.auth, &location.channel, &details).await?;
+                    permit.done(size);
                     sender
                         .send((id, index))
                         .await
@@ -411,11 +738,17 @@ impl State {
             Ok::<_, UploadError>(ids)
         });
 
-        let token = Arc::clone(&token);
-        let channel = Arc::clone(&channel);
+        let pool2 = pool.clone();
         let preuploads = tokio::spawn(async move {
-            while let Some(cluster) = reader.next_cluster() {
-                let details = api::preupload(&token, &channel, cluster.get_size()).await;
+            for cluster in reader.by_ref() {
+                let location = stripes::assign(&pool2, cluster.index() as usize).clone();
+                let details = api::preupload(
+                    &location.auth,
+                    &location.channel,
+                    cluster.size(),
+                    slicing.slice_size,
+                )
+                .await;
                 let details = match details {
                     Ok(details) => details,
                     Err(err) => return Err(err),
@@ -424,7 +757,10 @@ impl State {
                 let sender = senders.pop().unwrap();
 
                 // When the receiver is dropped, uploading was canceled
-                if sender.send((details, cluster, tx.clone())).is_err() {
+                if sender
+                    .send((details, cluster, location, tx.clone()))
+                    .is_err()
+                {
                     break;
                 }
             }
@@ -432,7 +768,7 @@ impl State {
             Ok(())
         });
 
-        let state = unsafe { &*self.rt.this };
+        let state = self.shared();
         tokio::spawn(async move {
             let now = Instant::now();
 
@@ -444,16 +780,36 @@ impl State {
             );
             let futures = select! {
                 futures = futures => futures,
-                _ = cancel_rx => {
+                _ = &mut cancel_rx => {
                     log::debug!("Upload canceled");
                     return;
                 }
             };
 
+            let futures = match futures {
+                Ok(result) => {
+                    tracker.phase(Phase::Finalizing);
+                    select! {
+                        extras = extras => extras.map(|extras| (result, extras)),
+                        _ = cancel_rx => {
+                            log::debug!("Upload canceled");
+                            return;
+                        }
+                    }
+                }
+                Err(err) => Err(err),
+            };
+
+            // The parity and the mirrors read the file again, and a file that shrank fails to read
+            let futures = match futures {
+                Ok(result) => source.unchanged().map(|_| result),
+                Err(err) => Err(source.unchanged().err().unwrap_or(err)),
+            };
+
             let mut state = state.write().await;
-            let handle = unsafe { state.rt.app_handle.as_ref().unwrap() };
-            let (ids, crc) = match futures {
-                Ok((ids, _, _, crc)) => (ids, crc),
+            let handle = state.handle();
+            let (ids, crc, erasure, mirrors) = match futures {
+                Ok(((ids, _, _, crc), (erasure, mirrors))) => (ids, crc, erasure, mirrors),
                 Err(err) => {
                     log::error!("Failed to upload a file, reason: {}", err);
 
@@ -461,9 +817,7 @@ impl State {
                         .emit_all("upload_error", &err)
                         .expect("failed to emit upload_error");
 
-                    state.rt.upload_queue.clear();
-                    state.rt.job = Job::Idle;
-
+                    state.upload_failed(&err);
                     return;
                 }
             };
@@ -482,15 +836,14 @@ impl State {
                 .as_secs();
 
             let file = File {
-                id: state.next_id(),
-                path: file,
-                name: None,
-                size: file_size,
                 download_ids: ids,
                 created_at: timestamp,
                 updated_at: timestamp,
                 crc32: crc,
-                encryption_key: None,
+                erasure,
+                mirrors,
+                locations: stripes::record(&pool, &primary[0].channel, clusters),
+                ..file
             };
 
             handle
@@ -504,3 +857,97 @@ impl State {
         });
     }
 }
+
+// A file opened for upload, along with what the upload needs to store it
+struct Opened<I> {
+    file: File, // as it's stored, once the ids and the checksum are in
+    size: u64,  // bytes read from the source
+    count: usize,
+    clusters: I,
+    source: Source,
+    seal: Option<Seal>,
+    tracker: Tracker,
+    crc_rx: mpsc::Receiver<(u64, Hasher)>,
+}
+
+// An unencrypted file as it's stored, what is only known once it's up is left empty
+fn stored(id: u32, source: &Source, size: u64, slicing: Slicing) -> File {
+    let compressed = source.compressed();
+
+    File {
+        id,
+        path: source.path.clone(),
+        name: None,
+        size: compressed.map_or(size, |c| c.original_size),
+        download_ids: Vec::new(),
+        created_at: 0,
+        updated_at: 0,
+        crc32: 0,
+        encryption_key: None,
+        vault: None,
+        format: Format::default(),
+        algorithm: Algorithm::default(),
+        compression: compressed.map(|compressed| Compression {
+            level: compressed.level,
+            size,
+        }),
+        erasure: None,
+        mirrors: Vec::new(),
+        locations: Vec::new(),
+        slicing,
+    }
+}
+
+// Only what is read is counted, so a compressed file is measured by its staged copy
+fn source_size(path: &str) -> u64 {
+    fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+// The file being uploaded, as it was when its upload started
+struct Source {
+    path: String, // of the original, the one recorded
+    fingerprint: Fingerprint,
+    staged: Option<Staged>,
+}
+
+// A private copy read instead of the original, changes to the original can't reach it
+enum Staged {
+    Compressed(Compressed),
+    Snapshot(Snapshot),
+}
+
+impl Source {
+    // What the clusters, the parity and the mirrors are read from
+    fn read_path(&self) -> &str {
+        match &self.staged {
+            Some(Staged::Compressed(compressed)) => &compressed.path,
+            Some(Staged::Snapshot(snapshot)) => &snapshot.path,
+            None => &self.path,
+        }
+    }
+
+    fn compressed(&self) -> Option<&Compressed> {
+        match &self.staged {
+            Some(Staged::Compressed(compressed)) => Some(compressed),
+            _ => None,
+        }
+    }
+
+    // A staged copy was checked once it was made
+    fn unchanged(&self) -> Result<(), UploadError> {
+        match self.staged {
+            Some(_) => Ok(()),
+            None => unchanged(&self.path, self.fingerprint),
+        }
+    }
+}
+
+fn unchanged(path: &str, fingerprint: Fingerprint) -> Result<(), UploadError> {
+    match Fingerprint::read(path) {
+        Ok(current) if current == fingerprint => Ok(()),
+        _ => {
+            log::warn!("{} changed while it was uploaded", path);
+            Err(UploadError::SourceChanged(path.to_owned()))
+        }
+    }
+}
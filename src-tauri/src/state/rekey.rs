@@ -0,0 +1,468 @@
+use super::errors::{DownloadError, RekeyError};
+use super::model::{Job, Mirror, State};
+use super::stripes::{self, Location};
+use crate::api;
+use crate::concurrency;
+use crate::io::rekey::Rekeyer;
+use crate::io::{Cipher, Format, Framing, Slicing};
+use crate::progress::{Kind, Phase, Tracker};
+use crate::utils::Flatten;
+
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use std::{cmp, mem};
+
+use crc32fast::Hasher;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use reqwest::Body;
+use tauri::Manager;
+use tokio::select;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+impl State {
+    pub fn extend_rekey_queue(&mut self, files: Vec<u32>, delete_old: bool) {
+        if !self.rt.job.is_rekey_extendable() {
+            log::warn!("Not rekeying, ignoring files");
+            return;
+        }
+
+        let handle = self.handle();
+        let mut queue = Vec::with_capacity(files.len());
+        for id in files {
+            match self.files.iter().find(|file| file.id == id) {
+                Some(file) if file.erasure.is_some() => {
+                    log::warn!("File {} has parity, skipping", id);
+                    handle
+                        .emit_all("rekey_error", &RekeyError::HasParity(id))
+                        .expect("failed to emit rekey_error");
+                }
+                Some(file) if file.encryption_key.is_some() => queue.push(id),
+                Some(_) => log::warn!("File {} has no stored key, skipping", id),
+                None => log::warn!("File not found: {}", id),
+            }
+        }
+
+        if queue.is_empty() {
+            log::warn!("No files to rekey");
+            return;
+        }
+
+        handle
+            .emit_all("extend_rekey_queue", &queue)
+            .expect("failed to emit extend_rekey_queue");
+
+        log::info!("Extending the queue with {} files", queue.len());
+        self.rt
+            .rekey_queue
+            .extend(queue.into_iter().map(|id| (id, delete_old)));
+
+        if self.rt.job == Job::Idle {
+            log::info!("Starting rekeying {} files", self.rt.rekey_queue.len());
+            self.rekey();
+        }
+    }
+
+    fn rekey(&mut self) {
+        let (id, delete_old) = match self.rt.rekey_queue.pop_front() {
+            Some(entry) => entry,
+            None => {
+                log::info!("No more files to rekey, stopping");
+
+                self.rt.job = Job::Idle;
+                return;
+            }
+        };
+
+        // The parity is computed over the whole stored stream, which is never at hand here
+        let (
+            size,
+            slicing,
+            old_ids,
+            locations,
+            mirrors,
+            old_key,
+            old_format,
+            algorithm,
+            expected_crc,
+        ) = match self.files.iter().find(|file| file.id == id) {
+            Some(file) if file.encryption_key.is_some() && file.erasure.is_none() => (
+                file.stored_size(),
+                file.slicing,
+                file.download_ids.clone(),
+                self.locations(file),
+                file.mirrors
+                    .iter()
+                    .map(|mirror| mirror.channel_id.clone())
+                    .collect::<Vec<_>>(),
+                file.encryption_key.unwrap(),
+                file.format,
+                file.algorithm,
+                file.crc32,
+            ),
+            _ => {
+                log::warn!(
+                    "File {} is gone, has no stored key or has parity, skipping",
+                    id
+                );
+                self.rekey();
+                return;
+            }
+        };
+
+        let handle = self.handle();
+        // Clusters are rewritten where they were, mirrors live with the primary pair
+        let credentials = locations.and_then(|locations| Ok((locations, self.pool()?.remove(0))));
+        let (locations, primary) = match credentials {
+            Ok(credentials) => credentials,
+            Err(err) => {
+                log::error!("failed to resolve credentials: {}", err);
+                handle
+                    .emit_all("rekey_error", &RekeyError::Upload(err.into()))
+                    .expect("failed to emit rekey_error");
+
+                self.rt.rekey_queue.clear();
+                self.rt.job = Job::Idle;
+                return;
+            }
+        };
+
+        log::info!("Rekeying file: {}", id);
+        let (cancel_tx, mut cancel_rx) = oneshot::channel::<()>();
+        self.rt.job = Job::Rekey { cancel_tx };
+
+        let (tracker, tx) = Tracker::start(
+            handle.clone(),
+            Kind::Rekey,
+            id,
+            size,
+            Phase::FetchingMetadata,
+        );
+
+        let (crc_tx, mut crc_rx) = mpsc::channel::<(u64, Hasher)>(4);
+        let crc_handle = tokio::spawn(async move {
+            let mut hashers = Vec::new();
+            while let Some((idx, hasher)) = crc_rx.recv().await {
+                let idx = idx as usize;
+                if hashers.len() <= idx {
+                    hashers.resize(idx + 1, Hasher::new());
+                }
+
+                hashers[idx] = hasher;
+            }
+
+            let mut hasher = Hasher::new();
+            for other in hashers {
+                hasher.combine(&other);
+            }
+
+            Ok::<_, RekeyError>(hasher.finalize())
+        });
+
+        let new_key = self.aes_key();
+        // Rekeyed files are always written in the current format
+        let rekeyer = Rekeyer::new(
+            Cipher::new(algorithm, &old_key),
+            Framing::new(old_format, id, size, slicing),
+            Cipher::new(algorithm, &new_key),
+            Framing::new(Format::V2, id, size, slicing),
+            tx,
+            crc_tx,
+        );
+        let final_size = slicing.encrypted_size(size);
+
+        let mirrors = mirrors
+            .into_iter()
+            .map(|channel_id| Location {
+                auth: primary.auth.clone(),
+                channel: Arc::new(channel_id),
+            })
+            .collect::<Vec<_>>();
+        let do_checksum = self.do_checksum;
+        let locations = Arc::new(locations);
+        let new_ids = Arc::new(Mutex::new(vec![0; old_ids.len()]));
+
+        let locations2 = locations.clone();
+        let new_ids2 = new_ids.clone();
+        let ids = old_ids.clone();
+        let tracker2 = tracker.clone();
+
+        let rekeyers = tokio::spawn(async move {
+            let attachments = stripes::fetch_attachments(&locations2, &ids).await?;
+
+            // Dropping the rekeyer closes the progress and crc channels once every slice is done
+            let rekeyer = &rekeyer;
+            stream::iter(attachments.into_iter().enumerate())
+                .map(Ok)
+                .try_for_each_concurrent(concurrency::UPLOAD.max(), |(cluster, urls)| {
+                    let Location { auth, channel } = locations2[cluster].clone();
+                    let new_ids = new_ids2.clone();
+                    let tracker = tracker2.clone();
+
+                    async move {
+                        let permit = concurrency::UPLOAD.acquire().await;
+                        tracker.phase(Phase::Uploading);
+                        let full_size = slicing.cluster_size();
+                        let cluster_size =
+                            cmp::min(full_size, final_size - cluster as u64 * full_size);
+
+                        let details =
+                            api::preupload(&auth, &channel, cluster_size, slicing.slice_size)
+                                .await?;
+                        if details.len() != urls.len() {
+                            log::error!(
+                                "Cluster {} has {} attachments, expected {}",
+                                cluster,
+                                urls.len(),
+                                details.len()
+                            );
+                            return Err(RekeyError::Download(DownloadError::NotFoundRemote));
+                        }
+
+                        let bodies = urls.into_iter().enumerate().map(|(slice, url)| {
+                            Body::wrap_stream(rekeyer.slice(url, cluster as u64, slice as u64))
+                        });
+
+                        api::upload_bodies(&details, bodies).await?;
+                        let id = api::finalize(&auth, &channel, &details).await?;
+                        new_ids.lock().expect("failed to lock ids")[cluster] = id;
+                        permit.done(cluster_size);
+
+                        Ok(())
+                    }
+                })
+                .await
+        });
+
+        let state = self.shared();
+        tokio::spawn(async move {
+            let now = Instant::now();
+
+            let rekeyed = unless_canceled(rekeyers, &mut cancel_rx, || {
+                log::debug!("Rekey canceled");
+                delete_messages(at(&locations, take_ids(&new_ids)));
+            });
+            let Some(rekeyed) = rekeyed.await else {
+                return;
+            };
+            tracker.phase(Phase::Verifying);
+
+            // The crc channel closes once the rekeyer is dropped along with its task
+            let futures = rekeyed.and(Flatten::flatten(crc_handle).await);
+
+            let result = match futures {
+                Ok(crc) if do_checksum && crc != expected_crc => {
+                    Err(RekeyError::ChecksumMismatch(expected_crc, crc))
+                }
+                Ok(_) => Ok(()),
+                Err(err) => Err(err),
+            };
+
+            // The old mirrors are sealed with the old key, so they are copied from the new clusters
+            let posted = Mutex::new(Vec::new());
+            let copying = copy_mirrors(&locations, &new_ids, &mirrors, slicing, &posted);
+            let result = match result {
+                Ok(()) => select! {
+                    result = copying => result,
+                    _ = &mut cancel_rx => {
+                        log::debug!("Rekey canceled");
+                        delete_messages(at(&locations, take_ids(&new_ids)));
+                        delete_messages(take_posted(&posted));
+                        return;
+                    }
+                },
+                Err(err) => Err(err),
+            };
+
+            let mut state = state.write().await;
+            let handle = state.handle();
+
+            let new_ids = take_ids(&new_ids);
+            let new_mirrors = match result {
+                Ok(new_mirrors) => new_mirrors,
+                Err(err) => {
+                    log::error!("Failed to rekey file, reason: {}", err);
+                    handle
+                        .emit_all("rekey_error", &err)
+                        .expect("failed to emit rekey_error");
+
+                    delete_messages(at(&locations, new_ids));
+                    delete_messages(take_posted(&posted));
+                    state.rt.rekey_queue.clear();
+                    state.rt.job = Job::Idle;
+                    return;
+                }
+            };
+
+            // Swap the messages and the key together, the file may have been deleted meanwhile
+            let file = match state
+                .files
+                .iter_mut()
+                .find(|file| file.id == id && file.download_ids == old_ids)
+            {
+                Some(file) => file,
+                None => {
+                    log::warn!("File {} changed while rekeying, discarding", id);
+                    delete_messages(at(&locations, new_ids));
+                    delete_messages(take_posted(&posted));
+                    state.rekey();
+                    return;
+                }
+            };
+
+            let stale_mirrors = mem::replace(&mut file.mirrors, new_mirrors);
+            file.download_ids = new_ids;
+            file.encryption_key = Some(new_key);
+            file.format = Format::V2;
+            file.updated_at = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("failed to get timestamp")
+                .as_secs();
+
+            log::info!(
+                "Rekeyed {} cluster(s) in {:.2}s",
+                old_ids.len(),
+                now.elapsed().as_secs_f64()
+            );
+
+            handle
+                .emit_all("file_rekeyed", &*file)
+                .expect("failed to emit file_rekeyed");
+
+            state.write();
+            if delete_old {
+                let mut messages = at(&locations, old_ids);
+                for mirror in stale_mirrors {
+                    let location = Location {
+                        auth: Arc::new(primary.auth.bot()),
+                        channel: Arc::new(mirror.channel_id),
+                    };
+
+                    messages.extend(
+                        mirror
+                            .download_ids
+                            .into_iter()
+                            .map(|id| (location.clone(), id)),
+                    );
+                }
+
+                delete_messages(messages);
+            }
+
+            state.rekey();
+        });
+    }
+}
+
+// Waits for the task unless canceled first. A canceled task is aborted and awaited before
+// cleaning up, so nothing it posts can be left behind once its ids are taken
+pub async fn unless_canceled<T, E: Default>(
+    mut task: JoinHandle<Result<T, E>>,
+    cancel_rx: &mut oneshot::Receiver<()>,
+    cleanup: impl FnOnce(),
+) -> Option<Result<T, E>> {
+    let abort = task.abort_handle();
+    select! {
+        result = &mut task => Some(result.unwrap_or_else(|_| Err(E::default()))),
+        _ = cancel_rx => {
+            abort.abort();
+            let _ = task.await;
+            cleanup();
+            None
+        }
+    }
+}
+
+pub fn take_ids(ids: &Mutex<Vec<u64>>) -> Vec<u64> {
+    mem::take(&mut *ids.lock().expect("failed to lock ids"))
+}
+
+fn take_posted(posted: &Mutex<Vec<(Location, u64)>>) -> Vec<(Location, u64)> {
+    mem::take(&mut *posted.lock().expect("failed to lock ids"))
+}
+
+// Every copy holds the same bytes, so the rekeyed clusters are copied to each mirror as they are
+async fn copy_mirrors(
+    sources: &[Location],
+    ids: &Mutex<Vec<u64>>,
+    mirrors: &[Location],
+    slicing: Slicing,
+    posted: &Mutex<Vec<(Location, u64)>>,
+) -> Result<Vec<Mirror>, RekeyError> {
+    let ids = ids.lock().expect("failed to lock ids").clone();
+    let mut copied = Vec::with_capacity(mirrors.len());
+    for mirror in mirrors {
+        log::info!("Mirroring rekeyed file to channel: {}", mirror.channel);
+        let attachments = stripes::fetch_attachments(sources, &ids).await?;
+        let download_ids = Mutex::new(vec![0; ids.len()]);
+
+        stream::iter(attachments.into_iter().enumerate())
+            .map(Ok)
+            .try_for_each_concurrent(concurrency::UPLOAD.max(), |(cluster, urls)| {
+                let Location { auth, channel } = mirror.clone();
+                let download_ids = &download_ids;
+
+                async move {
+                    let permit = concurrency::UPLOAD.acquire().await;
+                    let mut responses = Vec::with_capacity(urls.len());
+                    for url in urls {
+                        responses.push(api::download(url).await?);
+                    }
+
+                    let sizes = responses
+                        .iter()
+                        .map(|response| response.content_length())
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or(RekeyError::Download(DownloadError::NotFoundRemote))?;
+                    let cluster_size = sizes.iter().sum::<u64>();
+
+                    let details =
+                        api::preupload(&auth, &channel, cluster_size, slicing.slice_size).await?;
+                    if details.len() != responses.len() {
+                        log::error!(
+                            "Cluster {} has {} attachments, expected {}",
+                            cluster,
+                            responses.len(),
+                            details.len()
+                        );
+                        return Err(RekeyError::Download(DownloadError::NotFoundRemote));
+                    }
+
+                    api::upload_bodies(&details, responses.into_iter().map(Body::from)).await?;
+                    let id = api::finalize(&auth, &channel, &details).await?;
+                    download_ids.lock().expect("failed to lock ids")[cluster] = id;
+                    posted
+                        .lock()
+                        .expect("failed to lock ids")
+                        .push((mirror.clone(), id));
+                    permit.done(cluster_size);
+
+                    Ok(())
+                }
+            })
+            .await?;
+
+        copied.push(Mirror {
+            channel_id: mirror.channel.to_string(),
+            download_ids: download_ids.into_inner().expect("failed to lock ids"),
+        });
+    }
+
+    Ok(copied)
+}
+
+// Pairs every message id with the location of its cluster
+pub fn at(locations: &[Location], ids: Vec<u64>) -> Vec<(Location, u64)> {
+    locations.iter().cloned().zip(ids).collect()
+}
+
+// Best effort, a message that could not be deleted only wastes space
+pub fn delete_messages(messages: Vec<(Location, u64)>) {
+    tokio::spawn(async move {
+        for (Location { auth, channel }, id) in messages.into_iter().filter(|(_, id)| *id != 0) {
+            if let Err(err) = api::delete_message(&auth, &channel, id).await {
+                log::error!("failed to delete message {}: {}", id, err);
+            }
+        }
+    });
+}
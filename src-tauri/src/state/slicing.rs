@@ -0,0 +1,51 @@
+use super::model::State;
+use crate::api::{self, Auth};
+use crate::io::Slicing;
+
+use std::sync::Arc;
+
+impl State {
+    // How new uploads are cut, the stripes and mirrors may live in guilds without the boost
+    pub fn slicing(&self) -> Slicing {
+        if let Some(slicing) = self.slicing {
+            return slicing;
+        }
+
+        match &self.rt.premium_tier {
+            Some((guild, tier))
+                if *guild == self.guild_id
+                    && self.stripes.is_empty()
+                    && self.mirrors.is_empty() =>
+            {
+                Slicing::for_tier(*tier)
+            }
+            _ => Slicing::default(),
+        }
+    }
+
+    // The boost level of the guild is only looked up once it matters
+    pub fn needs_premium_tier(&self) -> bool {
+        self.slicing.is_none()
+            && self.stripes.is_empty()
+            && self.mirrors.is_empty()
+            && !matches!(&self.rt.premium_tier, Some((guild, _)) if *guild == self.guild_id)
+    }
+}
+
+// Files stay within the limit of an unboosted guild when the lookup fails
+pub async fn premium_tier(auth: Auth, guild: &str) -> u8 {
+    match api::fetch_guild(&Arc::new(auth), guild).await {
+        Ok(guild) => {
+            log::info!(
+                "Guild {} has premium tier {}",
+                guild.name,
+                guild.premium_tier
+            );
+            guild.premium_tier
+        }
+        Err(err) => {
+            log::warn!("Failed to look up the premium tier: {}", err);
+            0
+        }
+    }
+}
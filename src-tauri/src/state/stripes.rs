@@ -0,0 +1,103 @@
+use super::errors::{DownloadError, InvalidWebhook};
+use super::model::{File, State};
+use crate::api::{self, Auth};
+
+use std::sync::Arc;
+
+// Where a cluster is stored and how to read and write there
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub auth: Arc<Auth>,
+    pub channel: Arc<String>,
+}
+
+impl State {
+    // Every pair clusters are spread across, the primary pair first
+    pub fn pool(&self) -> Result<Vec<Location>, InvalidWebhook> {
+        let primary = Location {
+            auth: Arc::new(self.auth()?),
+            channel: Arc::new(self.channel_id.clone()),
+        };
+
+        let stripes = self.stripes.iter().map(|stripe| {
+            Ok(Location {
+                auth: Arc::new(self.stripe_auth(stripe)?),
+                channel: Arc::new(stripe.channel_id.clone()),
+            })
+        });
+
+        [Ok(primary)].into_iter().chain(stripes).collect()
+    }
+
+    // Location of every cluster of a file, files that are not striped live in the primary channel
+    pub fn locations(&self, file: &File) -> Result<Vec<Location>, InvalidWebhook> {
+        let pool = self.pool()?;
+        if file.locations.is_empty() {
+            return Ok(vec![pool[0].clone(); file.download_ids.len()]);
+        }
+
+        // Threads and stripes removed from the settings are read with the primary credentials,
+        // through the bot since a webhook can't touch another channel
+        let locations = file
+            .locations
+            .iter()
+            .map(|channel| {
+                let auth = pool
+                    .iter()
+                    .find(|location| *location.channel == *channel)
+                    .map_or_else(
+                        || Arc::new(pool[0].auth.bot()),
+                        |location| location.auth.clone(),
+                    );
+
+                Location {
+                    auth,
+                    channel: Arc::new(channel.clone()),
+                }
+            })
+            .collect();
+
+        Ok(locations)
+    }
+}
+
+// Clusters are handed out round-robin, so every pair gets its own share of the rate limit
+pub fn assign(pool: &[Location], cluster: usize) -> &Location {
+    &pool[cluster % pool.len()]
+}
+
+// Channels are only recorded when some cluster is not in the primary channel
+pub fn record(pool: &[Location], primary: &str, clusters: usize) -> Vec<String> {
+    if pool.len() < 2 && *pool[0].channel == primary {
+        return Vec::new();
+    }
+
+    (0..clusters)
+        .map(|cluster| assign(pool, cluster).channel.to_string())
+        .collect()
+}
+
+// Fetches the attachment urls of every message, asking each channel only for its own messages
+pub async fn fetch_attachments(
+    locations: &[Location],
+    ids: &[u64],
+) -> Result<Vec<Vec<String>>, DownloadError> {
+    let mut attachments = vec![Vec::new(); ids.len()];
+    let mut pending = (0..ids.len()).collect::<Vec<_>>();
+
+    while let Some(&first) = pending.first() {
+        let Location { auth, channel } = &locations[first];
+        let (group, rest) = pending
+            .into_iter()
+            .partition::<Vec<_>, _>(|idx| locations[*idx].channel == *channel);
+        pending = rest;
+
+        let group_ids = group.iter().map(|idx| ids[*idx]).collect::<Vec<_>>();
+        let fetched = api::fetch_attachments(auth, channel, &group_ids).await?;
+        for (idx, urls) in group.into_iter().zip(fetched) {
+            attachments[idx] = urls;
+        }
+    }
+
+    Ok(attachments)
+}
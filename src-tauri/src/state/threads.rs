@@ -0,0 +1,92 @@
+use super::model::{State, Thread, ThreadMode};
+use super::stripes::Location;
+
+use std::path::{Component, Path};
+use std::sync::Arc;
+
+impl State {
+    // Name of the thread every file of a batch goes to
+    pub fn thread_names(&self, paths: &[String]) -> Vec<Option<String>> {
+        match self.thread_mode {
+            ThreadMode::Off => vec![None; paths.len()],
+            ThreadMode::Folder => top_folders(paths).into_iter().map(Some).collect(),
+            ThreadMode::Batch => {
+                let timestamp = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .expect("failed to get timestamp")
+                    .as_secs();
+
+                vec![Some(format!("batch-{}", timestamp)); paths.len()]
+            }
+        }
+    }
+
+    // Threads belong to the channel they were created in, so they are scoped to it
+    pub fn thread_id(&self, name: &str) -> Option<&str> {
+        self.threads
+            .iter()
+            .find(|thread| thread.channel_id == self.channel_id && thread.name == name)
+            .map(|thread| thread.id.as_str())
+    }
+
+    pub fn add_thread(&mut self, name: String, id: String) {
+        log::info!("Created thread {} for {}", id, name);
+        self.threads.push(Thread {
+            channel_id: self.channel_id.clone(),
+            name,
+            id,
+        });
+    }
+}
+
+// The pool with the primary channel swapped for the thread, a webhook only posts to its own
+// channel so the bot writes to the thread
+pub fn threaded(pool: &[Location], thread: Option<&str>) -> Vec<Location> {
+    let mut pool = pool.to_vec();
+    if let Some(thread) = thread {
+        pool[0] = Location {
+            auth: Arc::new(pool[0].auth.bot()),
+            channel: Arc::new(thread.to_owned()),
+        };
+    }
+
+    pool
+}
+
+// The first folder below the deepest folder all of the files share, files right in that
+// folder are named after it
+fn top_folders(paths: &[String]) -> Vec<String> {
+    let parents = paths
+        .iter()
+        .map(|path| Path::new(path).parent().unwrap_or(Path::new("")))
+        .collect::<Vec<_>>();
+
+    let mut common = parents
+        .first()
+        .map_or_else(Vec::new, |parent| parent.components().collect::<Vec<_>>());
+    for parent in parents.iter().skip(1) {
+        let shared = common
+            .iter()
+            .zip(parent.components())
+            .take_while(|(a, b)| **a == *b)
+            .count();
+        common.truncate(shared);
+    }
+
+    let fallback = common
+        .iter()
+        .rev()
+        .find_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .unwrap_or_else(|| "files".to_owned());
+
+    parents
+        .iter()
+        .map(|parent| match parent.components().nth(common.len()) {
+            Some(Component::Normal(name)) => name.to_string_lossy().into_owned(),
+            _ => fallback.clone(),
+        })
+        .collect()
+}
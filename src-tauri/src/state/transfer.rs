@@ -0,0 +1,307 @@
+use super::errors::TransferError;
+use super::model::{File, Job, State};
+use super::rekey::{at, delete_messages, take_ids, unless_canceled};
+use super::stripes::{self, Location};
+use crate::api;
+use crate::concurrency;
+use crate::progress::{Kind, Phase, Tracker};
+
+use std::iter;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use futures::stream::{self, StreamExt, TryStreamExt};
+use reqwest::Body;
+use tauri::Manager;
+use tokio::sync::oneshot;
+
+impl State {
+    pub fn extend_transfer_queue(&mut self, files: Vec<u32>, profile: String, delete_old: bool) {
+        if !self.rt.job.is_transfer_extendable() {
+            log::warn!("Not transferring, ignoring files");
+            return;
+        }
+
+        let target = match self.profiles.iter().find(|target| target.name == profile) {
+            Some(target) => target,
+            None => {
+                log::warn!("No inactive profile named {}", profile);
+                return;
+            }
+        };
+
+        // The id is part of the nonces, so the file keeps it in the target library
+        let mut queue = Vec::with_capacity(files.len());
+        for id in files {
+            match self.files.iter().find(|file| file.id == id) {
+                Some(_) if target.files.iter().any(|file| file.id == id) => {
+                    log::warn!("File {} is already in profile {}, skipping", id, profile)
+                }
+                Some(_) => queue.push(id),
+                None => log::warn!("File not found: {}", id),
+            }
+        }
+
+        if queue.is_empty() {
+            log::warn!("No files to transfer");
+            return;
+        }
+
+        let handle = self.handle();
+        handle
+            .emit_all("extend_transfer_queue", &queue)
+            .expect("failed to emit extend_transfer_queue");
+
+        log::info!("Extending the queue with {} files", queue.len());
+        self.rt.transfer_queue.extend(
+            queue
+                .into_iter()
+                .map(|id| (id, profile.clone(), delete_old)),
+        );
+
+        if self.rt.job == Job::Idle {
+            log::info!(
+                "Starting transferring {} files",
+                self.rt.transfer_queue.len()
+            );
+            self.transfer();
+        }
+    }
+
+    fn transfer(&mut self) {
+        let (id, profile, delete_old) = match self.rt.transfer_queue.pop_front() {
+            Some(entry) => entry,
+            None => {
+                log::info!("No more files to transfer, stopping");
+
+                self.rt.job = Job::Idle;
+                return;
+            }
+        };
+
+        let target_channel = self
+            .profiles
+            .iter()
+            .find(|target| target.name == profile)
+            .map(|target| target.channel_id.clone());
+
+        let (old_ids, parity_ids, slicing, sources, total) = match (
+            self.files.iter().find(|file| file.id == id),
+            target_channel.as_ref(),
+        ) {
+            (Some(file), Some(_)) => (
+                file.download_ids.clone(),
+                file.erasure
+                    .as_ref()
+                    .map_or_else(Vec::new, |erasure| erasure.download_ids.clone()),
+                file.slicing,
+                self.locations(file),
+                stored_bytes(file),
+            ),
+            _ => {
+                log::warn!("File {} or profile {} is gone, skipping", id, profile);
+                self.transfer();
+                return;
+            }
+        };
+
+        let handle = self.handle();
+        // The data clusters come from wherever they are striped, the parity from the primary pair
+        let credentials = sources.and_then(|sources| Ok((sources, self.pool()?.remove(0))));
+        let (mut sources, primary) = match credentials {
+            Ok(credentials) => credentials,
+            Err(err) => {
+                log::error!("failed to resolve credentials: {}", err);
+                handle
+                    .emit_all("transfer_error", &TransferError::Upload(err.into()))
+                    .expect("failed to emit transfer_error");
+
+                self.rt.transfer_queue.clear();
+                self.rt.job = Job::Idle;
+                return;
+            }
+        };
+
+        log::info!("Transferring file {} to profile {}", id, profile);
+        let (cancel_tx, mut cancel_rx) = oneshot::channel::<()>();
+        self.rt.job = Job::Transfer { cancel_tx };
+
+        let (tracker, tx) = Tracker::start(
+            handle.clone(),
+            Kind::Transfer,
+            id,
+            total,
+            Phase::FetchingMetadata,
+        );
+
+        sources.extend(iter::repeat_n(primary.clone(), parity_ids.len()));
+        let ids = [&old_ids[..], &parity_ids[..]].concat();
+        let old_messages = at(&sources, ids.clone());
+
+        // A webhook only posts to its own channel
+        let target = Location {
+            auth: Arc::new(primary.auth.bot()),
+            channel: Arc::new(target_channel.unwrap()),
+        };
+
+        let new_ids = Arc::new(Mutex::new(vec![0; ids.len()]));
+        let new_ids2 = new_ids.clone();
+        let target2 = target.clone();
+        let tracker2 = tracker.clone();
+
+        let copiers = tokio::spawn(async move {
+            let attachments = stripes::fetch_attachments(&sources, &ids).await?;
+            tracker2.phase(Phase::Uploading);
+
+            // The stored bytes are copied as they are, so keys and checksums stay valid
+            stream::iter(attachments.into_iter().enumerate())
+                .map(Ok)
+                .try_for_each_concurrent(concurrency::UPLOAD.max(), |(cluster, urls)| {
+                    let Location { auth, channel } = target2.clone();
+                    let new_ids = new_ids2.clone();
+                    let tx = tx.clone();
+
+                    async move {
+                        let permit = concurrency::UPLOAD.acquire().await;
+                        let mut responses = Vec::with_capacity(urls.len());
+                        for url in urls {
+                            responses.push(api::download(url).await?);
+                        }
+
+                        let sizes = responses
+                            .iter()
+                            .map(|response| response.content_length())
+                            .collect::<Option<Vec<_>>>()
+                            .ok_or(TransferError::Layout(cluster))?;
+                        let cluster_size = sizes.iter().sum::<u64>();
+
+                        // Attachments keep their size, the target guild has to allow it
+                        let details =
+                            api::preupload(&auth, &channel, cluster_size, slicing.slice_size)
+                                .await?;
+                        if details.len() != responses.len() {
+                            log::error!(
+                                "Cluster {} has {} attachments, expected {}",
+                                cluster,
+                                responses.len(),
+                                details.len()
+                            );
+                            return Err(TransferError::Layout(cluster));
+                        }
+
+                        api::upload_bodies(&details, responses.into_iter().map(Body::from)).await?;
+                        let id = api::finalize(&auth, &channel, &details).await?;
+                        new_ids.lock().expect("failed to lock ids")[cluster] = id;
+                        permit.done(cluster_size);
+                        tx.add(cluster_size as usize);
+
+                        Ok(())
+                    }
+                })
+                .await
+        });
+
+        let targets = vec![target; old_ids.len() + parity_ids.len()];
+        let state = self.shared();
+        tokio::spawn(async move {
+            let now = Instant::now();
+
+            let copied = unless_canceled(copiers, &mut cancel_rx, || {
+                log::debug!("Transfer canceled");
+                delete_messages(at(&targets, take_ids(&new_ids)));
+            });
+            let Some(result) = copied.await else {
+                return;
+            };
+            tracker.phase(Phase::Finalizing);
+
+            let mut state = state.write().await;
+            let handle = state.handle();
+
+            let mut new_ids = take_ids(&new_ids);
+            if let Err(err) = result {
+                log::error!("Failed to transfer file, reason: {}", err);
+                handle
+                    .emit_all("transfer_error", &err)
+                    .expect("failed to emit transfer_error");
+
+                delete_messages(at(&targets, new_ids));
+                state.rt.transfer_queue.clear();
+                state.rt.job = Job::Idle;
+                return;
+            }
+
+            // The file may have been deleted or rekeyed meanwhile
+            let idx = state
+                .files
+                .iter()
+                .position(|file| file.id == id && file.download_ids == old_ids);
+            let target = state
+                .profiles
+                .iter()
+                .position(|target| target.name == profile);
+
+            let (idx, target) = match (idx, target) {
+                (Some(idx), Some(target)) => (idx, target),
+                _ => {
+                    log::warn!("File {} changed while transferring, discarding", id);
+                    delete_messages(at(&targets, new_ids));
+                    state.transfer();
+                    return;
+                }
+            };
+
+            // Moving takes the file out of this library, copying leaves the original in place
+            let mut file = match delete_old {
+                true => state.files.remove(idx),
+                false => state.files[idx].clone(),
+            };
+
+            let new_parity_ids = new_ids.split_off(old_ids.len());
+            file.download_ids = new_ids;
+            file.locations = Vec::new();
+            if let Some(erasure) = file.erasure.as_mut() {
+                erasure.download_ids = new_parity_ids;
+            }
+
+            // Mirrors go along with a move, a copy stands on its own
+            if !delete_old {
+                file.mirrors = Vec::new();
+            }
+
+            file.updated_at = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("failed to get timestamp")
+                .as_secs();
+
+            log::info!(
+                "Transferred {} cluster(s) in {:.2}s",
+                targets.len(),
+                now.elapsed().as_secs_f64()
+            );
+
+            state.profiles[target].files.push(file);
+            handle
+                .emit_all("file_transferred", (id, delete_old))
+                .expect("failed to emit file_transferred");
+
+            state.write();
+            if delete_old {
+                delete_messages(old_messages);
+            }
+
+            state.transfer();
+        });
+    }
+}
+
+// What the messages hold, the copies are made as they are stored
+fn stored_bytes(file: &File) -> u64 {
+    let data = match file.encryption_key.is_some() || file.vault.is_some() {
+        true => file.slicing.encrypted_size(file.stored_size()),
+        false => file.stored_size(),
+    };
+
+    // The parity may carry some overhead of its own, it's only an estimate
+    data + file.erasure.as_ref().map_or(0, |erasure| erasure.size)
+}
@@ -1,14 +1,18 @@
 use super::errors::DownloadError;
-use super::model::{Job, State};
-use crate::api::{self, Take};
-use crate::io::consts::{BYTES_PER_SLICE, DOWNLOAD_THREADS, SLICE_SIZE};
+use super::mirrors::Sources;
+use super::model::{Job, State, Vault};
+use super::parity::Recovery;
+use crate::concurrency;
+use crate::io::compression;
 use crate::io::secure_writer::{SecureClusterW, SecureWriter};
 use crate::io::writer::{InsecureClusterW, InsecureWriter};
-use crate::utils::{download_target, Flatten};
+use crate::io::{Cipher, Framing};
+use crate::progress::{Kind, Phase, Tracker};
+use crate::utils::{download_target, remove_staged, staging_target, Flatten};
 
+use std::fs;
 use std::sync::Arc;
 use std::time::Instant;
-use std::{cmp, fs};
 
 use crc32fast::Hasher;
 use futures::future;
@@ -16,6 +20,7 @@ use futures::stream::{self, StreamExt, TryStreamExt};
 use tauri::Manager;
 use tokio::select;
 use tokio::sync::{mpsc, oneshot};
+use tokio::task;
 
 impl State {
     pub fn extend_download_queue(&mut self, files: Vec<u32>) {
@@ -26,11 +31,11 @@ impl State {
 
         let mut queue = Vec::with_capacity(files.len());
         let mut pairs = Vec::with_capacity(files.len());
-        let handle = unsafe { self.rt.app_handle.as_ref().unwrap() };
+        let handle = self.handle();
 
         for id in files {
             if let Some(file) = self.files.iter().find(|file| file.id == id) {
-                pairs.push((&file.path, file.size));
+                pairs.push((&file.path, file.stored_size()));
                 queue.push(file.id);
             }
         }
@@ -45,6 +50,10 @@ impl State {
             .expect("failed to emit extend_download_queue");
 
         log::info!("Extending the queue with {} files", queue.len());
+        let queued = queue
+            .iter()
+            .filter_map(|id| self.queued_download(*id))
+            .collect::<Vec<_>>();
         self.rt.download_queue.extend(queue);
 
         if self.rt.job == Job::Idle {
@@ -53,6 +62,9 @@ impl State {
                 self.rt.download_queue.len()
             );
             self.download();
+        } else {
+            self.queue.extend(queued);
+            self.write();
         }
     }
 
@@ -63,30 +75,117 @@ impl State {
                 log::info!("No more files to download, stopping");
 
                 self.rt.job = Job::Idle;
+                self.forget_queue();
                 return;
             }
         };
 
+        self.remember_downloads(Some(id));
+
         log::info!("Attempting to download file: {}", id);
         let (cancel_tx, cancel_rx) = oneshot::channel::<()>();
         self.rt.job = Job::Download { cancel_tx };
 
-        let encryption_key = self
-            .files
-            .iter()
-            .find(|file| file.id == id)
-            .map(|file| file.encryption_key)
-            .flatten();
+        let file = self.files.iter().find(|file| file.id == id);
+        let encryption_key = file.and_then(|file| file.encryption_key);
+        let vault = file.and_then(|file| file.vault);
 
-        if let Some(key) = encryption_key {
-            self.download_secure(id, cancel_rx, key);
-        } else {
-            self.download_insecure(id, cancel_rx);
+        match (encryption_key, vault) {
+            (Some(key), _) => self.download_secure(id, cancel_rx, key),
+            (None, Some(vault)) => self.download_vault(id, cancel_rx, vault),
+            (None, None) => self.download_insecure(id, cancel_rx),
         }
     }
 
+    // The key of a vault file is never stored, so ask for the passphrase before downloading
+    fn download_vault(&mut self, id: u32, mut cancel_rx: oneshot::Receiver<()>, vault: Vault) {
+        let handle = self.handle();
+        let (passphrase_tx, passphrase_rx) = oneshot::channel::<Option<String>>();
+        self.rt.passphrase_tx = Some(passphrase_tx);
+
+        log::info!("Waiting for the passphrase of file: {}", id);
+        handle
+            .emit_all("passphrase_required", id)
+            .expect("failed to emit passphrase_required");
+
+        let state = self.shared();
+        tokio::spawn(async move {
+            let passphrase = select! {
+                biased;
+                _ = &mut cancel_rx => {
+                    log::debug!("Download canceled");
+                    return;
+                }
+                passphrase = passphrase_rx => passphrase.ok().flatten(),
+            };
+
+            let passphrase = match passphrase {
+                Some(passphrase) => passphrase,
+                None => {
+                    log::info!("No passphrase provided, skipping file: {}", id);
+
+                    let mut state = state.write().await;
+                    state.download();
+                    return;
+                }
+            };
+
+            let key = task::spawn_blocking(move || vault.derive(&passphrase)).await;
+
+            let mut state = state.write().await;
+            let handle = state.handle();
+            let key = match key {
+                Ok(Ok(Some(key))) => key,
+                Ok(Ok(None)) => {
+                    log::info!("Wrong passphrase for file: {}", id);
+                    handle
+                        .emit_all("download_error", &DownloadError::WrongPassphrase)
+                        .expect("failed to emit download_error");
+
+                    // Nothing was downloaded yet, so ask again
+                    if cancel_rx.try_recv().is_err() && state.rt.job != Job::Idle {
+                        state.download_vault(id, cancel_rx, vault);
+                    }
+                    return;
+                }
+                Ok(Err(err)) => {
+                    log::error!("failed to derive key: {}", err);
+                    handle
+                        .emit_all(
+                            "download_error",
+                            &DownloadError::EncryptionError(err.to_string()),
+                        )
+                        .expect("failed to emit download_error");
+
+                    state.rt.download_queue.clear();
+                    state.forget_queue();
+                    state.rt.job = Job::Idle;
+                    return;
+                }
+                Err(_) => {
+                    handle
+                        .emit_all("download_error", &DownloadError::JoinError)
+                        .expect("failed to emit download_error");
+
+                    state.rt.download_queue.clear();
+                    state.forget_queue();
+                    state.rt.job = Job::Idle;
+                    return;
+                }
+            };
+
+            // Canceled while deriving the key
+            if cancel_rx.try_recv().is_ok() || state.rt.job == Job::Idle {
+                log::debug!("Download canceled");
+                return;
+            }
+
+            state.download_secure(id, cancel_rx, key);
+        });
+    }
+
     fn download_secure(&mut self, id: u32, cancel_rx: oneshot::Receiver<()>, key: [u8; 32]) {
-        let handle = unsafe { self.rt.app_handle.as_ref().unwrap() };
+        let handle = self.handle();
         let file = match self.files.iter().find(|file| file.id == id) {
             Some(file) => file,
             None => {
@@ -97,42 +196,61 @@ impl State {
 
                 self.rt.job = Job::Idle;
                 self.rt.download_queue.clear();
+                self.forget_queue();
                 return;
             }
         };
 
-        let (tx, mut rx) = mpsc::channel::<usize>(10);
-        tokio::spawn(async move {
-            let mut bytes = 0;
-            while let Some(read) = rx.recv().await {
-                bytes += read;
+        let credentials = self
+            .auth()
+            .and_then(|auth| Ok((Arc::new(auth), self.locations(file)?)));
+        let (auth, locations) = match credentials {
+            Ok(credentials) => credentials,
+            Err(err) => {
+                log::error!("failed to resolve credentials: {}", err);
                 handle
-                    .emit_all("download_progress", bytes)
-                    .expect("failed to emit download_progress");
+                    .emit_all("download_error", &DownloadError::from(err))
+                    .expect("failed to emit download_error");
+
+                self.rt.job = Job::Idle;
+                self.rt.download_queue.clear();
+                self.forget_queue();
+                return;
             }
-        });
+        };
+
+        let (tracker, tx) = Tracker::start(
+            handle.clone(),
+            Kind::Download,
+            id,
+            file.stored_size(),
+            Phase::FetchingMetadata,
+        );
 
         let target = download_target(&file.path);
-        let token = Arc::new(self.token.clone());
+        let staged = file.compression.is_some().then(staging_target);
+        let output = staged.clone().unwrap_or_else(|| target.clone());
+        let expected_crc = file.crc32;
         let channel = Arc::new(self.channel_id.clone());
         let cluster_count = file.download_ids.len();
-        let mut ids = file.download_ids.clone();
+        let recovery = Recovery::new(auth.clone(), channel.clone(), file, Some(key)).map(Arc::new);
+        let sources = Arc::new(Sources::new(auth, locations, file));
 
         let (crc_tx, crc_rx) = self
             .do_checksum
             .then(|| mpsc::channel::<(u64, Hasher)>(4))
             .map_or_else(|| (None, None), |(tx, rx)| (Some(tx), Some(rx)));
 
-        let slices = file.size / BYTES_PER_SLICE;
+        let slices = file.stored_size().div_ceil(file.slicing.bytes_per_slice());
         let crc_handle = tokio::spawn(async move {
             let mut rx = match crc_rx {
                 Some(rx) => rx,
                 None => return Ok(None),
             };
 
-            let mut hashers = vec![unsafe { std::mem::zeroed() }; slices as usize];
+            let mut hashers = vec![Hasher::new(); slices as usize];
             while let Some((idx, hasher)) = rx.recv().await {
-                hashers.insert(idx as usize, hasher);
+                hashers[idx as usize] = hasher;
             }
 
             let mut hasher = Hasher::new();
@@ -143,96 +261,103 @@ impl State {
             Ok(Some(hasher.finalize()))
         });
 
-        let writer = match SecureWriter::new(&target, &key, tx, crc_tx) {
+        let framing = Framing::new(file.format, file.id, file.stored_size(), file.slicing);
+        let cipher = Cipher::new(file.algorithm, &key);
+        let writer = match SecureWriter::new(&output, cipher, framing, tx, crc_tx) {
             Ok(writer) => writer,
             Err(err) => {
-                log::error!("failed to open file: {}", target);
+                log::error!("failed to open file: {}", output);
                 handle
                     .emit_all("download_error", &DownloadError::Io(err))
                     .expect("failed to emit download_error");
 
                 self.rt.job = Job::Idle;
                 self.rt.download_queue.clear();
+                self.forget_queue();
                 return;
             }
         };
 
-        log::info!("Downloading file: {}", target);
-        let mut senders = Vec::with_capacity(ids.len());
-        let mut receivers = Vec::with_capacity(ids.len());
+        log::info!("Downloading file: {}", output);
+        let mut senders = Vec::with_capacity(cluster_count);
+        let mut receivers = Vec::with_capacity(cluster_count);
 
-        for _ in 0..ids.len() {
+        for _ in 0..cluster_count {
             let (sender, receiver) = oneshot::channel::<SecureClusterW>();
             senders.push(sender);
             receivers.push(receiver);
         }
 
+        let writer = Arc::new(writer);
+        let writer2 = writer.clone();
+        let sources2 = sources.clone();
+        let recovery2 = recovery.clone();
+        let tracker2 = tracker.clone();
+        let slice_size = file.slicing.slice_size;
+        let download_threads = concurrency::DOWNLOAD.max();
         let stream = stream::iter(receivers);
-        let downloaders =
-            stream
-                .map(Ok)
-                .try_for_each_concurrent(DOWNLOAD_THREADS, move |rx| async move {
+        let downloaders = stream
+            .map(Ok)
+            .try_for_each_concurrent(download_threads, move |rx| {
+                let writer = writer2.clone();
+                let sources = sources2.clone();
+                let recovery = recovery2.clone();
+                let tracker = tracker2.clone();
+                async move {
+                    // Fetching stopped early and dropped the sender, it reports why
                     let mut cluster = match rx.await {
                         Ok(cluster) => cluster,
-                        Err(_) => return Ok(()), // TODO: comment why returning Ok(()) is actually ok
+                        Err(_) => return Ok(()),
                     };
 
-                    cluster.download().await
-                });
+                    tracker.phase(Phase::Downloading);
 
-        let future = tokio::spawn(async move {
-            let message_count = cmp::min(ids.len() * 2, 100);
-            let mut set = ids.clone();
+                    // Every slice but the last one of the file is full, close enough for a rate
+                    let permit = concurrency::DOWNLOAD.acquire().await;
+                    let size = cluster.slices() as u64 * slice_size;
 
-            'outer: while let Some(id) = ids.first() {
-                let mut messages =
-                    match api::fetch_messages(&token, &channel, *id, message_count).await {
-                        Ok(messages) => messages,
+                    // A cluster that fails to download or decode is fetched again from the mirrors
+                    let index = cluster.index();
+                    let result = match cluster.download().await {
                         Err(err) => {
-                            log::error!("failed to fetch messages: {}", err);
-                            return Err(err);
+                            sources
+                                .retry(index, err, |urls| {
+                                    let writer = writer.clone();
+                                    async move { writer.cluster(index, urls).download().await }
+                                })
+                                .await
                         }
+                        result => result,
                     };
 
-                let id = *id;
-                let mut has_found = false;
-                for message in messages.iter_mut() {
-                    let message_id = message
-                        .id
-                        .parse::<u64>()
-                        .expect("failed to parse message ID");
-
-                    if message_id == id {
-                        has_found = true;
+                    if result.is_ok() {
+                        permit.done(size);
                     }
 
-                    if let Some((idx, id)) = set
-                        .iter_mut()
-                        .enumerate()
-                        .find(|(_, id)| **id == message_id)
-                    {
-                        ids.retain(|id| *id != message_id);
-                        let attachments = message.attachments.take();
-                        let cluster = writer.cluster(idx, attachments);
-                        *id = 0;
-
-                        let sender = senders.pop().unwrap();
-                        if sender.send(cluster).is_err() {
-                            break 'outer;
+                    match (result, recovery) {
+                        (Err(err), Some(recovery)) => {
+                            recovery.erase(index, &err);
+                            Ok(())
                         }
+                        (result, _) => result,
                     }
                 }
+            });
 
-                if !has_found {
-                    log::warn!("Message not found: {}", id);
-                    return Err(DownloadError::NotFoundRemote);
-                }
-            }
+        let recovery3 = recovery.clone();
 
-            Ok(())
+        let future = tokio::spawn(async move {
+            sources
+                .fetch(recovery3.as_deref(), |index, attachments| {
+                    let cluster = writer.cluster(index, attachments);
+
+                    // When the receiver is dropped, downloading was canceled
+                    senders.pop().unwrap().send(cluster).is_ok()
+                })
+                .await
         });
 
-        let state = unsafe { &*self.rt.this };
+        let state = self.shared();
         tokio::spawn(async move {
             let now = Instant::now();
 
@@ -245,7 +370,7 @@ impl State {
                 futures = futures => futures,
                 _ = cancel_rx => {
                     log::debug!("Download canceled");
-                    if let Err(err) = fs::remove_file(&target) {
+                    if let Err(err) = fs::remove_file(&output) {
                         log::error!("failed to remove file: {}", err);
                     }
 
@@ -255,9 +380,32 @@ impl State {
 
             let took = now.elapsed().as_secs_f64();
             log::info!("Downloaded {} cluster(s) in {:.2}s", cluster_count, took);
+            tracker.phase(Phase::Verifying);
+
+            // Lost clusters are rebuilt before anything else reads the output
+            let futures = match (futures, &recovery) {
+                (Ok((a, b, crc)), Some(recovery)) => {
+                    recovery.recover(&output, crc).await.map(|crc| (a, b, crc))
+                }
+                (futures, _) => futures,
+            };
+
+            // Inflating a large file takes a while, so it happens before taking the lock
+            let futures = match (futures, staged) {
+                (Ok(result), Some(staged)) => {
+                    inflate(staged, target.clone(), result.2, expected_crc)
+                        .await
+                        .map(|_| result)
+                }
+                (Err(err), Some(staged)) => {
+                    remove_staged(&staged);
+                    Err(err)
+                }
+                (futures, None) => futures,
+            };
 
             let mut state = state.write().await;
-            let handle = unsafe { state.rt.app_handle.as_ref().unwrap() };
+            let handle = state.handle();
             let crc = match futures {
                 Ok((_, _, crc)) => crc,
                 Err(err) => {
@@ -268,6 +416,7 @@ impl State {
                         .expect("failed to emit download_error");
 
                     state.rt.download_queue.clear();
+                    state.forget_queue();
                     state.rt.job = Job::Idle;
 
                     return;
@@ -286,6 +435,7 @@ impl State {
                     .expect("failed to emit download_error");
 
                 state.rt.download_queue.clear();
+                state.forget_queue();
                 state.rt.job = Job::Idle;
 
                 return;
@@ -300,7 +450,7 @@ impl State {
     }
 
     fn download_insecure(&mut self, id: u32, cancel_rx: oneshot::Receiver<()>) {
-        let handle = unsafe { self.rt.app_handle.as_ref().unwrap() };
+        let handle = self.handle();
         let file = match self.files.iter().find(|file| file.id == id) {
             Some(file) => file,
             None => {
@@ -311,40 +461,59 @@ impl State {
 
                 self.rt.job = Job::Idle;
                 self.rt.download_queue.clear();
+                self.forget_queue();
                 return;
             }
         };
 
-        let (tx, mut rx) = mpsc::channel::<usize>(10);
-        tokio::spawn(async move {
-            let mut bytes = 0;
-            while let Some(read) = rx.recv().await {
-                bytes += read;
+        let credentials = self
+            .auth()
+            .and_then(|auth| Ok((Arc::new(auth), self.locations(file)?)));
+        let (auth, locations) = match credentials {
+            Ok(credentials) => credentials,
+            Err(err) => {
+                log::error!("failed to resolve credentials: {}", err);
                 handle
-                    .emit_all("download_progress", bytes)
-                    .expect("failed to emit download_progress");
+                    .emit_all("download_error", &DownloadError::from(err))
+                    .expect("failed to emit download_error");
+
+                self.rt.job = Job::Idle;
+                self.rt.download_queue.clear();
+                self.forget_queue();
+                return;
             }
-        });
+        };
+
+        let (tracker, tx) = Tracker::start(
+            handle.clone(),
+            Kind::Download,
+            id,
+            file.stored_size(),
+            Phase::FetchingMetadata,
+        );
 
         let target = download_target(&file.path);
-        let token = Arc::new(self.token.clone());
+        let staged = file.compression.is_some().then(staging_target);
+        let output = staged.clone().unwrap_or_else(|| target.clone());
+        let expected_crc = file.crc32;
         let channel = Arc::new(self.channel_id.clone());
         let cluster_count = file.download_ids.len();
-        let mut ids = file.download_ids.clone();
+        let recovery = Recovery::new(auth.clone(), channel.clone(), file, None).map(Arc::new);
+        let sources = Arc::new(Sources::new(auth, locations, file));
 
         let (crc_tx, crc_rx) = self
             .do_checksum
             .then(|| mpsc::channel::<(u64, Hasher)>(4))
             .map_or_else(|| (None, None), |(tx, rx)| (Some(tx), Some(rx)));
 
-        let slices = (file.size + SLICE_SIZE - 1) / SLICE_SIZE;
+        let slices = file.stored_size().div_ceil(file.slicing.slice_size);
         let crc_handle = tokio::spawn(async move {
             let mut rx = match crc_rx {
                 Some(rx) => rx,
                 None => return Ok(None),
             };
 
-            let mut hashers = vec![unsafe { std::mem::zeroed() }; slices as usize];
+            let mut hashers = vec![Hasher::new(); slices as usize];
             while let Some((idx, hasher)) = rx.recv().await {
                 hashers[idx as usize] = hasher;
             }
@@ -357,96 +526,101 @@ impl State {
             Ok(Some(hasher.finalize()))
         });
 
-        let writer = match InsecureWriter::new(&target, tx, crc_tx) {
+        let writer = match InsecureWriter::new(&output, file.slicing, tx, crc_tx) {
             Ok(writer) => writer,
             Err(err) => {
-                log::error!("failed to open file: {}", target);
+                log::error!("failed to open file: {}", output);
                 handle
                     .emit_all("download_error", &DownloadError::Io(err))
                     .expect("failed to emit download_error");
 
                 self.rt.job = Job::Idle;
                 self.rt.download_queue.clear();
+                self.forget_queue();
                 return;
             }
         };
 
-        log::info!("Downloading file: {}", target);
-        let mut senders = Vec::with_capacity(ids.len());
-        let mut receivers = Vec::with_capacity(ids.len());
+        log::info!("Downloading file: {}", output);
+        let mut senders = Vec::with_capacity(cluster_count);
+        let mut receivers = Vec::with_capacity(cluster_count);
 
-        for _ in 0..ids.len() {
+        for _ in 0..cluster_count {
             let (sender, receiver) = oneshot::channel::<InsecureClusterW>();
             senders.push(sender);
             receivers.push(receiver);
         }
 
+        let writer = Arc::new(writer);
+        let writer2 = writer.clone();
+        let sources2 = sources.clone();
+        let recovery2 = recovery.clone();
+        let tracker2 = tracker.clone();
+        let slice_size = file.slicing.slice_size;
+        let download_threads = concurrency::DOWNLOAD.max();
         let stream = stream::iter(receivers);
-        let downloaders =
-            stream
-                .map(Ok)
-                .try_for_each_concurrent(DOWNLOAD_THREADS, move |rx| async move {
+        let downloaders = stream
+            .map(Ok)
+            .try_for_each_concurrent(download_threads, move |rx| {
+                let writer = writer2.clone();
+                let sources = sources2.clone();
+                let recovery = recovery2.clone();
+                let tracker = tracker2.clone();
+                async move {
+                    // Fetching stopped early and dropped the sender, it reports why
                     let mut cluster = match rx.await {
                         Ok(cluster) => cluster,
-                        Err(_) => return Ok(()), // TODO: comment why returning Ok(()) is actually ok
+                        Err(_) => return Ok(()),
                     };
 
-                    cluster.download().await
-                });
+                    tracker.phase(Phase::Downloading);
 
-        let future = tokio::spawn(async move {
-            let message_count = cmp::min(ids.len() * 2, 100);
-            let mut set = ids.clone();
+                    // Every slice but the last one of the file is full, close enough for a rate
+                    let permit = concurrency::DOWNLOAD.acquire().await;
+                    let size = cluster.slices() as u64 * slice_size;
 
-            'outer: while let Some(id) = ids.first() {
-                let mut messages =
-                    match api::fetch_messages(&token, &channel, *id, message_count).await {
-                        Ok(messages) => messages,
+                    // A cluster that fails to download or decode is fetched again from the mirrors
+                    let index = cluster.index();
+                    let result = match cluster.download().await {
                         Err(err) => {
-                            log::error!("failed to fetch messages: {}", err);
-                            return Err(err);
+                            sources
+                                .retry(index, err, |urls| {
+                                    let writer = writer.clone();
+                                    async move { writer.cluster(index, urls).download().await }
+                                })
+                                .await
                         }
+                        result => result,
                     };
 
-                let id = *id;
-                let mut has_found = false;
-                for message in messages.iter_mut() {
-                    let message_id = message
-                        .id
-                        .parse::<u64>()
-                        .expect("failed to parse message ID");
-
-                    if message_id == id {
-                        has_found = true;
+                    if result.is_ok() {
+                        permit.done(size);
                     }
 
-                    if let Some((idx, id)) = set
-                        .iter_mut()
-                        .enumerate()
-                        .find(|(_, id)| **id == message_id)
-                    {
-                        ids.retain(|id| *id != message_id);
-                        let attachments = message.attachments.take();
-                        let cluster = writer.cluster(idx, attachments);
-                        *id = 0;
-
-                        let sender = senders.pop().unwrap();
-                        if sender.send(cluster).is_err() {
-                            break 'outer;
+                    match (result, recovery) {
+                        (Err(err), Some(recovery)) => {
+                            recovery.erase(index, &err);
+                            Ok(())
                         }
+                        (result, _) => result,
                     }
                 }
+            });
 
-                if !has_found {
-                    log::warn!("Message not found: {}", id);
-                    return Err(DownloadError::NotFoundRemote);
-                }
-            }
+        let recovery3 = recovery.clone();
 
-            Ok(())
+        let future = tokio::spawn(async move {
+            sources
+                .fetch(recovery3.as_deref(), |index, attachments| {
+                    let cluster = writer.cluster(index, attachments);
+
+                    // When the receiver is dropped, downloading was canceled
+                    senders.pop().unwrap().send(cluster).is_ok()
+                })
+                .await
         });
 
-        let state = unsafe { &*self.rt.this };
+        let state = self.shared();
         tokio::spawn(async move {
             let now = Instant::now();
 
@@ -459,7 +633,7 @@ impl State {
                 futures = futures => futures,
                 _ = cancel_rx => {
                     log::debug!("Download canceled");
-                    if let Err(err) = fs::remove_file(&target) {
+                    if let Err(err) = fs::remove_file(&output) {
                         log::error!("failed to remove file: {}", err);
                     }
 
@@ -469,9 +643,32 @@ impl State {
 
             let took = now.elapsed().as_secs_f64();
             log::info!("Downloaded {} cluster(s) in {:.2}s", cluster_count, took);
+            tracker.phase(Phase::Verifying);
+
+            // Lost clusters are rebuilt before anything else reads the output
+            let futures = match (futures, &recovery) {
+                (Ok((a, b, crc)), Some(recovery)) => {
+                    recovery.recover(&output, crc).await.map(|crc| (a, b, crc))
+                }
+                (futures, _) => futures,
+            };
+
+            // Inflating a large file takes a while, so it happens before taking the lock
+            let futures = match (futures, staged) {
+                (Ok(result), Some(staged)) => {
+                    inflate(staged, target.clone(), result.2, expected_crc)
+                        .await
+                        .map(|_| result)
+                }
+                (Err(err), Some(staged)) => {
+                    remove_staged(&staged);
+                    Err(err)
+                }
+                (futures, None) => futures,
+            };
 
             let mut state = state.write().await;
-            let handle = unsafe { state.rt.app_handle.as_ref().unwrap() };
+            let handle = state.handle();
             let crc = match futures {
                 Ok((_, _, crc)) => crc,
                 Err(err) => {
@@ -482,6 +679,7 @@ impl State {
                         .expect("failed to emit download_error");
 
                     state.rt.download_queue.clear();
+                    state.forget_queue();
                     state.rt.job = Job::Idle;
 
                     return;
@@ -500,6 +698,7 @@ impl State {
                     .expect("failed to emit download_error");
 
                 state.rt.download_queue.clear();
+                state.forget_queue();
                 state.rt.job = Job::Idle;
 
                 return;
@@ -513,3 +712,38 @@ impl State {
         });
     }
 }
+
+// The checksum covers the stored stream, so it is verified before the staged file is inflated
+async fn inflate(
+    staged: String,
+    target: String,
+    crc: Option<u32>,
+    expected_crc: u32,
+) -> Result<(), DownloadError> {
+    if let Some(crc) = crc
+        && crc != expected_crc
+    {
+        remove_staged(&staged);
+        return Err(DownloadError::ChecksumMismatch(crc, expected_crc));
+    }
+
+    let result = task::spawn_blocking(move || {
+        let result = compression::decompress(&staged, &target);
+        remove_staged(&staged);
+
+        if result.is_err()
+            && let Err(err) = fs::remove_file(&target)
+        {
+            log::error!("failed to remove file: {}", err);
+        }
+
+        result
+    })
+    .await;
+
+    match result {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(err)) => Err(DownloadError::Io(err)),
+        Err(_) => Err(DownloadError::JoinError),
+    }
+}
@@ -0,0 +1,269 @@
+use crate::model::{Limits, Throttle};
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{Local, Timelike};
+use futures::{Stream, StreamExt};
+use sync_wrapper::SyncStream;
+use tokio::time;
+
+// Bytes per second, zero is unlimited, which a configured limit never is
+static GLOBAL_UPLOAD: AtomicU64 = AtomicU64::new(0);
+static GLOBAL_DOWNLOAD: AtomicU64 = AtomicU64::new(0);
+static TRANSFER_UPLOAD: AtomicU64 = AtomicU64::new(0);
+static TRANSFER_DOWNLOAD: AtomicU64 = AtomicU64::new(0);
+
+// Shared by every transfer going the same way
+static UPLOAD: Bucket = Bucket::new(&GLOBAL_UPLOAD);
+static DOWNLOAD: Bucket = Bucket::new(&GLOBAL_DOWNLOAD);
+
+static SETTINGS: RwLock<Option<Throttle>> = RwLock::new(None);
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+// A token bucket that goes into debt, so concurrent transfers wait in turn instead of racing
+struct Bucket {
+    limit: &'static AtomicU64,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    tokens: f64,
+    refilled: Option<Instant>,
+}
+
+impl Bucket {
+    const fn new(limit: &'static AtomicU64) -> Self {
+        Self {
+            limit,
+            inner: Mutex::new(Inner {
+                tokens: 0.0,
+                refilled: None,
+            }),
+        }
+    }
+
+    async fn take(&self, bytes: usize) {
+        // The limit is read every time, so a change applies to transfers already running
+        let limit = self.limit.load(Ordering::Relaxed) as f64;
+        if limit == 0.0 {
+            return;
+        }
+
+        let wait = {
+            let mut inner = self.inner.lock().expect("failed to lock bucket");
+            let now = Instant::now();
+            let elapsed = inner
+                .refilled
+                .map_or(0.0, |refilled| (now - refilled).as_secs_f64());
+
+            // At most a second worth of bytes builds up while idle
+            inner.tokens = (inner.tokens + elapsed * limit).min(limit) - bytes as f64;
+            inner.refilled = Some(now);
+
+            (inner.tokens < 0.0).then(|| Duration::from_secs_f64(-inner.tokens / limit))
+        };
+
+        if let Some(wait) = wait {
+            time::sleep(wait).await;
+        }
+    }
+}
+
+// Limits a single file going up or down, along with every other one going the same way.
+// Made once per file and cloned into each of its slices, which all draw from the same bucket
+#[derive(Clone)]
+pub struct Gate {
+    own: Arc<Bucket>,
+    global: &'static Bucket,
+}
+
+impl Gate {
+    pub fn upload() -> Self {
+        Self {
+            own: Arc::new(Bucket::new(&TRANSFER_UPLOAD)),
+            global: &UPLOAD,
+        }
+    }
+
+    pub fn download() -> Self {
+        Self {
+            own: Arc::new(Bucket::new(&TRANSFER_DOWNLOAD)),
+            global: &DOWNLOAD,
+        }
+    }
+
+    pub async fn pass(&self, bytes: usize) {
+        self.own.take(bytes).await;
+        self.global.take(bytes).await;
+    }
+}
+
+// Wraps the buffers of a slice so they are handed to the request body no faster than allowed
+pub fn upload<S>(
+    gate: Gate,
+    stream: S,
+) -> impl Stream<Item = io::Result<Vec<u8>>> + Send + Sync + 'static
+where
+    S: Stream<Item = io::Result<Vec<u8>>> + Send + 'static,
+{
+    let stream = stream.then(move |buffer| {
+        let gate = gate.clone();
+        async move {
+            if let Ok(buffer) = &buffer {
+                gate.pass(buffer.len()).await;
+            }
+
+            buffer
+        }
+    });
+
+    // The request body has to be Sync, the stream is only polled through a unique reference
+    SyncStream::new(stream.boxed())
+}
+
+pub fn validate(throttle: &Throttle) -> Result<(), String> {
+    let windows = throttle.schedule.iter().map(|window| window.global);
+    for limits in [throttle.global, throttle.transfer]
+        .into_iter()
+        .chain(windows)
+    {
+        if limits.upload == Some(0) || limits.download == Some(0) {
+            return Err("A limit must be above zero, leave it empty for no limit".to_owned());
+        }
+    }
+
+    for window in &throttle.schedule {
+        if window.start >= MINUTES_PER_DAY || window.end >= MINUTES_PER_DAY {
+            return Err("Schedule times must be between 00:00 and 23:59".to_owned());
+        }
+
+        if window.start == window.end {
+            return Err("A schedule window can't start and end at the same time".to_owned());
+        }
+    }
+
+    Ok(())
+}
+
+// Takes effect right away, for transfers already running as well
+pub fn configure(throttle: Throttle) {
+    *SETTINGS.write().expect("failed to lock throttle") = Some(throttle);
+    apply();
+}
+
+// Keeps the global limits in line with the schedule
+pub async fn watch() {
+    loop {
+        time::sleep(Duration::from_secs(30)).await;
+        apply();
+    }
+}
+
+fn apply() {
+    let settings = SETTINGS.read().expect("failed to lock throttle");
+    let Some(throttle) = settings.as_ref() else {
+        return;
+    };
+
+    let time = Local::now().time();
+    let now = (time.hour() * 60 + time.minute()) as u16;
+
+    store(&GLOBAL_UPLOAD, &GLOBAL_DOWNLOAD, global_at(throttle, now));
+    store(&TRANSFER_UPLOAD, &TRANSFER_DOWNLOAD, throttle.transfer);
+}
+
+// Windows that end before they start run past midnight
+fn global_at(throttle: &Throttle, now: u16) -> Limits {
+    throttle
+        .schedule
+        .iter()
+        .find(|window| match window.start < window.end {
+            true => window.start <= now && now < window.end,
+            false => now >= window.start || now < window.end,
+        })
+        .map_or(throttle.global, |window| window.global)
+}
+
+fn store(upload: &AtomicU64, download: &AtomicU64, limits: Limits) {
+    upload.store(limits.upload.unwrap_or(0), Ordering::Relaxed);
+    download.store(limits.download.unwrap_or(0), Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Window;
+
+    fn limit(upload: u64) -> Limits {
+        Limits {
+            upload: Some(upload),
+            download: None,
+        }
+    }
+
+    fn throttle(schedule: Vec<Window>) -> Throttle {
+        Throttle {
+            global: limit(1),
+            transfer: Limits::default(),
+            schedule,
+        }
+    }
+
+    fn window(start: u16, end: u16, upload: u64) -> Window {
+        Window {
+            start,
+            end,
+            global: limit(upload),
+        }
+    }
+
+    #[test]
+    fn window_applies_from_its_start_up_to_its_end() {
+        let throttle = throttle(vec![window(9 * 60, 17 * 60, 2)]);
+
+        assert_eq!(global_at(&throttle, 9 * 60 - 1).upload, Some(1));
+        assert_eq!(global_at(&throttle, 9 * 60).upload, Some(2));
+        assert_eq!(global_at(&throttle, 17 * 60 - 1).upload, Some(2));
+        assert_eq!(global_at(&throttle, 17 * 60).upload, Some(1));
+    }
+
+    #[test]
+    fn window_runs_past_midnight() {
+        let throttle = throttle(vec![window(22 * 60, 6 * 60, 2)]);
+
+        assert_eq!(global_at(&throttle, 22 * 60).upload, Some(2));
+        assert_eq!(global_at(&throttle, MINUTES_PER_DAY - 1).upload, Some(2));
+        assert_eq!(global_at(&throttle, 0).upload, Some(2));
+        assert_eq!(global_at(&throttle, 6 * 60 - 1).upload, Some(2));
+        assert_eq!(global_at(&throttle, 6 * 60).upload, Some(1));
+        assert_eq!(global_at(&throttle, 12 * 60).upload, Some(1));
+    }
+
+    #[test]
+    fn first_matching_window_wins() {
+        let throttle = throttle(vec![
+            window(8 * 60, 12 * 60, 2),
+            window(10 * 60, 14 * 60, 3),
+        ]);
+
+        assert_eq!(global_at(&throttle, 11 * 60).upload, Some(2));
+        assert_eq!(global_at(&throttle, 13 * 60).upload, Some(3));
+    }
+
+    #[test]
+    fn zero_limits_are_rejected() {
+        let mut throttle = throttle(vec![window(0, 60, 2)]);
+        assert!(validate(&throttle).is_ok());
+
+        throttle.schedule[0].global.download = Some(0);
+        assert!(validate(&throttle).is_err());
+
+        throttle.schedule.clear();
+        throttle.transfer.upload = Some(0);
+        assert!(validate(&throttle).is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{env, future::Future, path::Path, sync::OnceLock};
+use std::{env, fs, future::Future, path::Path, sync::OnceLock};
 
 use tokio::task::{JoinError, JoinHandle};
 
@@ -71,6 +71,22 @@ pub fn download_target(path: &str) -> String {
     }
 }
 
+// A fresh file in the app data directory for intermediate copies
+pub fn staging_target() -> String {
+    let dir = format!("{}/staging", path());
+    if let Err(err) = fs::create_dir_all(&dir) {
+        log::error!("failed to create staging directory: {}", err);
+    }
+
+    format!("{}/{:016x}", dir, rand::random::<u64>())
+}
+
+pub fn remove_staged(path: &str) {
+    if let Err(err) = fs::remove_file(path) {
+        log::error!("failed to remove staged file: {}", err);
+    }
+}
+
 pub trait Flatten<T, E1, E2>
 where
     Self: Future<Output = Result<Result<T, E1>, E2>>,
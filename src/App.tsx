@@ -10,6 +10,8 @@ import Footer from './components/footer';
 import Settings from "./components/settings";
 import ErrorModal from './components/error';
 import DeleteModal from "./components/delete";
+import PassphraseModal from "./components/passphrase";
+import VaultModal from "./components/vault";
 
 export default function App() {
   const [settingsOpen, setSettingsOpen] = createSignal(false);
@@ -26,10 +28,18 @@ export default function App() {
 
   const [deleteModalOpen, setDeleteModalOpen] = createSignal(false);
 
-  let unlistenEraseFiles: UnlistenFn | null = null;
+  const [passphraseFile, setPassphraseFile] = createSignal<number | null>(null);
+  const [wrongPassphrase, setWrongPassphrase] = createSignal(false);
+
+  // Uploads go through a passphrase while the vault is on
+  const [vault, setVault] = createSignal(false);
+  const [vaultFiles, setVaultFiles] = createSignal<string[] | null>(null);
+
+  let unlistenProfileSwitched: UnlistenFn | null = null;
   let unlistenFileUploaded: UnlistenFn | null = null;
   let unlistenUploadError: UnlistenFn | null = null;
   let unlistenDownloadError: UnlistenFn | null = null;
+  let unlistenPassphraseRequired: UnlistenFn | null = null;
 
   function onRename(e: CustomEvent<{ id: number, name: string }>) {
     const { id, name } = e.detail;
@@ -45,9 +55,9 @@ export default function App() {
   onMount(async () => {
     document.addEventListener("rename", onRename as any);
 
-    unlistenEraseFiles = await listen("erase_files", async () => {
+    unlistenProfileSwitched = await listen<IFile[]>("profile_switched", async data => {
       batch(() => {
-        setFiles([]);
+        setFiles(data.payload);
         setQuery("");
       });
     });
@@ -73,6 +83,12 @@ export default function App() {
     });
 
     unlistenDownloadError = await listen<Omit<IError, "job">>("download_error", async data => {
+      // The passphrase is asked for again right after
+      if (data.payload.type === "WrongPassphrase") {
+        setWrongPassphrase(true);
+        return;
+      }
+
       batch(() => {
         setErrorOpen(true);
         setError({
@@ -82,6 +98,10 @@ export default function App() {
       });
     });
 
+    unlistenPassphraseRequired = await listen<number>("passphrase_required", async data => {
+      setPassphraseFile(data.payload);
+    });
+
     const settings = JSON.parse(await invoke<string>("get_settings"));
     const files = JSON.parse(await invoke<string>("get_files"));
 
@@ -96,10 +116,11 @@ export default function App() {
 
   onCleanup(() => {
     document.removeEventListener("rename", onRename as any);
-    unlistenEraseFiles?.();
+    unlistenProfileSwitched?.();
     unlistenFileUploaded?.();
     unlistenUploadError?.();
     unlistenDownloadError?.();
+    unlistenPassphraseRequired?.();
   });
 
   createEffect(async () => {
@@ -121,10 +142,38 @@ export default function App() {
     });
   }
 
+  async function upload(files: string[]) {
+    if (vault()) {
+      setVaultFiles(files);
+      return;
+    }
+
+    await invoke("upload_files", { files, passphrase: null });
+  }
+
+  async function uploadVault(passphrase: string) {
+    const files = vaultFiles();
+    setVaultFiles(null);
+
+    await invoke("upload_files", { files, passphrase });
+  }
+
+  async function providePassphrase(passphrase: string | null) {
+    batch(() => {
+      setPassphraseFile(null);
+      setWrongPassphrase(false);
+    });
+
+    await invoke("provide_passphrase", { passphrase });
+  }
+
   return (
     <div class="app">
       <Header
         openSettings={() => setSettingsOpen(true)}
+        upload={upload}
+        vault={vault()}
+        toggleVault={() => setVault(vault => !vault)}
         query={query()}
         setQuery={setQuery}
         selected={selected().length}
@@ -148,6 +197,7 @@ export default function App() {
           files={files}
           order={order}
           download={async file => await invoke("download_files", { files: [file] })}
+          upload={upload}
           remove={() => setDeleteModalOpen(true)}
         />
       </Show>
@@ -180,6 +230,24 @@ export default function App() {
         confirm={deleteSelected}
         cancel={() => setDeleteModalOpen(false)}
       />
+
+      <PassphraseModal
+        isOpen={() => passphraseFile() !== null}
+        name={() => {
+          const file = files().find(file => file.id === passphraseFile());
+          return file?.name ?? file?.path ?? "this file";
+        }}
+        wrong={wrongPassphrase}
+        submit={providePassphrase}
+        skip={() => providePassphrase(null)}
+      />
+
+      <VaultModal
+        isOpen={() => vaultFiles() !== null}
+        count={() => vaultFiles()?.length ?? 0}
+        submit={uploadVault}
+        cancel={() => setVaultFiles(null)}
+      />
     </div>
   );
 }
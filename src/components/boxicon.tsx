@@ -6,6 +6,7 @@ type Props = {
   onClick: () => void;
   size?: number;
   disabled?: boolean;
+  active?: boolean;
 };
 
 export default function BoxIcon(props: Props) {
@@ -14,7 +15,7 @@ export default function BoxIcon(props: Props) {
       class={styles.boxicon}
       onClick={props.onClick}
       style={{ "--size": props.size || 36 + "px" }}
-      classList={{ [styles.disabled]: props.disabled }}
+      classList={{ [styles.disabled]: props.disabled, [styles.active]: props.active }}
     >
       {props.children}
     </div>
@@ -30,6 +30,7 @@ type Props = {
   files: Accessor<IFile[]>;
   order: Accessor<number[] | null>;
   download: (id: number) => void;
+  upload: (files: string[]) => void;
   remove: () => void;
 }
 
@@ -46,7 +47,7 @@ const fmtTime = new Intl.DateTimeFormat(undefined, {
   second: "numeric",
 });
 
-export default function Content({ setSelected, selected, files, order, download, remove }: Props) {
+export default function Content({ setSelected, selected, files, order, download, upload, remove }: Props) {
   const [hovering, setHovering] = createSignal(false);
   const [context, setContext] = createSignal<{ x: number, y: number, id: number }>({ x: 0, y: 0, id: -1 });
   const [contextOpen, setContextOpen] = createSignal(false);
@@ -91,11 +92,9 @@ export default function Content({ setSelected, selected, files, order, download,
     window.addEventListener("keydown", onKeydown);
     document.addEventListener("focusName", onFocus);
 
-    unlistenDrop = await listen("tauri://file-drop", async data => {
+    unlistenDrop = await listen<string[]>("tauri://file-drop", async data => {
       setHovering(false);
-      await invoke("upload_files", {
-        files: data.payload,
-      });
+      upload(data.payload);
     });
 
     unlistenDropHover = await listen("tauri://file-drop-hover", () => {
@@ -241,7 +240,7 @@ type FileProps = {
   openContextMenu: (x: number, y: number) => void;
 };
 
-function File({ selected, onClick, openContextMenu, id, path, name, size, created_at, encryption_key }: IFile & FileProps) {
+function File({ selected, onClick, openContextMenu, id, path, name, size, created_at, encryption_key, vault }: IFile & FileProps) {
   const [fileName, setFileName] = createSignal(name || filename(path));
   const [focused, setFocused] = createSignal(false);
 
@@ -302,7 +301,7 @@ function File({ selected, onClick, openContextMenu, id, path, name, size, create
     >
       <div class={styles.icon}>
         <FileIcon filename={fileName()} />
-        {encryption_key !== null && <BsFileEarmarkLock2Fill class={styles.encrypted} />}
+        {(encryption_key !== null || vault !== null) && <BsFileEarmarkLock2Fill class={styles.encrypted} />}
       </div>
       <div class={styles.name}>
         <input
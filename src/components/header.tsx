@@ -1,5 +1,6 @@
 import {
   AiOutlineFileAdd,
+  AiOutlineLock,
   AiOutlineCloudDownload,
   AiOutlineDelete,
   AiOutlineEdit,
@@ -7,12 +8,14 @@ import {
 } from "solid-icons/ai";
 
 import { open } from "@tauri-apps/api/dialog";
-import { invoke } from "@tauri-apps/api";
 import BoxIcon from "./boxicon";
 import styles from "./header.module.scss";
 
 type Props = {
   openSettings: () => void;
+  upload: (files: string[]) => void;
+  vault: boolean;
+  toggleVault: () => void;
   query: string;
   setQuery: (query: string) => void;
   selected: number;
@@ -33,9 +36,7 @@ export default function Header(props: Props) {
     }
 
     const files = !Array.isArray(result) ? [result] : result;
-    invoke("upload_files", {
-      files,
-    });
+    props.upload(files);
   }
 
   return (
@@ -44,6 +45,10 @@ export default function Header(props: Props) {
         <AiOutlineFileAdd />
       </BoxIcon>
 
+      <BoxIcon onClick={props.toggleVault} active={props.vault}>
+        <AiOutlineLock />
+      </BoxIcon>
+
       <input
         type="text"
         placeholder="Search"
@@ -0,0 +1,57 @@
+import { Accessor, createEffect, createSignal, Show } from "solid-js";
+import styles from "./passphrase.module.scss";
+
+type Props = {
+  isOpen: Accessor<boolean>;
+  name: Accessor<string>;
+  wrong: Accessor<boolean>;
+  submit: (passphrase: string) => void;
+  skip: () => void;
+}
+
+export default function PassphraseModal({ isOpen, name, wrong, submit, skip }: Props) {
+  const [passphrase, setPassphrase] = createSignal("");
+  let inputRef: HTMLInputElement | undefined;
+
+  // Asked again after a wrong one, so start from an empty field every time
+  createEffect(() => {
+    if (isOpen()) {
+      setPassphrase("");
+      setTimeout(() => inputRef?.focus(), 0);
+    }
+  });
+
+  return (
+    <div class={styles.container} classList={{ [styles.open]: isOpen() }}>
+      <div class={styles.modal}>
+        <div class={styles.header}>
+          <h1>Passphrase Required</h1>
+        </div>
+
+        <div class={styles.body}>
+          <p>Enter the passphrase of {name()} to download it.</p>
+          <Show when={wrong()}>
+            <p class={styles.wrong}>The passphrase is wrong, please try again.</p>
+          </Show>
+          <input
+            type="password"
+            placeholder="Passphrase"
+            value={passphrase()}
+            onInput={(e) => setPassphrase((e.target as HTMLInputElement).value)}
+            onKeyDown={(e) => e.key === "Enter" && passphrase().length > 0 && submit(passphrase())}
+            ref={inputRef}
+          />
+        </div>
+
+        <div class={styles.actions}>
+          <button class={styles.cancel} onClick={skip}>
+            Skip
+          </button>
+          <button class={styles.confirm} onClick={() => passphrase().length > 0 && submit(passphrase())}>
+            Download
+          </button>
+        </div>
+      </div>
+    </div>
+  );
+}
@@ -0,0 +1,70 @@
+import { Accessor, createEffect, createSignal, Show } from "solid-js";
+import styles from "./passphrase.module.scss";
+
+type Props = {
+  isOpen: Accessor<boolean>;
+  count: Accessor<number>;
+  submit: (passphrase: string) => void;
+  cancel: () => void;
+}
+
+export default function VaultModal({ isOpen, count, submit, cancel }: Props) {
+  const [passphrase, setPassphrase] = createSignal("");
+  const [repeated, setRepeated] = createSignal("");
+  let inputRef: HTMLInputElement | undefined;
+
+  // Typed twice, a typo would lock the files away for good
+  const mismatch = () => repeated().length > 0 && passphrase() !== repeated();
+  const ready = () => passphrase().length > 0 && passphrase() === repeated();
+
+  createEffect(() => {
+    if (isOpen()) {
+      setPassphrase("");
+      setRepeated("");
+      setTimeout(() => inputRef?.focus(), 0);
+    }
+  });
+
+  return (
+    <div class={styles.container} classList={{ [styles.open]: isOpen() }}>
+      <div class={styles.modal}>
+        <div class={styles.header}>
+          <h1>Vault Upload</h1>
+        </div>
+
+        <div class={styles.body}>
+          <p>
+            The {count() === 1 ? "file is" : `${count()} files are`} encrypted with a key derived from this
+            passphrase. It is never stored, so the files can't be downloaded without it.
+          </p>
+          <input
+            type="password"
+            placeholder="Passphrase"
+            value={passphrase()}
+            onInput={(e) => setPassphrase((e.target as HTMLInputElement).value)}
+            ref={inputRef}
+          />
+          <input
+            type="password"
+            placeholder="Repeat passphrase"
+            value={repeated()}
+            onInput={(e) => setRepeated((e.target as HTMLInputElement).value)}
+            onKeyDown={(e) => e.key === "Enter" && ready() && submit(passphrase())}
+          />
+          <Show when={mismatch()}>
+            <p class={styles.wrong}>The passphrases don't match.</p>
+          </Show>
+        </div>
+
+        <div class={styles.actions}>
+          <button class={styles.cancel} onClick={cancel}>
+            Cancel
+          </button>
+          <button class={styles.confirm} onClick={() => ready() && submit(passphrase())}>
+            Upload
+          </button>
+        </div>
+      </div>
+    </div>
+  );
+}
@@ -10,6 +10,7 @@ declare global {
     size: number;
     created_at: number;
     encryption_key: null | number[];
+    vault: null | object;
   }
 
   interface ISettings {